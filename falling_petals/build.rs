@@ -16,5 +16,39 @@ fn main() -> Result<()> {
     ////let mut paths_to_copy = Vec::new();
     ////paths_to_copy.push("res/");
     //copy_items(&paths_to_copy, out_dir, &copy_options)?;
+
+    record_git_revision();
     Ok(())
 }
+
+/// Makes the git revision the program is built from available as FALLING_PETALS_GIT_REVISION (with
+/// "-dirty" appended if there are uncommitted changes), so that it can be recorded in the render
+/// sidecars.  Nothing is set if the program isn't being built from a git checkout.
+fn record_git_revision() {
+    let git = |args: &[&str]| {
+        std::process::Command::new("git")
+            .args(args)
+            .output()
+            .ok()
+            .filter(|output| output.status.success())
+            .map(|output| String::from_utf8_lossy(&output.stdout).trim().to_string())
+    };
+    let (Some(revision), Some(git_dir)) = (
+        git(&["rev-parse", "HEAD"]),
+        git(&["rev-parse", "--absolute-git-dir"]),
+    ) else {
+        return;
+    };
+    let dirty = git(&["status", "--porcelain", "--untracked-files=no"])
+        .is_some_and(|status| !status.is_empty());
+    let suffix = if dirty { "-dirty" } else { "" };
+    println!("cargo:rustc-env=FALLING_PETALS_GIT_REVISION={revision}{suffix}");
+    // Listing any file here stops cargo from rerunning this script whenever anything in the package
+    // changes, so list the sources as well as the files git changes on a commit or checkout.
+    for path in ["build.rs", "Cargo.toml", "src", "res"] {
+        println!("cargo:rerun-if-changed={path}");
+    }
+    for file in ["HEAD", "index"] {
+        println!("cargo:rerun-if-changed={git_dir}/{file}");
+    }
+}
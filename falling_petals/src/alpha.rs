@@ -0,0 +1,156 @@
+//! Conversion of frames exported with a transparent background to the alpha mode of the output.
+//! The petals are blended with premultiplied alpha in linear color space, so the rendered frames hold
+//! linear colors multiplied by alpha.  8-bit frames are then sRGB encoded, which matches neither of
+//! the usual conventions (straight alpha, or sRGB encoded colors multiplied by alpha), so they always
+//! need to be converted.  16-bit floating point frames stay linear, which is already what EXR files
+//! with premultiplied alpha hold.
+
+use crate::configuration::AlphaMode;
+use crate::image_sequence::linear_to_srgb;
+
+/// Converts the pixels of frames read back from a video export target with a transparent
+/// background.
+pub struct AlphaConversion {
+    mode: AlphaMode,
+    texture_format: wgpu::TextureFormat,
+    /// For 8-bit sRGB frames, the converted value of each color byte for each alpha byte (indexed
+    /// by color * 256 + alpha).
+    srgb8_table: Vec<u8>,
+}
+
+impl AlphaConversion {
+    pub fn new(mode: AlphaMode, texture_format: wgpu::TextureFormat) -> Self {
+        let srgb8_table = match texture_format {
+            wgpu::TextureFormat::Bgra8UnormSrgb => (0..=255u8)
+                .flat_map(|color| (0..=255u8).map(move |alpha| convert_srgb8(color, alpha, mode)))
+                .collect(),
+            _ => Vec::new(),
+        };
+        Self {
+            mode,
+            texture_format,
+            srgb8_table,
+        }
+    }
+
+    /// Converts a frame (in the texture format it was rendered in) in place.
+    pub fn convert(&self, frame: &mut [u8]) {
+        match self.texture_format {
+            wgpu::TextureFormat::Bgra8UnormSrgb => {
+                for pixel in frame.chunks_exact_mut(4) {
+                    let alpha = usize::from(pixel[3]);
+                    for color in &mut pixel[..3] {
+                        *color = self.srgb8_table[usize::from(*color) * 256 + alpha];
+                    }
+                }
+            }
+            wgpu::TextureFormat::Rgba16Float if self.mode == AlphaMode::Straight => {
+                for pixel in frame.chunks_exact_mut(8) {
+                    let alpha = half::f16::from_le_bytes([pixel[6], pixel[7]]).to_f32();
+                    for color in pixel[..6].chunks_exact_mut(2) {
+                        let value = half::f16::from_le_bytes([color[0], color[1]]).to_f32();
+                        let value = if alpha > 0.0 { value / alpha } else { 0.0 };
+                        color.copy_from_slice(&half::f16::from_f32(value).to_le_bytes());
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+/// Converts an sRGB encoded color value of a pixel rendered with premultiplied alpha (in linear
+/// color space) to the given alpha mode.
+fn convert_srgb8(color: u8, alpha: u8, mode: AlphaMode) -> u8 {
+    if alpha == 0 {
+        return 0;
+    }
+    let alpha = f32::from(alpha) / 255.0;
+    let straight = linear_to_srgb((srgb_to_linear(f32::from(color) / 255.0) / alpha).min(1.0));
+    let converted = match mode {
+        AlphaMode::Straight => straight,
+        AlphaMode::Premultiplied => straight * alpha,
+    };
+    (converted * 255.0).round() as u8
+}
+
+/// The inverse of the sRGB transfer function.
+pub fn srgb_to_linear(value: f32) -> f32 {
+    if value <= 0.04045 {
+        value / 12.92
+    } else {
+        ((value + 0.055) / 1.055).powf(2.4)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{export_frames, gpu_test, headless_test_config};
+
+    #[test]
+    fn converts_srgb8_pixels() {
+        for mode in [AlphaMode::Straight, AlphaMode::Premultiplied] {
+            let conversion = AlphaConversion::new(mode, wgpu::TextureFormat::Bgra8UnormSrgb);
+            // Opaque pixels stay the same, and fully transparent ones become black.
+            let mut frame = [10, 128, 250, 255, 100, 100, 100, 0];
+            conversion.convert(&mut frame);
+            assert_eq!(frame, [10, 128, 250, 255, 0, 0, 0, 0]);
+        }
+        // White at half opacity: half of linear white is 188 once sRGB encoded.
+        let mut frame = [188, 188, 0, 128];
+        AlphaConversion::new(AlphaMode::Straight, wgpu::TextureFormat::Bgra8UnormSrgb)
+            .convert(&mut frame);
+        assert_eq!(frame, [255, 255, 0, 128]);
+        let mut frame = [188, 188, 0, 128];
+        AlphaConversion::new(
+            AlphaMode::Premultiplied,
+            wgpu::TextureFormat::Bgra8UnormSrgb,
+        )
+        .convert(&mut frame);
+        assert_eq!(frame, [128, 128, 0, 128]);
+    }
+
+    #[test]
+    fn converts_rgba16f_pixels() {
+        let to_bytes = |values: [f32; 4]| {
+            values
+                .iter()
+                .flat_map(|&value| half::f16::from_f32(value).to_le_bytes())
+                .collect::<Vec<_>>()
+        };
+        let mut frame = to_bytes([0.25, 0.5, 0.0, 0.5]);
+        AlphaConversion::new(AlphaMode::Straight, wgpu::TextureFormat::Rgba16Float)
+            .convert(&mut frame);
+        assert_eq!(frame, to_bytes([0.5, 1.0, 0.0, 0.5]));
+        let mut frame = to_bytes([0.25, 0.5, 0.0, 0.5]);
+        AlphaConversion::new(AlphaMode::Premultiplied, wgpu::TextureFormat::Rgba16Float)
+            .convert(&mut frame);
+        assert_eq!(frame, to_bytes([0.25, 0.5, 0.0, 0.5]));
+    }
+
+    #[test]
+    fn exports_transparent_background() {
+        let Some((_gpu_lock, directory)) = gpu_test("transparent_background") else {
+            return;
+        };
+        let mut config = headless_test_config("transparent_background", Some(4));
+        config.n_petals = 500; // So that some petals are in view in the first frame
+        config.video_export_backend = crate::configuration::VideoExportBackend::ImageSequence;
+        config.video_export_width = 64;
+        config.video_export_height = 48;
+        config.video_export_file = directory.join("frame_#.png").to_string_lossy().into_owned();
+        config.transparent_background.enabled = true;
+        config.validate().unwrap();
+        export_frames(config, 1);
+        let frame = image::open(directory.join("frame_0.png"))
+            .unwrap()
+            .to_rgba8();
+        // The background is transparent black, while the petals are (at least partly) opaque.
+        assert!(frame.pixels().any(|pixel| pixel.0 == [0, 0, 0, 0]));
+        assert!(frame.pixels().any(|pixel| pixel[3] > 0));
+        assert!(frame
+            .pixels()
+            .all(|pixel| pixel[3] > 0 || pixel.0 == [0, 0, 0, 0]));
+    }
+}
@@ -0,0 +1,460 @@
+//! Export of the rendered frames as an animated GIF or WebP image, for short previews that can be
+//! shared directly (e.g. in a chat) without converting a video first.  Frames are dropped to bring
+//! the frame rate down, and each frame is reduced to a palette of at most 256 colors with the
+//! NeuQuant algorithm, which GIF files need and which keeps the (lossless) WebP files small.
+
+mod webp;
+
+use crate::configuration::{AnimatedImageConfig, ExportTargetConfig};
+use anyhow::{bail, Context};
+use std::fs::File;
+use std::io::BufWriter;
+use std::sync::mpsc::Receiver;
+
+/// Sampling factor of the NeuQuant algorithm, from 1 (the slowest, with the best colors) to 30.
+const QUANTIZER_SAMPLE_FACTOR: i32 = 10;
+
+/// File formats for animated images.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AnimatedImageFormat {
+    Gif,
+    Webp,
+}
+
+impl AnimatedImageFormat {
+    /// Returns the format of the given file by its extension (None if it isn't a GIF or WebP file).
+    pub fn from_file(file: &str) -> Option<Self> {
+        let extension = std::path::Path::new(file).extension()?.to_str()?;
+        match extension.to_ascii_lowercase().as_str() {
+            "gif" => Some(Self::Gif),
+            "webp" => Some(Self::Webp),
+            _ => None,
+        }
+    }
+
+    /// Largest width and height of an image in this format.
+    fn max_size(self) -> u32 {
+        match self {
+            Self::Gif => u32::from(u16::MAX),
+            Self::Webp => webp::MAX_SIZE,
+        }
+    }
+}
+
+/// Returns the size that a target of the given size is rendered at as an animated image: scaled
+/// down (keeping its aspect ratio) to fit within the maximum width and height.
+pub fn fit_size(width: u32, height: u32, config: &AnimatedImageConfig) -> (u32, u32) {
+    let scale = |max_size: u32, size: u32| match max_size {
+        0 => 1.0,
+        max_size => f64::from(max_size) / f64::from(size),
+    };
+    let scale = scale(config.max_width, width).min(scale(config.max_height, height));
+    if scale >= 1.0 {
+        return (width, height);
+    }
+    let scaled = |size: u32| ((f64::from(size) * scale).round() as u32).max(1);
+    (scaled(width), scaled(height))
+}
+
+/// Checks the animated image settings of an export target.
+pub fn validate_target(target: &ExportTargetConfig) -> anyhow::Result<()> {
+    let Some(format) = AnimatedImageFormat::from_file(&target.file) else {
+        bail!(
+            "The animated image {} must be a .gif or .webp file",
+            target.file
+        );
+    };
+    let config = &target.animated_image;
+    if config.frame_rate == 0 {
+        bail!(
+            "animated_image.frame_rate must be at least 1 (export target {})",
+            target.file
+        );
+    }
+    if !(2..=256).contains(&config.colors) {
+        bail!(
+            "animated_image.colors must be between 2 and 256 (export target {})",
+            target.file
+        );
+    }
+    let (width, height) = fit_size(target.width, target.height, config);
+    let max_size = format.max_size();
+    if width > max_size || height > max_size {
+        bail!(
+            "Export target {} would be {width}x{height} pixels, but {format:?} images can be at \
+            most {max_size}x{max_size} (set animated_image.max_width and max_height)",
+            target.file
+        );
+    }
+    Ok(())
+}
+
+/// Creates the directory the animated image will be written to (if needed).
+pub fn prepare_output(file: &str) -> anyhow::Result<()> {
+    match std::path::Path::new(file).parent() {
+        Some(directory) if !directory.as_os_str().is_empty() => std::fs::create_dir_all(directory)
+            .with_context(|| format!("Failed to create directory {}", directory.display())),
+        _ => Ok(()),
+    }
+}
+
+/// Which of the rendered frames are kept in the animation, and how long each one is shown.
+#[derive(Clone, Copy, Debug, PartialEq)]
+struct FrameTiming {
+    /// Every frame_step-th rendered frame is kept.
+    frame_step: u32,
+    video_frame_rate: u32,
+}
+
+impl FrameTiming {
+    /// Keeps the rendered frames (at `video_frame_rate`) closest to the given frame rate.
+    fn new(video_frame_rate: u32, frame_rate: u32) -> Self {
+        let frame_step = (f64::from(video_frame_rate) / f64::from(frame_rate)).round() as u32;
+        Self {
+            frame_step: frame_step.max(1),
+            video_frame_rate,
+        }
+    }
+
+    /// Returns how long the `frame_idx`th frame of the animation is shown, in units of
+    /// 1 / `units_per_second` seconds.  The frames' start times are rounded rather than their
+    /// durations, so that the rounding errors don't add up over the animation.
+    fn duration(self, frame_idx: u64, units_per_second: u64) -> u64 {
+        let start_time = |frame_idx: u64| {
+            let video_frame_rate = u64::from(self.video_frame_rate);
+            (frame_idx * u64::from(self.frame_step) * units_per_second + video_frame_rate / 2)
+                / video_frame_rate
+        };
+        start_time(frame_idx + 1) - start_time(frame_idx)
+    }
+}
+
+/// A frame reduced to a palette.
+struct IndexedFrame {
+    /// RGBA colors of the palette
+    palette: Vec<[u8; 4]>,
+    /// Palette index of each pixel
+    indices: Vec<u8>,
+    /// Index of the transparent color (if any of the pixels are transparent, and only for frames
+    /// with binary alpha)
+    transparent_idx: Option<u8>,
+}
+
+/// Reduces a frame of BGRA pixels (with straight alpha) to a palette of at most `colors` colors.
+/// With `binary_alpha` (for GIF files, whose pixels are either opaque or fully transparent), pixels
+/// less than half opaque become transparent, and the others opaque.
+fn quantize(frame: &[u8], colors: usize, binary_alpha: bool) -> IndexedFrame {
+    let mut pixels = frame
+        .chunks_exact(4)
+        .flat_map(|bgra| [bgra[2], bgra[1], bgra[0], bgra[3]])
+        .collect::<Vec<_>>();
+    let mut transparent = Vec::new();
+    if binary_alpha {
+        transparent = pixels.chunks_exact(4).map(|rgba| rgba[3] < 128).collect();
+        for rgba in pixels.chunks_exact_mut(4) {
+            rgba[3] = u8::MAX;
+        }
+    }
+    let transparent = |pixel_idx: usize| transparent.get(pixel_idx).copied().unwrap_or(false);
+    let opaque_pixels = pixels
+        .chunks_exact(4)
+        .enumerate()
+        .filter(|&(pixel_idx, _)| !transparent(pixel_idx))
+        .flat_map(|(_, rgba)| rgba)
+        .copied()
+        .collect::<Vec<_>>();
+    let has_transparency = opaque_pixels.len() < pixels.len() && binary_alpha;
+
+    let mut palette = Vec::new();
+    let mut quantizer = None;
+    if !opaque_pixels.is_empty() {
+        let n_colors = colors - usize::from(has_transparency);
+        let neu_quant =
+            color_quant::NeuQuant::new(QUANTIZER_SAMPLE_FACTOR, n_colors, &opaque_pixels);
+        palette = neu_quant
+            .color_map_rgba()
+            .chunks_exact(4)
+            .map(|rgba| [rgba[0], rgba[1], rgba[2], rgba[3]])
+            .collect();
+        if binary_alpha {
+            for color in &mut palette {
+                color[3] = u8::MAX;
+            }
+        }
+        quantizer = Some(neu_quant);
+    }
+    let transparent_idx = has_transparency.then(|| {
+        palette.push([0, 0, 0, 0]);
+        (palette.len() - 1) as u8
+    });
+    let indices = pixels
+        .chunks_exact(4)
+        .enumerate()
+        .map(|(pixel_idx, rgba)| match (&quantizer, transparent_idx) {
+            (Some(quantizer), _) if !transparent(pixel_idx) => quantizer.index_of(rgba) as u8,
+            (_, Some(transparent_idx)) => transparent_idx,
+            _ => unreachable!("every pixel is either opaque or transparent"),
+        })
+        .collect();
+    IndexedFrame {
+        palette,
+        indices,
+        transparent_idx,
+    }
+}
+
+/// Writes the frames of an animated image in either format.
+enum AnimationWriter {
+    Gif(gif::Encoder<BufWriter<File>>),
+    Webp(webp::AnimatedWebpWriter<BufWriter<File>>),
+}
+
+impl AnimationWriter {
+    fn new(
+        format: AnimatedImageFormat,
+        file: File,
+        width: u32,
+        height: u32,
+    ) -> anyhow::Result<Self> {
+        let writer = BufWriter::new(file);
+        Ok(match format {
+            AnimatedImageFormat::Gif => {
+                let mut encoder = gif::Encoder::new(writer, width as u16, height as u16, &[])?;
+                encoder.set_repeat(gif::Repeat::Infinite)?;
+                Self::Gif(encoder)
+            }
+            AnimatedImageFormat::Webp => {
+                Self::Webp(webp::AnimatedWebpWriter::new(writer, width, height)?)
+            }
+        })
+    }
+
+    fn format(&self) -> AnimatedImageFormat {
+        match self {
+            Self::Gif(_) => AnimatedImageFormat::Gif,
+            Self::Webp(_) => AnimatedImageFormat::Webp,
+        }
+    }
+
+    /// Writes the `frame_idx`th frame of the animation.
+    fn write_frame(
+        &mut self,
+        frame: IndexedFrame,
+        frame_idx: u64,
+        timing: FrameTiming,
+        width: u32,
+        height: u32,
+    ) -> anyhow::Result<()> {
+        match self {
+            Self::Gif(encoder) => {
+                let gif_frame = gif::Frame {
+                    // GIF delays are in hundredths of a second.
+                    delay: timing.duration(frame_idx, 100) as u16,
+                    // Clear the frame before the next one, so that it doesn't show through the
+                    // next one's transparent pixels.
+                    dispose: gif::DisposalMethod::Background,
+                    transparent: frame.transparent_idx,
+                    width: width as u16,
+                    height: height as u16,
+                    palette: Some(
+                        frame
+                            .palette
+                            .iter()
+                            .flat_map(|&[r, g, b, _]| [r, g, b])
+                            .collect(),
+                    ),
+                    buffer: std::borrow::Cow::Owned(frame.indices),
+                    ..Default::default()
+                };
+                encoder.write_frame(&gif_frame)?;
+            }
+            Self::Webp(writer) => {
+                let rgba = frame
+                    .indices
+                    .iter()
+                    .flat_map(|&idx| frame.palette[usize::from(idx)])
+                    .collect::<Vec<_>>();
+                writer.write_frame(&rgba, timing.duration(frame_idx, 1000) as u32)?
+            }
+        }
+        Ok(())
+    }
+
+    /// Finishes the file and writes out anything still buffered.
+    fn finish(self) -> anyhow::Result<()> {
+        use std::io::Write;
+        let mut writer = match self {
+            Self::Gif(encoder) => encoder.into_inner()?,
+            Self::Webp(writer) => writer.finish()?,
+        };
+        writer.flush()?;
+        Ok(())
+    }
+}
+
+/// Receives the rendered frames (as BGRA pixels, at `video_frame_rate`) and writes the ones kept at
+/// the animation's frame rate to an animated image.  Stops early if writing the file fails.
+pub fn animated_image_thread_fn(
+    receiver: Receiver<Vec<u8>>,
+    output_file: String,
+    config: AnimatedImageConfig,
+    width: u32,
+    height: u32,
+    video_frame_rate: u32,
+) -> anyhow::Result<()> {
+    log::debug!("Animated image thread starting.");
+    let format = AnimatedImageFormat::from_file(&output_file)
+        .with_context(|| format!("{output_file} isn't a .gif or .webp file"))?;
+    let file =
+        File::create(&output_file).with_context(|| format!("Failed to create {output_file}"))?;
+    let mut writer = AnimationWriter::new(format, file, width, height)
+        .with_context(|| format!("Failed to write {output_file}"))?;
+    let timing = FrameTiming::new(video_frame_rate, config.frame_rate);
+    let frames = receiver.iter().step_by(timing.frame_step as usize);
+    for (frame_idx, frame) in (0..).zip(frames) {
+        let binary_alpha = writer.format() == AnimatedImageFormat::Gif;
+        let indexed_frame = quantize(&frame, config.colors as usize, binary_alpha);
+        writer
+            .write_frame(indexed_frame, frame_idx, timing, width, height)
+            .with_context(|| format!("Failed to write {output_file}"))?;
+    }
+    writer
+        .finish()
+        .with_context(|| format!("Failed to write {output_file}"))?;
+    log::debug!("Animated image thread finished.");
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::configuration::VideoExportBackend;
+    use crate::test_support::{export_frames, gpu_test, headless_test_config};
+
+    #[test]
+    fn frames_are_dropped_and_timed_to_the_frame_rate() {
+        let timing = FrameTiming::new(60, 15);
+        assert_eq!(timing.frame_step, 4);
+        let durations = (0..15)
+            .map(|frame_idx| timing.duration(frame_idx, 100))
+            .collect::<Vec<_>>();
+        assert_eq!(&durations[..3], [7, 6, 7]);
+        assert_eq!(durations.iter().sum::<u64>(), 100);
+        assert_eq!(FrameTiming::new(24, 30).frame_step, 1);
+        assert_eq!(FrameTiming::new(30, 20).duration(0, 1000), 67);
+
+        let config = AnimatedImageConfig::default();
+        assert_eq!(fit_size(1920, 1080, &config), (480, 270));
+        assert_eq!(fit_size(1080, 1920, &config), (270, 480));
+        assert_eq!(fit_size(320, 240, &config), (320, 240));
+        let config = AnimatedImageConfig {
+            max_width: 0,
+            max_height: 100,
+            ..config
+        };
+        assert_eq!(fit_size(1920, 1080, &config), (178, 100));
+        assert_eq!(
+            AnimatedImageFormat::from_file("previews/loop.GIF"),
+            Some(AnimatedImageFormat::Gif)
+        );
+        assert_eq!(AnimatedImageFormat::from_file("loop.mp4"), None);
+    }
+
+    #[test]
+    fn transparent_pixels_get_their_own_color() {
+        // Orange pixels, half transparent blue ones, and nearly transparent white ones (BGRA)
+        let colors = [[0, 128, 255, 255], [255, 0, 0, 128], [255, 255, 255, 10]];
+        let frame = colors
+            .iter()
+            .flat_map(|bgra| std::iter::repeat_n(bgra, 300).flatten())
+            .copied()
+            .collect::<Vec<_>>();
+        let assert_close = |actual: [u8; 4], expected: [u8; 4]| {
+            let close = (0..4).all(|channel| actual[channel].abs_diff(expected[channel]) <= 8);
+            assert!(close, "{actual:?} != {expected:?}");
+        };
+
+        let gif_frame = quantize(&frame, 16, true);
+        let transparent_idx = gif_frame.transparent_idx.unwrap();
+        assert_eq!(gif_frame.palette.len(), 16);
+        assert_eq!(gif_frame.palette[usize::from(transparent_idx)], [0; 4]);
+        let gif_color =
+            |pixel_idx: usize| gif_frame.palette[usize::from(gif_frame.indices[pixel_idx])];
+        assert_close(gif_color(0), [255, 128, 0, 255]);
+        assert_close(gif_color(300), [0, 0, 255, 255]);
+        assert_eq!(gif_frame.indices[600], transparent_idx);
+
+        let webp_frame = quantize(&frame, 16, false);
+        assert_eq!(webp_frame.transparent_idx, None);
+        let webp_color =
+            |pixel_idx: usize| webp_frame.palette[usize::from(webp_frame.indices[pixel_idx])];
+        assert_close(webp_color(300), [0, 0, 255, 128]);
+        assert_close(webp_color(600), [255, 255, 255, 10]);
+    }
+
+    #[test]
+    fn exports_animated_gif_and_webp_previews() {
+        let Some((_gpu_lock, directory)) = gpu_test("animated_image") else {
+            return;
+        };
+        let mut config = headless_test_config("animated_image", Some(5));
+        config.n_petals = 300;
+        config.video_export_fps = 60;
+        let animated_image = AnimatedImageConfig {
+            frame_rate: 15,
+            max_width: 64,
+            ..Default::default()
+        };
+        config.export_targets = ["preview.gif", "preview.webp"]
+            .iter()
+            .map(|file| ExportTargetConfig {
+                file: directory.join(file).to_string_lossy().into_owned(),
+                width: 128,
+                height: 96,
+                aspect_ratio: None,
+                backend: VideoExportBackend::AnimatedImage,
+                encoder: Default::default(),
+                image_sequence: Default::default(),
+                animated_image,
+                raw_stream: Default::default(),
+            })
+            .collect();
+        config.validate().unwrap();
+        export_frames(config, 8);
+
+        use image::AnimationDecoder;
+        let open = |file: &str| {
+            std::io::BufReader::new(std::fs::File::open(directory.join(file)).unwrap())
+        };
+        let gif_frames = image::codecs::gif::GifDecoder::new(open("preview.gif"))
+            .unwrap()
+            .into_frames()
+            .collect_frames()
+            .unwrap();
+        let webp_frames = image::codecs::webp::WebPDecoder::new(open("preview.webp"))
+            .unwrap()
+            .into_frames()
+            .collect_frames()
+            .unwrap();
+        // Every 4th frame is kept, at the target's size scaled down to the maximum width.
+        assert_eq!((gif_frames.len(), webp_frames.len()), (2, 2));
+        let delays = webp_frames
+            .iter()
+            .map(|frame| frame.delay().numer_denom_ms())
+            .collect::<Vec<_>>();
+        assert_eq!(delays, [(67, 1), (66, 1)]);
+        for (gif_frame, webp_frame) in gif_frames.iter().zip(&webp_frames) {
+            assert_eq!(gif_frame.buffer().dimensions(), (64, 48));
+            assert_eq!(webp_frame.buffer().dimensions(), (64, 48));
+            assert!(gif_frame.buffer().pixels().any(|pixel| pixel[0] > 0));
+            // Both formats reduce the frames to the same palette.
+            for (gif_pixel, webp_pixel) in gif_frame
+                .buffer()
+                .pixels()
+                .zip(webp_frame.buffer().pixels())
+            {
+                assert_eq!(gif_pixel, webp_pixel);
+            }
+        }
+    }
+}
@@ -0,0 +1,146 @@
+//! Writer for animated WebP files.  The image crate version used here can only decode WebP files,
+//! so each frame is encoded as a lossless (VP8L) image by the image-webp crate, and its image data
+//! is put in a frame of the animation.  See
+//! https://developers.google.com/speed/webp/docs/riff_container.
+
+use std::io::{Seek, SeekFrom, Write};
+
+/// Largest width and height of a WebP image.
+pub const MAX_SIZE: u32 = 1 << 14;
+
+/// Writes the frames of an animation to a WebP file as they come.  The sizes in the file's headers
+/// are filled in by finish().
+pub struct AnimatedWebpWriter<W: Write + Seek> {
+    writer: W,
+    width: u32,
+    height: u32,
+    /// Whether any of the frames has transparent pixels (noted in the file's header)
+    has_alpha: bool,
+}
+
+impl<W: Write + Seek> AnimatedWebpWriter<W> {
+    /// Writes the file header of an endlessly looping animation with a transparent background.
+    pub fn new(mut writer: W, width: u32, height: u32) -> std::io::Result<Self> {
+        writer.write_all(b"RIFF")?;
+        writer.write_all(&0u32.to_le_bytes())?; // The file size is filled in by finish().
+        writer.write_all(b"WEBP")?;
+        writer.write_all(b"VP8X")?;
+        writer.write_all(&10u32.to_le_bytes())?;
+        // The flags are filled in by finish(), followed by 3 reserved bytes.
+        writer.write_all(&[0; 4])?;
+        writer.write_all(&u24_le_bytes(width - 1))?;
+        writer.write_all(&u24_le_bytes(height - 1))?;
+        writer.write_all(b"ANIM")?;
+        writer.write_all(&6u32.to_le_bytes())?;
+        writer.write_all(&[0, 0, 0, 0])?; // Background color
+        writer.write_all(&0u16.to_le_bytes())?; // Loop count (0 loops forever)
+        Ok(Self {
+            writer,
+            width,
+            height,
+            has_alpha: false,
+        })
+    }
+
+    /// Writes a frame covering the whole canvas, given as RGBA pixels.  It is shown for `duration`
+    /// milliseconds.
+    pub fn write_frame(&mut self, rgba: &[u8], duration: u32) -> std::io::Result<()> {
+        self.has_alpha |= rgba.chunks_exact(4).any(|pixel| pixel[3] < u8::MAX);
+        let vp8l = encode_vp8l(rgba, self.width, self.height)?;
+        let mut frame = Vec::with_capacity(24 + vp8l.len() + 1);
+        frame.extend(u24_le_bytes(0)); // X and Y offsets
+        frame.extend(u24_le_bytes(0));
+        frame.extend(u24_le_bytes(self.width - 1));
+        frame.extend(u24_le_bytes(self.height - 1));
+        frame.extend(u24_le_bytes(duration.min(0xff_ffff)));
+        // Frames replace the canvas rather than being blended with the previous one, and aren't
+        // disposed of.
+        frame.push(0b10);
+        frame.extend(b"VP8L");
+        frame.extend((vp8l.len() as u32).to_le_bytes());
+        frame.extend(vp8l);
+        if frame.len() % 2 == 1 {
+            frame.push(0);
+        }
+        self.writer.write_all(b"ANMF")?;
+        self.writer.write_all(&(frame.len() as u32).to_le_bytes())?;
+        self.writer.write_all(&frame)
+    }
+
+    /// Fills in the file size and flags in the file header, and returns the writer.
+    pub fn finish(mut self) -> std::io::Result<W> {
+        let file_size = self.writer.stream_position()?;
+        self.writer.seek(SeekFrom::Start(4))?;
+        self.writer
+            .write_all(&((file_size - 8) as u32).to_le_bytes())?;
+        self.writer.seek(SeekFrom::Start(20))?;
+        let alpha_flag = if self.has_alpha { 0b1_0000 } else { 0 };
+        self.writer.write_all(&[alpha_flag | 0b10])?; // Animation flag
+        self.writer.seek(SeekFrom::Start(file_size))?;
+        self.writer.flush()?;
+        Ok(self.writer)
+    }
+}
+
+fn u24_le_bytes(value: u32) -> [u8; 3] {
+    let [byte0, byte1, byte2, _] = value.to_le_bytes();
+    [byte0, byte1, byte2]
+}
+
+/// Encodes RGBA pixels as a lossless WebP image, and returns its VP8L image data.
+fn encode_vp8l(rgba: &[u8], width: u32, height: u32) -> std::io::Result<Vec<u8>> {
+    let mut image = Vec::new();
+    image_webp::WebPEncoder::new(&mut image)
+        .encode(rgba, width, height, image_webp::ColorType::Rgba8)
+        .map_err(std::io::Error::other)?;
+    // A still image without metadata is written as the RIFF header, followed by a single VP8L chunk
+    // (its name, its size, and its data).
+    match image.get(12..20) {
+        Some([b'V', b'P', b'8', b'L', size @ ..]) => {
+            let size = u32::from_le_bytes(size.try_into().unwrap()) as usize;
+            Ok(image[20..20 + size].to_vec())
+        }
+        _ => Err(std::io::Error::other("Unexpected WebP image layout")),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::AnimationDecoder;
+
+    #[test]
+    fn frames_decode_to_the_written_pixels() {
+        let (width, height) = (37, 21);
+        // A frame with mostly transparent background and some noise, and an opaque one.
+        let mut state = 12345u32;
+        let noisy = (0..width * height)
+            .flat_map(|idx| {
+                state = state.wrapping_mul(1103515245).wrapping_add(12345);
+                if idx % 5 == 0 || idx / width == 3 {
+                    (state >> 8).to_le_bytes()
+                } else {
+                    [0; 4]
+                }
+            })
+            .collect::<Vec<_>>();
+        let striped = (0..width * height)
+            .flat_map(|idx| [[255, 0, 0, 255], [0, 255, 0, 255]][(idx / width % 2) as usize])
+            .collect::<Vec<_>>();
+        let frames = [noisy, striped];
+
+        let mut writer =
+            AnimatedWebpWriter::new(std::io::Cursor::new(Vec::new()), width, height).unwrap();
+        for frame in &frames {
+            writer.write_frame(frame, 40).unwrap();
+        }
+        let file = writer.finish().unwrap().into_inner();
+        let decoder = image::codecs::webp::WebPDecoder::new(std::io::Cursor::new(file)).unwrap();
+        let decoded_frames = decoder.into_frames().collect_frames().unwrap();
+        assert_eq!(decoded_frames.len(), frames.len());
+        for (frame, decoded_frame) in frames.iter().zip(&decoded_frames) {
+            assert_eq!(decoded_frame.delay().numer_denom_ms(), (40, 1));
+            assert_eq!(decoded_frame.buffer().as_raw(), frame);
+        }
+    }
+}
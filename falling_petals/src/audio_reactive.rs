@@ -0,0 +1,367 @@
+//! Audio-reactive mode: the audio file is decoded and analyzed up front, giving the energy of a few
+//! frequency bands for every frame of the simulation.  These energies then drive parameters of the
+//! simulation (as set by the mappings in the config file).  The analysis only depends on the audio
+//! file and the frame index, so rendering stays deterministic and the rendered frames stay in sync
+//! with the same audio when it is muxed into the video.
+
+use crate::configuration::{AudioBand, AudioReactiveConfig, AudioReactiveParameter};
+use anyhow::Context;
+use rustfft::num_complex::Complex;
+use symphonia::core::audio::SampleBuffer;
+use symphonia::core::codecs::DecoderOptions;
+use symphonia::core::formats::FormatOptions;
+use symphonia::core::io::MediaSourceStream;
+use symphonia::core::meta::MetadataOptions;
+use symphonia::core::probe::Hint;
+
+/// Number of audio samples analyzed for each frame.
+const FFT_SIZE: usize = 2048;
+
+/// Frequency range of each band (in Hz).
+fn band_range(band: AudioBand) -> (f32, f32) {
+    match band {
+        AudioBand::Bass => (20.0, 250.0),
+        AudioBand::Mid => (250.0, 4000.0),
+        AudioBand::Treble => (4000.0, 16000.0),
+    }
+}
+
+const BANDS: [AudioBand; 3] = [AudioBand::Bass, AudioBand::Mid, AudioBand::Treble];
+
+/// Energies of the bands for one frame, each between 0 and 1.
+#[derive(Debug, Default, Copy, Clone, PartialEq)]
+pub struct BandEnergies {
+    /// Smoothed energy (rises instantly, decays over the smoothing time)
+    pub level: [f32; 3],
+    /// How much louder the band suddenly got compared to the previous frame
+    pub onset: [f32; 3],
+}
+
+/// Multipliers and offsets applied to the simulation parameters for one frame.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct Modulation {
+    pub gust_strength: f32,
+    pub fall_speed: f32,
+    pub spin_rate: f32,
+    /// Distance to push the petals outward from the center
+    pub burst: f32,
+}
+
+impl Default for Modulation {
+    fn default() -> Self {
+        Self {
+            gust_strength: 1.0,
+            fall_speed: 1.0,
+            spin_rate: 1.0,
+            burst: 0.0,
+        }
+    }
+}
+
+/// Per-frame band energies of an audio file, and how they map to the simulation parameters.
+pub struct AudioReactivity {
+    config: AudioReactiveConfig,
+    energies: Vec<BandEnergies>,
+}
+
+impl AudioReactivity {
+    /// Decodes and analyzes `file`, starting `offset` seconds in (where the video starts).
+    pub fn from_file(
+        config: &AudioReactiveConfig,
+        file: &str,
+        offset: f64,
+        frame_rate: u32,
+    ) -> anyhow::Result<Self> {
+        let (samples, sample_rate) =
+            decode_mono(file).with_context(|| format!("Failed to decode audio file {file}"))?;
+        log::info!(
+            "Analyzing {:.1}s of audio from {file}",
+            samples.len() as f64 / f64::from(sample_rate)
+        );
+        let energies = analyze(&samples, sample_rate, offset, frame_rate, config.smoothing);
+        Ok(Self {
+            config: config.clone(),
+            energies,
+        })
+    }
+
+    /// Returns the band energies for the given frame (all zero after the end of the audio).
+    pub fn energies(&self, frame_idx: u64) -> BandEnergies {
+        usize::try_from(frame_idx)
+            .ok()
+            .and_then(|frame_idx| self.energies.get(frame_idx))
+            .copied()
+            .unwrap_or_default()
+    }
+
+    /// Returns how the simulation parameters are modulated in the given frame.
+    pub fn modulation(&self, frame_idx: u64) -> Modulation {
+        let energies = self.energies(frame_idx);
+        let mut modulation = Modulation::default();
+        for mapping in &self.config.mappings {
+            let band_idx = BANDS.iter().position(|&band| band == mapping.band).unwrap();
+            let level = mapping.amount * energies.level[band_idx];
+            match mapping.parameter {
+                AudioReactiveParameter::GustStrength => modulation.gust_strength += level,
+                AudioReactiveParameter::FallSpeed => modulation.fall_speed += level,
+                AudioReactiveParameter::SpinRate => modulation.spin_rate += level,
+                AudioReactiveParameter::Burst => {
+                    modulation.burst += mapping.amount * energies.onset[band_idx]
+                }
+            }
+        }
+        // Negative amounts can reduce the parameters, but not turn them around.
+        modulation.gust_strength = modulation.gust_strength.max(0.0);
+        modulation.fall_speed = modulation.fall_speed.max(0.0);
+        modulation.spin_rate = modulation.spin_rate.max(0.0);
+        modulation
+    }
+}
+
+/// Decodes an audio file, mixing all its channels down to one.  Returns the samples and the sample
+/// rate.
+fn decode_mono(file: &str) -> anyhow::Result<(Vec<f32>, u32)> {
+    let source = MediaSourceStream::new(Box::new(std::fs::File::open(file)?), Default::default());
+    let mut hint = Hint::new();
+    if let Some(extension) = std::path::Path::new(file).extension() {
+        hint.with_extension(&extension.to_string_lossy());
+    }
+    let probed = symphonia::default::get_probe().format(
+        &hint,
+        source,
+        &FormatOptions::default(),
+        &MetadataOptions::default(),
+    )?;
+    let mut format = probed.format;
+    let track = format
+        .default_track()
+        .context("The file has no audio track")?;
+    let track_id = track.id;
+    let sample_rate = track
+        .codec_params
+        .sample_rate
+        .context("Unknown sample rate")?;
+    let mut decoder =
+        symphonia::default::get_codecs().make(&track.codec_params, &DecoderOptions::default())?;
+    let mut samples = Vec::new();
+    loop {
+        let packet = match format.next_packet() {
+            Ok(packet) => packet,
+            // The end of the file is reported as an unexpected EOF.
+            Err(symphonia::core::errors::Error::IoError(error))
+                if error.kind() == std::io::ErrorKind::UnexpectedEof =>
+            {
+                break
+            }
+            Err(error) => return Err(error.into()),
+        };
+        if packet.track_id() != track_id {
+            continue;
+        }
+        let decoded = decoder.decode(&packet)?;
+        let spec = *decoded.spec();
+        let n_channels = spec.channels.count();
+        let mut buffer = SampleBuffer::<f32>::new(decoded.capacity() as u64, spec);
+        buffer.copy_interleaved_ref(decoded);
+        samples.extend(
+            buffer
+                .samples()
+                .chunks_exact(n_channels)
+                .map(|frame| frame.iter().sum::<f32>() / n_channels as f32),
+        );
+    }
+    Ok((samples, sample_rate))
+}
+
+/// Computes the band energies for each video frame, from the audio starting `offset` seconds in.
+fn analyze(
+    samples: &[f32],
+    sample_rate: u32,
+    offset: f64,
+    frame_rate: u32,
+    smoothing: f32,
+) -> Vec<BandEnergies> {
+    let duration = samples.len() as f64 / f64::from(sample_rate) - offset;
+    let n_frames = (duration * f64::from(frame_rate)).ceil().max(0.0) as usize;
+    // The scalar implementation gives the same results on every CPU (the SIMD ones are chosen based
+    // on the CPU's features), so renders can be reproduced on other machines.
+    let fft = rustfft::FftPlannerScalar::<f32>::new().plan_fft_forward(FFT_SIZE);
+    // Hann window, to reduce the leakage between frequency bins.
+    let window = (0..FFT_SIZE)
+        .map(|idx| 0.5 - 0.5 * (2.0 * std::f32::consts::PI * idx as f32 / FFT_SIZE as f32).cos())
+        .collect::<Vec<_>>();
+    let bin_width = sample_rate as f32 / FFT_SIZE as f32;
+    let mut buffer = vec![Complex::default(); FFT_SIZE];
+    // Amplitude (square root of the total power) of each band in each frame.
+    let raw_energies = (0..n_frames)
+        .map(|frame_idx| {
+            // Analyze the samples centered on the middle of the frame.
+            let center = ((offset + (frame_idx as f64 + 0.5) / f64::from(frame_rate))
+                * f64::from(sample_rate)) as i64;
+            for (idx, value) in buffer.iter_mut().enumerate() {
+                let sample_idx = center - (FFT_SIZE / 2) as i64 + idx as i64;
+                let sample = usize::try_from(sample_idx)
+                    .ok()
+                    .and_then(|sample_idx| samples.get(sample_idx))
+                    .copied()
+                    .unwrap_or(0.0);
+                *value = Complex::new(sample * window[idx], 0.0);
+            }
+            fft.process(&mut buffer);
+            BANDS.map(|band| {
+                let (low, high) = band_range(band);
+                buffer[..FFT_SIZE / 2]
+                    .iter()
+                    .enumerate()
+                    .filter(|(bin, _)| (low..high).contains(&(*bin as f32 * bin_width)))
+                    .map(|(_, value)| value.norm_sqr())
+                    .sum::<f32>()
+                    .sqrt()
+            })
+        })
+        .collect::<Vec<_>>();
+
+    // Normalize each band by its loud passages (rather than its single loudest frame, so that one
+    // spike doesn't make the rest of the track look quiet).
+    let loud = [0, 1, 2].map(|band_idx| {
+        let mut values = raw_energies
+            .iter()
+            .map(|energies| energies[band_idx])
+            .collect::<Vec<_>>();
+        values.sort_unstable_by(f32::total_cmp);
+        values
+            .get(values.len() * 98 / 100)
+            .copied()
+            .unwrap_or_default()
+    });
+    // Bands that are nearly silent throughout the track (compared to the loudest band) stay near
+    // zero, rather than having their noise scaled up.
+    let floor = 0.01 * loud.iter().copied().fold(0.0, f32::max);
+    let scales = loud.map(|loud| {
+        if loud > 0.0 {
+            1.0 / loud.max(floor)
+        } else {
+            0.0
+        }
+    });
+    let decay = if smoothing > 0.0 {
+        (-1.0 / (smoothing * frame_rate as f32)).exp()
+    } else {
+        0.0
+    };
+    let mut level = [0.0f32; 3];
+    raw_energies
+        .iter()
+        .map(|raw| {
+            let mut energies = BandEnergies::default();
+            for band_idx in 0..3 {
+                let value = (raw[band_idx] * scales[band_idx]).min(1.0);
+                energies.onset[band_idx] = (value - level[band_idx]).max(0.0);
+                level[band_idx] = value.max(level[band_idx] * decay);
+                energies.level[band_idx] = level[band_idx];
+            }
+            energies
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::configuration::AudioMappingConfig;
+
+    /// One second of a sine wave of the given frequency (and amplitude), at 48 kHz.
+    fn sine(frequency: f32, amplitude: f32) -> Vec<f32> {
+        (0..48000)
+            .map(|idx| {
+                amplitude * (2.0 * std::f32::consts::PI * frequency * idx as f32 / 48000.0).sin()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn tones_land_in_their_bands() {
+        // A quiet bass tone followed by a loud treble tone.
+        let mut samples = sine(100.0, 0.2);
+        samples.extend(sine(8000.0, 0.8));
+        let energies = analyze(&samples, 48000, 0.0, 30, 0.0);
+        assert_eq!(energies.len(), 60);
+        let bass_frame = energies[15];
+        assert!(bass_frame.level[0] > 0.9, "{bass_frame:?}");
+        assert!(bass_frame.level[2] < 0.01, "{bass_frame:?}");
+        let treble_frame = energies[45];
+        assert!(treble_frame.level[2] > 0.9, "{treble_frame:?}");
+        assert!(treble_frame.level[0] < 0.01, "{treble_frame:?}");
+        // The treble comes in suddenly at the one second mark.
+        assert!(energies[30].onset[2] > 0.5, "{:?}", energies[30]);
+        assert!(energies[40].onset[2] < 0.01, "{:?}", energies[40]);
+
+        // Starting half a second in shifts the frames.
+        let offset_energies = analyze(&samples, 48000, 0.5, 30, 0.0);
+        assert_eq!(offset_energies.len(), 45);
+        for band_idx in 0..3 {
+            let difference = offset_energies[30].level[band_idx] - energies[45].level[band_idx];
+            assert!(difference.abs() < 1e-3);
+        }
+    }
+
+    #[test]
+    fn smoothing_decays_after_peaks() {
+        let mut samples = sine(100.0, 1.0);
+        samples.extend(vec![0.0; 48000]);
+        let energies = analyze(&samples, 48000, 0.0, 30, 0.5);
+        // Half a second after the tone stops, the level has decayed by a factor of e.
+        let decayed = energies[32 + 15].level[0] / energies[32].level[0];
+        assert!((decayed - (-1.0f32).exp()).abs() < 0.05, "{decayed}");
+    }
+
+    #[test]
+    fn decodes_wav_files_and_maps_bands_to_parameters() {
+        // A minimal 16-bit mono WAV file with a bass tone.
+        let samples = sine(60.0, 0.5)
+            .into_iter()
+            .map(|sample| (sample * f32::from(i16::MAX)) as i16)
+            .collect::<Vec<_>>();
+        let data_size = 2 * samples.len() as u32;
+        let mut wav = Vec::new();
+        wav.extend(b"RIFF");
+        wav.extend((36 + data_size).to_le_bytes());
+        wav.extend(b"WAVEfmt ");
+        wav.extend(16u32.to_le_bytes()); // Size of the format chunk
+        wav.extend(1u16.to_le_bytes()); // PCM
+        wav.extend(1u16.to_le_bytes()); // Channels
+        wav.extend(48000u32.to_le_bytes()); // Sample rate
+        wav.extend((2 * 48000u32).to_le_bytes()); // Byte rate
+        wav.extend(2u16.to_le_bytes()); // Block align
+        wav.extend(16u16.to_le_bytes()); // Bits per sample
+        wav.extend(b"data");
+        wav.extend(data_size.to_le_bytes());
+        wav.extend(samples.iter().flat_map(|sample| sample.to_le_bytes()));
+        let path = std::env::temp_dir().join("falling_petals_audio_reactive_test.wav");
+        std::fs::write(&path, wav).unwrap();
+
+        let config = AudioReactiveConfig {
+            enabled: true,
+            smoothing: 0.0,
+            mappings: vec![
+                AudioMappingConfig {
+                    band: AudioBand::Bass,
+                    parameter: AudioReactiveParameter::GustStrength,
+                    amount: 2.0,
+                },
+                AudioMappingConfig {
+                    band: AudioBand::Treble,
+                    parameter: AudioReactiveParameter::FallSpeed,
+                    amount: 2.0,
+                },
+            ],
+        };
+        let reactivity =
+            AudioReactivity::from_file(&config, &path.to_string_lossy(), 0.0, 60).unwrap();
+        assert_eq!(reactivity.energies.len(), 60);
+        let modulation = reactivity.modulation(30);
+        assert!(modulation.gust_strength > 2.8, "{modulation:?}");
+        assert!(modulation.fall_speed < 1.1, "{modulation:?}");
+        assert_eq!(reactivity.modulation(1000), Modulation::default());
+    }
+}
@@ -0,0 +1,273 @@
+//! Auxiliary passes for compositing: a linear depth pass and an ID matte pass, exported alongside
+//! the frames of an export target (the beauty pass).  Each pass is rendered to its own off-screen
+//! texture with the same camera, but with a fragment shader that writes data instead of colors: the
+//! depth buffer value of the nearest petal, or its ID.  Once a frame has been read back, the data is
+//! encoded into the target's output format, so that the pass is written by the same backend (as a
+//! video or an image sequence, with the same frame numbering).
+
+use crate::configuration::{AuxiliaryPassesConfig, ExportPass, IdMatte};
+use crate::graphics::camera::UprightPerspectiveCamera;
+
+/// Returns the auxiliary passes to export for each export target.
+pub fn enabled_passes(config: &AuxiliaryPassesConfig) -> Vec<ExportPass> {
+    let mut passes = Vec::new();
+    if config.depth {
+        passes.push(ExportPass::Depth);
+    }
+    match config.id_matte {
+        IdMatte::None => {}
+        IdMatte::Petal => passes.push(ExportPass::PetalId),
+        IdMatte::Variant => passes.push(ExportPass::VariantId),
+    }
+    passes
+}
+
+/// Returns the file that an auxiliary pass of the given export target is written to.  The name of
+/// the pass is added before the frame number of an image sequence pattern, and otherwise before
+/// the extension (e.g. "loop_depth.mp4" and "frames/petals_depth_######.png").
+pub fn output_file(target_file: &str, pass: ExportPass) -> String {
+    let name = match pass {
+        ExportPass::Beauty => return target_file.to_string(),
+        ExportPass::Depth => "depth",
+        ExportPass::PetalId | ExportPass::VariantId => "id",
+    };
+    let file_name_start = target_file.rfind(['/', '\\']).map_or(0, |idx| idx + 1);
+    let file_name = &target_file[file_name_start..];
+    if let Some(hashes) = file_name.find('#') {
+        let insert_at = file_name_start + hashes;
+        let separator = match target_file[..insert_at].chars().last() {
+            None | Some('_' | '-' | '.' | '/' | '\\') => "",
+            Some(_) => "_",
+        };
+        return format!(
+            "{}{separator}{name}_{}",
+            &target_file[..insert_at],
+            &target_file[insert_at..]
+        );
+    }
+    match file_name.rfind('.').filter(|&idx| idx > 0) {
+        Some(extension) => {
+            let insert_at = file_name_start + extension;
+            format!(
+                "{}_{name}{}",
+                &target_file[..insert_at],
+                &target_file[insert_at..]
+            )
+        }
+        None => format!("{target_file}_{name}"),
+    }
+}
+
+/// Format of the texture an auxiliary pass is rendered to.
+pub fn texture_format(pass: ExportPass) -> wgpu::TextureFormat {
+    match pass {
+        ExportPass::Beauty => wgpu::TextureFormat::Bgra8UnormSrgb,
+        ExportPass::Depth => wgpu::TextureFormat::R32Float,
+        ExportPass::PetalId | ExportPass::VariantId => wgpu::TextureFormat::R32Uint,
+    }
+}
+
+/// Name of the fragment shader entry point that renders the pass.
+pub fn fragment_entry_point(pass: ExportPass) -> &'static str {
+    match pass {
+        ExportPass::Beauty => "fs_textured_vertex",
+        ExportPass::Depth => "fs_depth",
+        ExportPass::PetalId => "fs_petal_id",
+        ExportPass::VariantId => "fs_variant_id",
+    }
+}
+
+/// Value the pass's texture is cleared to before rendering (where there are no petals): the far
+/// plane for the depth pass, and 0 for the ID matte.
+pub fn clear_value(pass: ExportPass) -> f64 {
+    match pass {
+        ExportPass::Depth => 1.0,
+        _ => 0.0,
+    }
+}
+
+/// Encodes the frames of an auxiliary pass (as read back from its texture) into the format the
+/// export backend writes.
+pub struct PassEncoding {
+    pass: ExportPass,
+    /// Format of the frames the export backend writes (see configuration::output_texture_format)
+    output_format: wgpu::TextureFormat,
+    /// Whether depths are written in world units (for EXR files) rather than scaled to [0, 1]
+    world_units: bool,
+}
+
+impl PassEncoding {
+    pub fn new(pass: ExportPass, output_format: wgpu::TextureFormat, world_units: bool) -> Self {
+        Self {
+            pass,
+            output_format,
+            world_units,
+        }
+    }
+
+    /// Encodes a frame of the pass.  The camera's near and far planes are used to linearize the
+    /// depth buffer values.
+    pub fn encode(&self, frame: &[u8], camera: &UprightPerspectiveCamera) -> Vec<u8> {
+        let (near, far) = (camera.z_near, camera.z_far);
+        let values = frame
+            .chunks_exact(4)
+            .map(|bytes| [bytes[0], bytes[1], bytes[2], bytes[3]]);
+        // Each pixel is encoded as red, green, blue, and alpha values from 0 to 1.
+        let pixels = values.map(|bytes| match self.pass {
+            ExportPass::Depth => {
+                let depth_buffer_value = f32::from_le_bytes(bytes);
+                let depth = linear_depth(depth_buffer_value, near, far);
+                let value = if self.world_units {
+                    depth
+                } else {
+                    ((depth - near) / (far - near)).clamp(0.0, 1.0)
+                };
+                let alpha = if depth_buffer_value < 1.0 { 1.0 } else { 0.0 };
+                [value, value, value, alpha]
+            }
+            _ => {
+                let id = u32::from_le_bytes(bytes);
+                let [_, red, green, blue] = id.to_be_bytes().map(|byte| f32::from(byte) / 255.0);
+                let alpha = if id > 0 { 1.0 } else { 0.0 };
+                [red, green, blue, alpha]
+            }
+        });
+        match self.output_format {
+            wgpu::TextureFormat::Rgba16Float => pixels
+                .flat_map(|pixel| pixel.map(|value| half::f16::from_f32(value).to_le_bytes()))
+                .flatten()
+                .collect(),
+            // 8-bit frames are in BGRA order.  The values are written as they are (they are data,
+            // so they aren't sRGB encoded like colors).
+            _ => pixels
+                .flat_map(|[red, green, blue, alpha]| {
+                    [blue, green, red, alpha].map(|value| (value * 255.0).round() as u8)
+                })
+                .collect(),
+        }
+    }
+}
+
+/// Converts a depth buffer value (from 0 at the near plane to 1 at the far plane) back to the
+/// distance from the camera along its view direction.
+fn linear_depth(depth_buffer_value: f32, near: f32, far: f32) -> f32 {
+    near * far / (far - depth_buffer_value * (far - near))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::configuration::{VideoExportBackend, VideoExportConfig};
+    use crate::state::FallingPetalsState;
+    use crate::test_support::{gpu_test, headless_test_config};
+
+    #[test]
+    fn passes_are_written_next_to_the_target() {
+        assert_eq!(output_file("loop.mp4", ExportPass::Depth), "loop_depth.mp4");
+        assert_eq!(
+            output_file("renders/v1.2/loop", ExportPass::PetalId),
+            "renders/v1.2/loop_id"
+        );
+        assert_eq!(
+            output_file("frames/petals_######.png", ExportPass::Depth),
+            "frames/petals_depth_######.png"
+        );
+        assert_eq!(
+            output_file("frames/frame####.exr", ExportPass::VariantId),
+            "frames/frame_id_####.exr"
+        );
+        assert_eq!(output_file("#.png", ExportPass::Depth), "depth_#.png");
+    }
+
+    #[test]
+    fn depths_and_ids_are_encoded() {
+        let camera = UprightPerspectiveCamera {
+            z_near: 0.5,
+            z_far: 10.5,
+            ..Default::default()
+        };
+        // A petal 3 units away (a quarter of the way from the near to the far plane), and the
+        // background.
+        let depth_buffer_value = 10.5 * (3.0 - 0.5) / ((10.5 - 0.5) * 3.0);
+        let frame = [depth_buffer_value, 1.0]
+            .iter()
+            .flat_map(|value: &f32| value.to_le_bytes())
+            .collect::<Vec<_>>();
+        let depth = PassEncoding::new(
+            ExportPass::Depth,
+            wgpu::TextureFormat::Bgra8UnormSrgb,
+            false,
+        )
+        .encode(&frame, &camera);
+        assert_eq!(depth, [64, 64, 64, 255, 255, 255, 255, 0]);
+        let depth = PassEncoding::new(ExportPass::Depth, wgpu::TextureFormat::Rgba16Float, true)
+            .encode(&frame, &camera);
+        let red = half::f16::from_le_bytes([depth[0], depth[1]]).to_f32();
+        assert!((red - 3.0).abs() < 0.01, "{red}");
+
+        let frame = [0x01_02_03u32 + 1, 0]
+            .iter()
+            .flat_map(|value| value.to_le_bytes())
+            .collect::<Vec<_>>();
+        let ids = PassEncoding::new(
+            ExportPass::PetalId,
+            wgpu::TextureFormat::Bgra8UnormSrgb,
+            false,
+        )
+        .encode(&frame, &camera);
+        assert_eq!(ids, [4, 2, 1, 255, 0, 0, 0, 0]);
+    }
+
+    #[test]
+    fn exports_depth_and_id_matte_passes() {
+        let Some((_gpu_lock, directory)) = gpu_test("auxiliary_pass") else {
+            return;
+        };
+        let mut config = headless_test_config("auxiliary_pass", Some(4));
+        config.n_petals = 500;
+        config.video_export_backend = VideoExportBackend::ImageSequence;
+        config.video_export_width = 64;
+        config.video_export_height = 48;
+        config.video_export_file = directory.join("frame_#.png").to_string_lossy().into_owned();
+        config.transparent_background.enabled = true;
+        config.auxiliary_passes.depth = true;
+        config.auxiliary_passes.id_matte = IdMatte::Variant;
+        config.validate().unwrap();
+        let video_export_configs = VideoExportConfig::all_from_config(&config, true);
+        assert_eq!(video_export_configs.len(), 3);
+        let mut simulation_state =
+            FallingPetalsState::new(None, config, video_export_configs).unwrap();
+        let n_variants = simulation_state.graphics_state.petal_variant_data.len() as u32;
+        for _ in 0..2 {
+            simulation_state.update();
+            simulation_state.render().unwrap();
+        }
+        simulation_state.finish_video_export().unwrap();
+        for frame_number in 0..2 {
+            let open = |name: &str| {
+                image::open(directory.join(format!("{name}_{frame_number}.png")))
+                    .unwrap()
+                    .to_rgba8()
+            };
+            let (frame, depth, id) = (open("frame"), open("frame_depth"), open("frame_id"));
+            assert!(depth.pixels().any(|pixel| pixel[3] > 0));
+            for ((frame_pixel, depth_pixel), id_pixel) in
+                frame.pixels().zip(depth.pixels()).zip(id.pixels())
+            {
+                // The passes cover the same pixels as the petals, with the background at the far
+                // plane in the depth pass and 0 in the ID matte.
+                let covered = depth_pixel[3] > 0;
+                assert_eq!(covered, id_pixel[3] > 0);
+                let id = u32::from_be_bytes([0, id_pixel[0], id_pixel[1], id_pixel[2]]);
+                if covered {
+                    assert!(frame_pixel[3] > 0);
+                    assert!(depth_pixel[0] < 255);
+                    assert!((1..=n_variants).contains(&id), "{id}");
+                } else {
+                    assert_eq!(depth_pixel.0, [255, 255, 255, 0]);
+                    assert_eq!(id, 0);
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,206 @@
+//! Checkpoints for resuming long renders after a crash or power cut.  With checkpoints enabled, the
+//! render command writes each video as a series of segments in a checkpoint directory next to the
+//! output, and each time a segment is finished it saves a snapshot of the simulation there.
+//! `falling_petals resume <DIRECTORY>` picks up at the start of the first unfinished segment, and
+//! once all the segments are written they are joined into the final video (and the audio track is
+//! added).  Image sequences are written straight to their numbered files, so for them a checkpoint
+//! just marks how many frames have been written.
+
+use crate::configuration::{VideoExportBackend, VideoExportConfig};
+use crate::sidecar::{CameraKeyframe, RenderSidecar};
+use crate::state::SimulationSnapshot;
+use anyhow::Context;
+use serde::{Deserialize, Serialize};
+use std::path::Path;
+
+/// Name of the checkpoint file within the checkpoint directory.
+const CHECKPOINT_FILE: &str = "checkpoint.toml";
+
+/// Everything needed to resume a render: its settings, how far it got, and the state of the
+/// simulation at that point.
+#[derive(Serialize, Deserialize)]
+pub struct Checkpoint {
+    /// Number of frames in each segment (the interval between checkpoints).
+    pub segment_frames: u64,
+    /// Simulation frame the render starts at.
+    pub start_frame: u64,
+    /// Number of frames in the whole render.
+    pub n_frames: u64,
+    /// Number of segments that have been written completely.
+    pub n_segments: u64,
+    /// Camera path being followed (when reproducing an earlier export).
+    pub camera_path: Vec<CameraKeyframe>,
+    /// Record of the render so far (including its config), written next to the finished video.
+    pub render_sidecar: RenderSidecar,
+    /// State of the simulation at the start of the first unfinished segment.
+    pub snapshot: SimulationSnapshot,
+}
+
+impl Checkpoint {
+    /// Saves the checkpoint to the checkpoint directory.  It is written to a temporary file first
+    /// and then renamed, so that a crash while saving leaves the previous checkpoint intact.
+    pub fn save(&self, directory: &str) -> anyhow::Result<()> {
+        let contents = toml::to_string(self).context("Failed to serialize the checkpoint")?;
+        let path = Path::new(directory).join(CHECKPOINT_FILE);
+        let temporary_path = path.with_extension("toml.tmp");
+        std::fs::write(&temporary_path, contents)
+            .with_context(|| format!("Failed to write {}", temporary_path.display()))?;
+        std::fs::rename(&temporary_path, &path)
+            .with_context(|| format!("Failed to write {}", path.display()))?;
+        log::debug!("Saved checkpoint at frame {}", self.snapshot.frame_idx);
+        Ok(())
+    }
+
+    /// Reads the checkpoint saved in the checkpoint directory.
+    pub fn load(directory: &str) -> anyhow::Result<Self> {
+        let path = Path::new(directory).join(CHECKPOINT_FILE);
+        let contents = std::fs::read_to_string(&path)
+            .with_context(|| format!("Failed to read {}", path.display()))?;
+        toml::from_str(&contents).with_context(|| format!("Failed to parse {}", path.display()))
+    }
+
+    /// Returns the total number of segments in the render (the last one can be shorter).
+    pub fn total_segments(&self) -> u64 {
+        self.n_frames.div_ceil(self.segment_frames)
+    }
+}
+
+/// Returns the checkpoint directory for a render whose (first) output is the given file.
+pub fn checkpoint_directory(output_file: &str) -> String {
+    format!("{output_file}.checkpoint")
+}
+
+/// Returns the file that the given segment of an export target is written to.  Segments keep the
+/// extension of the output file, so that ffmpeg writes them in the same container format.
+pub fn segment_file(
+    directory: &str,
+    target_idx: usize,
+    segment_idx: u64,
+    output_file: &str,
+) -> String {
+    let extension = Path::new(output_file)
+        .extension()
+        .map_or(String::new(), |extension| {
+            format!(".{}", extension.to_string_lossy())
+        });
+    Path::new(directory)
+        .join(format!(
+            "target{target_idx}_segment{segment_idx:05}{extension}"
+        ))
+        .to_string_lossy()
+        .into_owned()
+}
+
+/// Returns the file each export target writes the given segment to.  Image sequences keep writing
+/// to their own file name pattern.
+pub fn segment_files(
+    directory: &str,
+    video_export_configs: &[VideoExportConfig],
+    segment_idx: u64,
+) -> Vec<String> {
+    video_export_configs
+        .iter()
+        .enumerate()
+        .map(
+            |(target_idx, video_export_config)| match video_export_config.backend {
+                VideoExportBackend::Ffmpeg => segment_file(
+                    directory,
+                    target_idx,
+                    segment_idx,
+                    &video_export_config.output_file,
+                ),
+                // Animated images and raw streams can't be written in segments (see
+                // render::check_segment_joining).
+                VideoExportBackend::ImageSequence
+                | VideoExportBackend::AnimatedImage
+                | VideoExportBackend::RawStream => video_export_config.output_file.clone(),
+            },
+        )
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{gpu_test, headless_test_config};
+    use crate::{cli, render, sidecar, state};
+
+    #[test]
+    fn segments_keep_the_output_extension() {
+        let directory = checkpoint_directory("renders/loop.mp4");
+        assert_eq!(directory, "renders/loop.mp4.checkpoint");
+        assert_eq!(
+            segment_file(&directory, 1, 12, "renders/loop.mp4"),
+            "renders/loop.mp4.checkpoint/target1_segment00012.mp4"
+        );
+        assert_eq!(
+            segment_file(&directory, 0, 0, "master"),
+            "renders/loop.mp4.checkpoint/target0_segment00000"
+        );
+    }
+
+    #[test]
+    fn resumed_render_matches_uninterrupted_one() {
+        let Some((_gpu_lock, directory)) = gpu_test("checkpoint") else {
+            return;
+        };
+        let mut config = headless_test_config("checkpoint", Some(11));
+        config.n_petals = 500;
+        config.video_export_backend = VideoExportBackend::ImageSequence;
+        config.video_export_width = 32;
+        config.video_export_height = 24;
+        let output = |name: &str| directory.join(name).to_string_lossy().into_owned();
+        let render_options = cli::RenderOptions {
+            length: cli::RenderLength::Frames(6),
+            start_frame: 0,
+            output: Some(output("plain_#.png")),
+            checkpoint_interval: None,
+        };
+        render::run_render(config.clone(), &render_options, Vec::new()).unwrap();
+
+        // Render the first two segments of two frames each, then stop as if the process had been
+        // killed, leaving only the checkpoint behind.
+        config.video_export_file = output("resumed_#.png");
+        let mut video_export_configs = VideoExportConfig::all_from_config(&config, true);
+        video_export_configs[0].n_frames = Some(6);
+        let mut simulation_state =
+            state::FallingPetalsState::new(None, config, video_export_configs).unwrap();
+        for _ in 0..4 {
+            simulation_state.update();
+            simulation_state.render().unwrap();
+        }
+        let checkpoint_directory = checkpoint_directory(&output("resumed_#.png"));
+        std::fs::create_dir_all(&checkpoint_directory).unwrap();
+        Checkpoint {
+            segment_frames: 2,
+            start_frame: 0,
+            n_frames: 6,
+            n_segments: 2,
+            camera_path: Vec::new(),
+            render_sidecar: simulation_state.render_sidecar.take().unwrap(),
+            snapshot: simulation_state.snapshot(),
+        }
+        .save(&checkpoint_directory)
+        .unwrap();
+        simulation_state.finish_video_export().unwrap();
+        drop(simulation_state);
+
+        render::run_resume(&checkpoint_directory).unwrap();
+        for frame_number in 0..6 {
+            let plain = image::open(directory.join(format!("plain_{frame_number}.png")))
+                .unwrap()
+                .into_rgba8();
+            let resumed = image::open(directory.join(format!("resumed_{frame_number}.png")))
+                .unwrap()
+                .into_rgba8();
+            assert!(plain == resumed, "frame {frame_number} differs");
+        }
+        assert!(!Path::new(&checkpoint_directory).exists());
+        let render_sidecar =
+            RenderSidecar::load(&sidecar::sidecar_path(&output("resumed_#.png"))).unwrap();
+        assert_eq!(
+            (render_sidecar.start_frame, render_sidecar.n_frames),
+            (0, 6)
+        );
+    }
+}
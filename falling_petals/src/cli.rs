@@ -0,0 +1,312 @@
+//! Command-line options.  The program is mostly configured through config.toml, so the command line
+//! only holds options that control how a particular run behaves (e.g. running without a window on
+//! a server with no display).
+
+use anyhow::{bail, Context};
+
+pub const USAGE: &str = "\
+Usage: falling_petals [OPTIONS]
+       falling_petals render (--frames <N> | --duration <SECONDS>) [OPTIONS] [RENDER OPTIONS]
+       falling_petals resume <CHECKPOINT DIRECTORY>
+       falling_petals --reproduce <SIDECAR> [--output <PATH>]
+
+Commands:
+  render              Render an exact number of frames to a video file without opening a window,
+                      as fast as the GPU allows, and exit once ffmpeg has finished writing it.
+  resume              Continue a render that was started with --checkpoint-interval and then
+                      interrupted, from its last checkpoint.
+
+Options:
+  --headless          Render without opening a window, only to the off-screen video export target
+                      (requires --frames).  Falls back to a software adapter if no GPU is available.
+  --frames <N>        Exit after rendering N frames.
+  --seed <SEED>       Seed for the random number generator (overrides random_seed in config.toml).
+  --reproduce <SIDECAR>
+                      Render an earlier export again, using the config, seed, frame range, and
+                      camera path recorded in the .render.toml sidecar written next to it (instead
+                      of config.toml).  Edit the config in the sidecar to change the render.
+  -h, --help          Print this help message and exit.
+
+Render options:
+  --duration <SECONDS>
+                      Length of the video to render (instead of --frames), converted to a frame
+                      count using video_export_fps.
+  --start-frame <N>   Simulate N frames before the first frame written to the video (default 0).
+  --output <PATH>     Video file to write (defaults to video_export_file in config.toml, or the
+                      file recorded in the sidecar with --reproduce).  A .gif or .webp file is
+                      written as an animated image (see animated_image in config.toml), and -
+                      streams raw frames to stdout (see raw_stream in config.toml).
+  --checkpoint-interval <SECONDS>
+                      Write the video in segments of this many seconds, saving a checkpoint after
+                      each one (in a .checkpoint directory next to the output), so that the render
+                      can be resumed if it is interrupted.";
+
+/// Options parsed from the command line.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct CliOptions {
+    /// Render without a window (only to the off-screen video export target).
+    pub headless: bool,
+    /// Number of frames to render before exiting (runs until the window is closed if None).
+    pub frames: Option<u64>,
+    /// Seed for the random number generator (overrides the one in the config file if set).
+    pub seed: Option<u64>,
+    /// Options for the render command (None if the program was not run with that command).
+    pub render: Option<RenderOptions>,
+    /// Options for reproducing an earlier export (None if --reproduce was not given).
+    pub reproduce: Option<ReproduceOptions>,
+    /// Checkpoint directory of the render to resume (None if the program was not run with the
+    /// resume command).
+    pub resume: Option<String>,
+    /// Print the usage message and exit.
+    pub help: bool,
+}
+
+/// Options of the render command.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RenderOptions {
+    /// How much video to render.
+    pub length: RenderLength,
+    /// Number of frames to simulate (without rendering them) before the first rendered frame.
+    pub start_frame: u64,
+    /// Video file to write (the one from the config file is used if None).
+    pub output: Option<String>,
+    /// Length of the segments between checkpoints, in seconds of video (no checkpoints if None).
+    pub checkpoint_interval: Option<f64>,
+}
+
+/// Options for reproducing an earlier export from its sidecar.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ReproduceOptions {
+    /// Sidecar written next to the earlier export.
+    pub sidecar: String,
+    /// Video file to write (the one recorded in the sidecar is used if None).
+    pub output: Option<String>,
+}
+
+/// Length of the video to render, either as a frame count or as a duration.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum RenderLength {
+    Frames(u64),
+    Seconds(f64),
+}
+
+impl RenderLength {
+    /// Returns the number of frames to render at the given frame rate.  Durations are rounded to
+    /// the nearest whole frame.
+    pub fn n_frames(self, frame_rate: u32) -> u64 {
+        match self {
+            RenderLength::Frames(n_frames) => n_frames,
+            RenderLength::Seconds(seconds) => (seconds * f64::from(frame_rate)).round() as u64,
+        }
+    }
+}
+
+impl CliOptions {
+    /// Parses the options from the program's arguments (not including the program name).
+    pub fn parse<I: IntoIterator<Item = String>>(args: I) -> anyhow::Result<Self> {
+        let mut options = CliOptions::default();
+        let mut args = args.into_iter().peekable();
+        if args.next_if(|arg| arg == "resume").is_some() {
+            let directory = args
+                .next()
+                .context("the resume command requires a checkpoint directory")?;
+            if let Some(arg) = args.next() {
+                bail!("the resume command takes no options (got \"{arg}\")");
+            }
+            options.resume = Some(directory);
+            return Ok(options);
+        }
+        let render = args.next_if(|arg| arg == "render").is_some();
+        let mut duration = None;
+        let mut start_frame = None;
+        let mut output = None;
+        let mut reproduce = None;
+        let mut checkpoint_interval = None;
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "--headless" => options.headless = true,
+                "--frames" => options.frames = Some(parse_value(&arg, args.next(), "frame count")?),
+                "--seed" => {
+                    let seed = parse_value(&arg, args.next(), "seed")?;
+                    // Seeds are written to config files and render sidecars, and TOML integers
+                    // are signed 64-bit integers.
+                    if seed > i64::MAX as u64 {
+                        bail!("the seed must be at most {}", i64::MAX);
+                    }
+                    options.seed = Some(seed);
+                }
+                "--duration" if render => {
+                    let seconds: f64 = parse_value(&arg, args.next(), "duration")?;
+                    if !seconds.is_finite() || seconds < 0.0 {
+                        bail!("invalid duration \"{seconds}\"");
+                    }
+                    duration = Some(seconds);
+                }
+                "--start-frame" if render => {
+                    start_frame = Some(parse_value(&arg, args.next(), "start frame")?)
+                }
+                "--checkpoint-interval" if render => {
+                    let seconds: f64 = parse_value(&arg, args.next(), "checkpoint interval")?;
+                    if !seconds.is_finite() || seconds <= 0.0 {
+                        bail!("invalid checkpoint interval \"{seconds}\"");
+                    }
+                    checkpoint_interval = Some(seconds);
+                }
+                "--output" => output = Some(args.next().context("--output requires a value")?),
+                "--reproduce" => {
+                    reproduce = Some(args.next().context("--reproduce requires a value")?)
+                }
+                "--duration" | "--start-frame" | "--checkpoint-interval" => {
+                    bail!("{arg} can only be used with the render command")
+                }
+                "-h" | "--help" => options.help = true,
+                _ => bail!("unknown argument \"{arg}\""),
+            }
+        }
+        if options.help {
+            return Ok(options);
+        }
+        if let Some(sidecar) = reproduce {
+            if render || options.headless || options.frames.is_some() || options.seed.is_some() {
+                bail!(
+                    "--reproduce takes the frame range and seed from the sidecar, so it can't be \
+                    used with the render command, --headless, --frames, or --seed"
+                );
+            }
+            options.reproduce = Some(ReproduceOptions { sidecar, output });
+            return Ok(options);
+        }
+        if output.is_some() && !render {
+            bail!("--output can only be used with the render command or --reproduce");
+        }
+        if render {
+            let length = match (options.frames, duration) {
+                (Some(n_frames), None) => RenderLength::Frames(n_frames),
+                (None, Some(seconds)) => RenderLength::Seconds(seconds),
+                _ => bail!("the render command requires exactly one of --frames or --duration"),
+            };
+            options.render = Some(RenderOptions {
+                length,
+                start_frame: start_frame.unwrap_or(0),
+                output,
+                checkpoint_interval,
+            });
+        } else if options.headless && options.frames.is_none() {
+            bail!("--headless requires --frames");
+        }
+        Ok(options)
+    }
+}
+
+/// Parses the value following the option `option` (described as `description` in errors).
+fn parse_value<T: std::str::FromStr>(
+    option: &str,
+    value: Option<String>,
+    description: &str,
+) -> anyhow::Result<T> {
+    let value = value.with_context(|| format!("{option} requires a value"))?;
+    value
+        .parse()
+        .ok()
+        .with_context(|| format!("invalid {description} \"{value}\""))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(args: &[&str]) -> anyhow::Result<CliOptions> {
+        CliOptions::parse(args.iter().map(|arg| arg.to_string()))
+    }
+
+    #[test]
+    fn parses_headless_frame_count() {
+        assert_eq!(parse(&[]).unwrap(), CliOptions::default());
+        let options = parse(&["--headless", "--frames", "300"]).unwrap();
+        assert!(options.headless);
+        assert_eq!(options.frames, Some(300));
+        assert_eq!(options.render, None);
+    }
+
+    #[test]
+    fn parses_render_command() {
+        let options = parse(&[
+            "render",
+            "--duration",
+            "600",
+            "--start-frame",
+            "120",
+            "--output",
+            "loop.mp4",
+            "--seed",
+            "7",
+        ])
+        .unwrap();
+        assert_eq!(options.seed, Some(7));
+        let render = options.render.unwrap();
+        assert_eq!(render.length, RenderLength::Seconds(600.0));
+        assert_eq!(render.length.n_frames(60), 36000);
+        assert_eq!(render.start_frame, 120);
+        assert_eq!(render.output.as_deref(), Some("loop.mp4"));
+
+        let render = parse(&["render", "--frames", "10"])
+            .unwrap()
+            .render
+            .unwrap();
+        assert_eq!(render.length.n_frames(60), 10);
+        assert_eq!(render.start_frame, 0);
+        assert_eq!(render.output, None);
+        assert_eq!(render.checkpoint_interval, None);
+    }
+
+    #[test]
+    fn rejects_invalid_arguments() {
+        assert!(parse(&["--headless"]).is_err());
+        assert!(parse(&["--frames"]).is_err());
+        assert!(parse(&["--frames", "-1"]).is_err());
+        assert!(parse(&["--seed", "9223372036854775808"]).is_err());
+        assert!(parse(&["--window"]).is_err());
+        assert!(parse(&["--duration", "10"]).is_err());
+        assert!(parse(&["render"]).is_err());
+        assert!(parse(&["render", "--frames", "10", "--duration", "10"]).is_err());
+        assert!(parse(&["render", "--duration", "-5"]).is_err());
+        assert!(parse(&["--frames", "10", "render"]).is_err());
+        assert!(parse(&["--output", "loop.mp4"]).is_err());
+        assert!(parse(&["--checkpoint-interval", "60"]).is_err());
+        assert!(parse(&["render", "--frames", "10", "--checkpoint-interval", "0"]).is_err());
+    }
+
+    #[test]
+    fn parses_checkpoints_and_resume_command() {
+        let render = parse(&["render", "--frames", "10", "--checkpoint-interval", "60"])
+            .unwrap()
+            .render
+            .unwrap();
+        assert_eq!(render.checkpoint_interval, Some(60.0));
+
+        let options = parse(&["resume", "loop.mp4.checkpoint"]).unwrap();
+        assert_eq!(options.resume.as_deref(), Some("loop.mp4.checkpoint"));
+        assert_eq!(options.render, None);
+        assert!(parse(&["resume"]).is_err());
+        assert!(parse(&["resume", "loop.mp4.checkpoint", "--seed", "7"]).is_err());
+    }
+
+    #[test]
+    fn parses_reproduce_option() {
+        let options = parse(&["--reproduce", "loop.mp4.render.toml"]).unwrap();
+        let reproduce = options.reproduce.unwrap();
+        assert_eq!(reproduce.sidecar, "loop.mp4.render.toml");
+        assert_eq!(reproduce.output, None);
+        assert_eq!(options.render, None);
+
+        let options = parse(&["--output", "slower.mp4", "--reproduce", "a.render.toml"]).unwrap();
+        assert_eq!(
+            options.reproduce.unwrap().output.as_deref(),
+            Some("slower.mp4")
+        );
+
+        assert!(parse(&["--reproduce"]).is_err());
+        assert!(parse(&["--reproduce", "a.render.toml", "--seed", "7"]).is_err());
+        assert!(parse(&["render", "--frames", "10", "--reproduce", "a.render.toml"]).is_err());
+    }
+}
@@ -6,11 +6,8 @@ use serde::{Deserialize, Serialize};
 /// other files to exist in any particular location.
 pub const DEFAULT_CONFIG_STR: &str = include_str!("../res/config.toml");
 
-/// Configuration values for the falling petals visualization.  Note that n_petals cannot be set
-/// larger than 1/4 the maximum allowed uniform buffer size of the GPU.  So on a GPU with a maximum
-/// uniform buffer size of 65536 bytes, n_petals cannot be set above 16384.  Doing so will cause a
-/// crash when the program tries to allocate a uniform buffer that is too big.
-#[derive(Serialize, Deserialize)]
+/// Configuration values for the falling petals visualization.
+#[derive(Serialize, Deserialize, Clone)]
 pub struct FallingPetalsConfig {
     /// The number of petals moving around in the simulation volume.
     pub n_petals: usize,
@@ -18,13 +15,40 @@ pub struct FallingPetalsConfig {
     pub min_scale: f32,
     /// Upper bound of the random scale factor applied to each petal.
     pub max_scale: f32,
+    /// Distribution the random scale factor of each petal is drawn from (always limited to the
+    /// range between min_scale and max_scale).
+    #[serde(default)]
+    pub scale_distribution: ScaleDistribution,
+    /// How strongly petal scale is correlated with depth, from 0 (independent) to 1 (fully
+    /// correlated).  Positive values make petals closer to the camera larger, which exaggerates the
+    /// perspective.  Negative values do the opposite.
+    #[serde(default)]
+    pub scale_depth_correlation: f32,
     /// List of texture files and where all the individual petal images are within each texture.
     pub petal_textures: Vec<PetalTextureConfig>,
+    /// Optional named groups of petal textures (e.g. "orange marigold" and "yellow marigold"), each
+    /// with a target ratio.  If any are defined, every texture must be assigned to one of them, and
+    /// the petals are split among the groups according to their ratios.
+    #[serde(default)]
+    pub petal_groups: Vec<PetalGroupConfig>,
+    /// Optional list of petal species, each with its own textures, size, spin, and motion.  If any
+    /// are defined, they replace the top-level petal_textures and petal_groups, and their petal
+    /// counts replace n_petals (except for species given as a fraction of n_petals).
+    #[serde(default)]
+    pub species: Vec<PetalSpeciesConfig>,
+    /// Number of vertices along each side of the grid used to render each petal (at least 2).  The
+    /// default of 3 renders each petal as a fan of 8 triangles, and larger values allow smoother
+    /// curvature profiles at the cost of more vertices per petal.
+    #[serde(default = "default_petal_mesh_resolution")]
+    pub petal_mesh_resolution: u32,
     /// Multiplier to adjust overall amount of petal bend.
     pub petal_bend_vertex_offset_multiplier: f32,
     /// Z-offsets for the 9 vertices (in row-major order) of each petal instance, used to ensure
     /// that the petals do not look perfectly flat.
     pub petal_bend_vertex_offsets: [f32; 9],
+    /// Settings for how the bend of each petal changes over time.
+    #[serde(default)]
+    pub bend_animation: BendAnimationConfig,
     /// Whether or not to limit the live rendering frame rate.  This does not affect the frame rate
     /// of any exported video.
     pub enable_frame_rate_limit: bool,
@@ -75,6 +99,9 @@ pub struct FallingPetalsConfig {
     /// The rotation speed for each petal is randomly chosen between min_rotation_speed and
     /// max_rotation_speed.
     pub max_rotation_speed: Deg<f32>,
+    /// Settings for how each petal's rotation axis and speed change over time.
+    #[serde(default)]
+    pub tumbling: TumblingConfig,
     /// Whether or not to export the rendered visualization to video.  If enabled, ffmpeg must be
     /// installed and visible on the current PATH for it to work.  Enabling this causes each frame
     /// to be rendered a second time to an off-screen buffer, whose pixel values are then piped over
@@ -88,6 +115,47 @@ pub struct FallingPetalsConfig {
     pub video_export_width: u32,
     /// The height (y resolution) of the exported video, if video export is enabled.
     pub video_export_height: u32,
+    /// Settings for how ffmpeg encodes the exported video.
+    #[serde(default)]
+    pub video_encoder: VideoEncoderConfig,
+    /// How exported frames are written: encoded to a video file by ffmpeg, or written directly as
+    /// a sequence of numbered image files (which doesn't need ffmpeg).
+    #[serde(default)]
+    pub video_export_backend: VideoExportBackend,
+    /// Settings for exporting to an image sequence.
+    #[serde(default)]
+    pub image_sequence: ImageSequenceConfig,
+    /// Settings for exporting to an animated GIF or WebP image.
+    #[serde(default)]
+    pub animated_image: AnimatedImageConfig,
+    /// Settings for streaming raw frames.
+    #[serde(default)]
+    pub raw_stream: RawStreamConfig,
+    /// Several export targets (each with its own file, resolution, and encoder settings), all
+    /// rendered from the same simulation frames.  If empty, the single target set by
+    /// video_export_file, video_export_width, etc. is used.
+    #[serde(default)]
+    pub export_targets: Vec<ExportTargetConfig>,
+    /// Settings for rendering exported frames in tiles (for frames larger than the GPU supports)
+    /// and supersampling them.
+    #[serde(default)]
+    pub tiled_export: TiledExportConfig,
+    /// Settings for exporting frames with a transparent background (for compositing).
+    #[serde(default)]
+    pub transparent_background: TransparentBackgroundConfig,
+    /// Auxiliary passes (depth and ID matte) exported alongside each export target.
+    #[serde(default)]
+    pub auxiliary_passes: AuxiliaryPassesConfig,
+    /// Audio track to add to exported videos.
+    #[serde(default)]
+    pub audio: AudioConfig,
+    /// Settings for driving the simulation with the spectrum of the audio file.
+    #[serde(default)]
+    pub audio_reactive: AudioReactiveConfig,
+    /// Seed for the random number generator that sets up and drives the simulation.  Runs with the
+    /// same seed and settings produce identical frames.  A random seed is chosen if not set.
+    #[serde(default)]
+    pub random_seed: Option<u64>,
 }
 
 impl Default for FallingPetalsConfig {
@@ -102,22 +170,1058 @@ impl Default for FallingPetalsConfig {
     }
 }
 
-#[derive(Serialize, Deserialize)]
+impl FallingPetalsConfig {
+    /// Checks that the export target keeps the alpha channel of transparent frames.
+    fn validate_transparent_target(&self, target: &ExportTargetConfig) -> anyhow::Result<()> {
+        match target.backend {
+            VideoExportBackend::Ffmpeg => {
+                let pixel_format = crate::video_encoder::pixel_format(&target.encoder);
+                if !pixel_format.is_some_and(crate::video_encoder::pixel_format_has_alpha) {
+                    anyhow::bail!(
+                        "Export target {} needs an encoder with an alpha channel for a transparent \
+                        background (e.g. the prores_4444 or vp9_alpha preset), but its pixel \
+                        format is {}",
+                        target.file,
+                        pixel_format.unwrap_or("not set")
+                    );
+                }
+            }
+            VideoExportBackend::ImageSequence => {
+                if target.image_sequence.format != ImageSequenceFormat::Exr
+                    && self.transparent_background.alpha == AlphaMode::Premultiplied
+                {
+                    anyhow::bail!(
+                        "PNG files always have straight alpha, so transparent_background.alpha \
+                        must be \"straight\" for export target {} (or use EXR files)",
+                        target.file
+                    );
+                }
+            }
+            VideoExportBackend::AnimatedImage => {
+                if self.transparent_background.alpha == AlphaMode::Premultiplied {
+                    anyhow::bail!(
+                        "GIF and WebP files always have straight alpha, so \
+                        transparent_background.alpha must be \"straight\" for export target {}",
+                        target.file
+                    );
+                }
+            }
+            // Raw frames keep the alpha channel in either mode.
+            VideoExportBackend::RawStream => {}
+        }
+        Ok(())
+    }
+
+    /// Returns the export targets: the export_targets list, or (if it's empty) the single target
+    /// set by the video_export_* settings.
+    pub fn export_targets(&self) -> Vec<ExportTargetConfig> {
+        if !self.export_targets.is_empty() {
+            return self.export_targets.clone();
+        }
+        vec![ExportTargetConfig {
+            file: self.video_export_file.clone(),
+            width: self.video_export_width,
+            height: self.video_export_height,
+            aspect_ratio: None,
+            backend: self.video_export_backend,
+            encoder: self.video_encoder.clone(),
+            image_sequence: self.image_sequence,
+            animated_image: self.animated_image,
+            raw_stream: self.raw_stream,
+        }]
+    }
+
+    /// Checks for settings that parse correctly but are inconsistent with each other.
+    pub fn validate(&self) -> anyhow::Result<()> {
+        validate_scale_settings(
+            "top-level",
+            self.min_scale,
+            self.max_scale,
+            &self.scale_distribution,
+            self.scale_depth_correlation,
+        )?;
+        validate_tumbling("top-level", &self.tumbling)?;
+        validate_bend_animation(&self.bend_animation)?;
+        for target in self.export_targets() {
+            if target.encoder.preset == EncoderPreset::Custom && target.encoder.codec.is_none() {
+                anyhow::bail!(
+                    "The encoder codec must be set when using the custom encoder preset (export \
+                    target {})",
+                    target.file
+                );
+            }
+            if self.transparent_background.enabled {
+                self.validate_transparent_target(&target)?;
+            }
+            if self.auxiliary_passes.id_matte != IdMatte::None
+                && target.backend == VideoExportBackend::AnimatedImage
+            {
+                anyhow::bail!(
+                    "The ID matte pass of export target {} can't be written as an animated image, \
+                    since reducing it to a palette doesn't keep the exact colors",
+                    target.file
+                );
+            }
+            if self.auxiliary_passes.id_matte != IdMatte::None
+                && target.backend == VideoExportBackend::Ffmpeg
+            {
+                let pixel_format = crate::video_encoder::pixel_format(&target.encoder);
+                if !pixel_format.is_some_and(crate::video_encoder::pixel_format_is_rgb) {
+                    anyhow::bail!(
+                        "The ID matte pass of export target {} needs an encoder that keeps the \
+                        exact colors (e.g. the lossless_archive preset), but its pixel format is {}",
+                        target.file,
+                        pixel_format.unwrap_or("not set")
+                    );
+                }
+            }
+            if target.width == 0 || target.height == 0 {
+                anyhow::bail!("Export target {} has a zero width or height", target.file);
+            }
+            if target
+                .aspect_ratio
+                .is_some_and(|aspect_ratio| aspect_ratio <= 0.0)
+            {
+                anyhow::bail!(
+                    "Export target {} has a non-positive aspect_ratio",
+                    target.file
+                );
+            }
+            // Pixel formats with chroma subsampling store the colors at a fraction of the video's
+            // resolution, so its size must be a multiple of the subsampling factors.
+            if target.backend == VideoExportBackend::Ffmpeg {
+                if let Some(pixel_format) = crate::video_encoder::pixel_format(&target.encoder) {
+                    let (x_factor, y_factor) =
+                        crate::video_encoder::chroma_subsampling(pixel_format);
+                    if !target.width.is_multiple_of(x_factor)
+                        || !target.height.is_multiple_of(y_factor)
+                    {
+                        anyhow::bail!(
+                            "Export target {} is {}x{}, but its pixel format {pixel_format} needs a \
+                            width that is a multiple of {x_factor} and a height that is a multiple \
+                            of {y_factor} (change the size, or use a pixel format without chroma \
+                            subsampling such as yuv444p)",
+                            target.file,
+                            target.width,
+                            target.height
+                        );
+                    }
+                }
+            }
+            if target.backend == VideoExportBackend::AnimatedImage {
+                crate::animated_image::validate_target(&target)?;
+            }
+        }
+        let stdout_targets = self
+            .export_targets()
+            .iter()
+            .filter(|target| {
+                target.backend == VideoExportBackend::RawStream
+                    && crate::raw_stream::is_stdout(&target.file)
+            })
+            .count();
+        if stdout_targets > 1
+            || (stdout_targets == 1
+                && !crate::auxiliary_passes::enabled_passes(&self.auxiliary_passes).is_empty())
+        {
+            anyhow::bail!(
+                "Only one export target can stream raw frames to stdout (\"{}\"), and not together \
+                with auxiliary passes; use named pipes for the other outputs",
+                crate::raw_stream::STDOUT
+            );
+        }
+        if self.tiled_export.supersampling == 0 {
+            anyhow::bail!("tiled_export.supersampling must be at least 1");
+        }
+        if self.audio.offset < 0.0 || self.audio.fade_in < 0.0 || self.audio.fade_out < 0.0 {
+            anyhow::bail!("audio.offset, audio.fade_in, and audio.fade_out must not be negative");
+        }
+        if self.audio_reactive.enabled && self.audio.file.is_none() {
+            anyhow::bail!("audio.file must be set when audio_reactive is enabled");
+        }
+        if self.audio_reactive.smoothing < 0.0 {
+            anyhow::bail!("audio_reactive.smoothing must not be negative");
+        }
+        if !(2..=crate::graphics::mesh::MAX_RESOLUTION).contains(&self.petal_mesh_resolution) {
+            anyhow::bail!(
+                "petal_mesh_resolution must be between 2 and {}",
+                crate::graphics::mesh::MAX_RESOLUTION
+            );
+        }
+        if self.species.is_empty() {
+            if self.petal_textures.is_empty() {
+                anyhow::bail!("no petal_textures are defined");
+            }
+            return validate_textures_and_groups(
+                "petal_textures",
+                &self.petal_textures,
+                &self.petal_groups,
+            );
+        }
+        let mut fraction_sum = 0.0;
+        for (idx, species) in self.species.iter().enumerate() {
+            match (species.n_petals, species.fraction) {
+                (Some(_), None) => {}
+                (None, Some(fraction)) => fraction_sum += fraction,
+                _ => anyhow::bail!(
+                    "species[{idx}] (\"{}\") must set exactly one of n_petals or fraction",
+                    species.name,
+                ),
+            }
+            if species.petal_textures.is_empty() {
+                anyhow::bail!(
+                    "species[{idx}] (\"{}\") has no petal_textures",
+                    species.name
+                );
+            }
+            validate_scale_settings(
+                &format!("species[{idx}]"),
+                species.min_scale.unwrap_or(self.min_scale),
+                species.max_scale.unwrap_or(self.max_scale),
+                species
+                    .scale_distribution
+                    .as_ref()
+                    .unwrap_or(&self.scale_distribution),
+                species
+                    .scale_depth_correlation
+                    .unwrap_or(self.scale_depth_correlation),
+            )?;
+            if let Some(tumbling) = &species.tumbling {
+                validate_tumbling(&format!("species[{idx}]"), tumbling)?;
+            }
+            // The species' movement signals are mixtures of sines over movement_period seconds,
+            // with amplitudes interpolated between the lowest and highest of their frequencies.
+            if species.movement_period.unwrap_or(self.movement_period) == 0 {
+                anyhow::bail!(
+                    "species[{idx}] (\"{}\") movement_period must be positive",
+                    species.name
+                );
+            }
+            if species
+                .movement_n_frequencies
+                .unwrap_or(self.movement_n_frequencies)
+                < 2
+            {
+                anyhow::bail!(
+                    "species[{idx}] (\"{}\") movement_n_frequencies must be at least 2",
+                    species.name
+                );
+            }
+            validate_textures_and_groups(
+                &format!("species[{idx}].petal_textures"),
+                &species.petal_textures,
+                &species.petal_groups,
+            )?;
+        }
+        if fraction_sum > 1.0 + 1e-4 {
+            anyhow::bail!("species fractions add up to {fraction_sum}, which is more than 1");
+        }
+        Ok(())
+    }
+
+    /// Returns the fully resolved settings for each petal species.  If no species are defined in
+    /// the config, a single species is built from the top-level petal settings.  Otherwise, any
+    /// settings a species does not specify fall back to the top-level values, and species given as
+    /// a fraction get that share of n_petals (split exactly, so the counts always add up).
+    pub fn resolve_species(&self) -> Vec<PetalSpecies> {
+        if self.species.is_empty() {
+            return vec![PetalSpecies {
+                name: String::from("default"),
+                n_petals: self.n_petals,
+                petal_textures: self.petal_textures.clone(),
+                petal_groups: self.petal_groups.clone(),
+                min_scale: self.min_scale,
+                max_scale: self.max_scale,
+                scale_distribution: self.scale_distribution.clone(),
+                scale_depth_correlation: self.scale_depth_correlation,
+                min_rotation_speed: self.min_rotation_speed,
+                max_rotation_speed: self.max_rotation_speed,
+                tumbling: self.tumbling.clone(),
+                fall_speed: self.fall_speed,
+                movement_period: self.movement_period,
+                movement_n_frequencies: self.movement_n_frequencies,
+                movement_high_freq_max_amplitude: self.movement_high_freq_max_amplitude,
+                movement_low_freq_max_amplitude: self.movement_low_freq_max_amplitude,
+            }];
+        }
+        // Apportion the fractions along with whatever fraction is left over, so that each species
+        // gets its exact share of n_petals (rounded so that the shares always add up).
+        let mut fractions = self
+            .species
+            .iter()
+            .map(|species| species.fraction.unwrap_or(0.0))
+            .collect::<Vec<_>>();
+        fractions.push((1.0 - fractions.iter().sum::<f32>()).max(0.0));
+        let fraction_counts = crate::variant_selection::apportion(self.n_petals, &fractions);
+        self.species
+            .iter()
+            .zip(fraction_counts)
+            .map(|(species, fraction_count)| PetalSpecies {
+                name: species.name.clone(),
+                n_petals: species.n_petals.unwrap_or(fraction_count),
+                petal_textures: species.petal_textures.clone(),
+                petal_groups: species.petal_groups.clone(),
+                min_scale: species.min_scale.unwrap_or(self.min_scale),
+                max_scale: species.max_scale.unwrap_or(self.max_scale),
+                scale_distribution: species
+                    .scale_distribution
+                    .clone()
+                    .unwrap_or_else(|| self.scale_distribution.clone()),
+                scale_depth_correlation: species
+                    .scale_depth_correlation
+                    .unwrap_or(self.scale_depth_correlation),
+                min_rotation_speed: species
+                    .min_rotation_speed
+                    .unwrap_or(self.min_rotation_speed),
+                max_rotation_speed: species
+                    .max_rotation_speed
+                    .unwrap_or(self.max_rotation_speed),
+                tumbling: species
+                    .tumbling
+                    .clone()
+                    .unwrap_or_else(|| self.tumbling.clone()),
+                fall_speed: species.fall_speed.unwrap_or(self.fall_speed),
+                movement_period: species.movement_period.unwrap_or(self.movement_period),
+                movement_n_frequencies: species
+                    .movement_n_frequencies
+                    .unwrap_or(self.movement_n_frequencies),
+                movement_high_freq_max_amplitude: species
+                    .movement_high_freq_max_amplitude
+                    .unwrap_or(self.movement_high_freq_max_amplitude),
+                movement_low_freq_max_amplitude: species
+                    .movement_low_freq_max_amplitude
+                    .unwrap_or(self.movement_low_freq_max_amplitude),
+            })
+            .collect()
+    }
+}
+
+fn validate_tumbling(label: &str, tumbling: &TumblingConfig) -> anyhow::Result<()> {
+    if tumbling.wander_period <= 0.0 || tumbling.flip_duration <= 0.0 {
+        anyhow::bail!("{label} tumbling wander_period and flip_duration must be positive");
+    }
+    Ok(())
+}
+
+fn validate_bend_animation(bend_animation: &BendAnimationConfig) -> anyhow::Result<()> {
+    if bend_animation.min_frequency < 0.0
+        || bend_animation.min_frequency > bend_animation.max_frequency
+    {
+        anyhow::bail!(
+            "bend_animation frequencies must satisfy 0 <= min_frequency <= max_frequency"
+        );
+    }
+    if bend_animation.bend_amplitude < 0.0 || bend_animation.flap_amplitude < 0.0 {
+        anyhow::bail!("bend_animation amplitudes must not be negative");
+    }
+    Ok(())
+}
+
+fn validate_scale_settings(
+    label: &str,
+    min_scale: f32,
+    max_scale: f32,
+    scale_distribution: &ScaleDistribution,
+    scale_depth_correlation: f32,
+) -> anyhow::Result<()> {
+    if min_scale > max_scale {
+        anyhow::bail!("{label} min_scale is larger than max_scale");
+    }
+    if !(-1.0..=1.0).contains(&scale_depth_correlation) {
+        anyhow::bail!("{label} scale_depth_correlation must be between -1 and 1");
+    }
+    match scale_distribution {
+        ScaleDistribution::Uniform => {}
+        ScaleDistribution::LogNormal { median, sigma } => {
+            if *median <= 0.0 || *sigma <= 0.0 || min_scale <= 0.0 {
+                anyhow::bail!(
+                    "{label} log_normal scale distribution needs a positive median, sigma, and \
+                    min_scale"
+                );
+            }
+        }
+        ScaleDistribution::TruncatedNormal { std_dev, .. } => {
+            if *std_dev <= 0.0 {
+                anyhow::bail!(
+                    "{label} truncated_normal scale distribution needs a positive std_dev"
+                );
+            }
+        }
+        ScaleDistribution::Histogram { bin_weights } => {
+            if bin_weights.iter().any(|&weight| weight < 0.0)
+                || bin_weights.iter().sum::<f32>() <= 0.0
+            {
+                anyhow::bail!(
+                    "{label} histogram scale distribution needs non-negative bin_weights with a \
+                    positive sum"
+                );
+            }
+        }
+    }
+    Ok(())
+}
+
+fn validate_textures_and_groups(
+    label: &str,
+    petal_textures: &[PetalTextureConfig],
+    petal_groups: &[PetalGroupConfig],
+) -> anyhow::Result<()> {
+    for (idx, texture) in petal_textures.iter().enumerate() {
+        if !(texture.weight.is_finite() && texture.weight >= 0.0) {
+            anyhow::bail!("{label}[{idx}].weight must be a non-negative number");
+        }
+        if !texture
+            .variant_weights
+            .iter()
+            .all(|weight| weight.is_finite() && *weight >= 0.0)
+        {
+            anyhow::bail!("{label}[{idx}].variant_weights must be non-negative numbers");
+        }
+        if !texture.variant_weights.is_empty()
+            && texture.variant_weights.len() != texture.petal_coordinates.len()
+        {
+            anyhow::bail!(
+                "{label}[{idx}] has {} variant_weights but {} petal_coordinates",
+                texture.variant_weights.len(),
+                texture.petal_coordinates.len(),
+            );
+        }
+        if petal_groups.is_empty() {
+            continue;
+        }
+        match &texture.group {
+            None => anyhow::bail!("{label}[{idx}] has no group, but petal_groups are defined"),
+            Some(group) if !petal_groups.iter().any(|g| &g.name == group) => {
+                anyhow::bail!("{label}[{idx}] uses undefined petal group \"{group}\"")
+            }
+            Some(_) => {}
+        }
+    }
+    // Every petal needs a variant to be chosen for it, so each share of the petals must go to at
+    // least one variant with a positive weight.
+    let weights = crate::variant_selection::variant_weights(petal_textures);
+    let has_positive_weight = |in_share: &dyn Fn(&PetalTextureConfig) -> bool| {
+        petal_textures
+            .iter()
+            .flat_map(|texture| std::iter::repeat_n(texture, texture.petal_coordinates.len()))
+            .zip(&weights)
+            .any(|(texture, &weight)| in_share(texture) && weight > 0.0)
+    };
+    if petal_groups.is_empty() {
+        if !has_positive_weight(&|_| true) {
+            anyhow::bail!("{label} needs at least one petal variant with a positive weight");
+        }
+        return Ok(());
+    }
+    for group in petal_groups {
+        if !(group.ratio.is_finite() && group.ratio >= 0.0) {
+            anyhow::bail!(
+                "The ratio of petal group \"{}\" of {label} must be a non-negative number",
+                group.name
+            );
+        }
+        if !has_positive_weight(&|texture| texture.group.as_ref() == Some(&group.name)) {
+            anyhow::bail!(
+                "Petal group \"{}\" of {label} has no textures with a positive weight",
+                group.name
+            );
+        }
+    }
+    if !petal_groups.iter().any(|group| group.ratio > 0.0) {
+        anyhow::bail!("At least one petal group of {label} must have a positive ratio");
+    }
+    Ok(())
+}
+
+/// Definition of a petal species (e.g. marigold petals, small whole flowers, or leaves), with its
+/// own textures, size, spin, and motion.  Any of the optional settings that are left out fall back
+/// to the corresponding top-level setting in FallingPetalsConfig.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct PetalSpeciesConfig {
+    /// Name of the species (only used for logging and error messages).
+    pub name: String,
+    /// Number of petals of this species.  Exactly one of n_petals and fraction must be set.
+    pub n_petals: Option<usize>,
+    /// Fraction of the top-level n_petals that should be of this species.
+    pub fraction: Option<f32>,
+    /// Texture files and petal images used for this species.
+    pub petal_textures: Vec<PetalTextureConfig>,
+    /// Optional named groups of this species' textures (see FallingPetalsConfig::petal_groups).
+    #[serde(default)]
+    pub petal_groups: Vec<PetalGroupConfig>,
+    pub min_scale: Option<f32>,
+    pub max_scale: Option<f32>,
+    pub scale_distribution: Option<ScaleDistribution>,
+    pub scale_depth_correlation: Option<f32>,
+    pub min_rotation_speed: Option<Deg<f32>>,
+    pub max_rotation_speed: Option<Deg<f32>>,
+    pub tumbling: Option<TumblingConfig>,
+    pub fall_speed: Option<f32>,
+    pub movement_period: Option<u32>,
+    pub movement_n_frequencies: Option<u32>,
+    pub movement_high_freq_max_amplitude: Option<f32>,
+    pub movement_low_freq_max_amplitude: Option<f32>,
+}
+
+/// Fully resolved settings for a single petal species (see FallingPetalsConfig::resolve_species).
+/// The fields have the same meaning as the corresponding fields of FallingPetalsConfig.
+#[derive(Clone)]
+pub struct PetalSpecies {
+    pub name: String,
+    pub n_petals: usize,
+    pub petal_textures: Vec<PetalTextureConfig>,
+    pub petal_groups: Vec<PetalGroupConfig>,
+    pub min_scale: f32,
+    pub max_scale: f32,
+    pub scale_distribution: ScaleDistribution,
+    pub scale_depth_correlation: f32,
+    pub min_rotation_speed: Deg<f32>,
+    pub max_rotation_speed: Deg<f32>,
+    pub tumbling: TumblingConfig,
+    pub fall_speed: f32,
+    pub movement_period: u32,
+    pub movement_n_frequencies: u32,
+    pub movement_high_freq_max_amplitude: f32,
+    pub movement_low_freq_max_amplitude: f32,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
 pub struct PetalTextureConfig {
     pub file: String,
     pub scale: f32,
     pub x_multiplier: f32,
     pub y_multiplier: f32,
     pub petal_coordinates: Vec<[f32; 4]>,
+    /// Relative weight of this texture when choosing petal variants.  It is split among the
+    /// texture's petal images, so it does not matter how many petal images the texture contains.
+    #[serde(default = "default_weight")]
+    pub weight: f32,
+    /// Optional relative weights for each entry in petal_coordinates (all equal if empty).
+    #[serde(default)]
+    pub variant_weights: Vec<f32>,
+    /// Name of the petal group this texture belongs to (if petal groups are used).
+    #[serde(default)]
+    pub group: Option<String>,
+    /// Curvature profile applied to the petals using this texture.
+    #[serde(default)]
+    pub curvature: PetalCurvatureConfig,
+    /// Optional curvature profiles for individual entries in petal_coordinates.  Entries beyond
+    /// the end of this list use the texture's curvature.
+    #[serde(default)]
+    pub variant_curvatures: Vec<PetalCurvatureConfig>,
+}
+
+impl PetalTextureConfig {
+    /// Returns the curvature profile of the petal variant at `variant_idx` within this texture.
+    pub fn variant_curvature(&self, variant_idx: usize) -> &PetalCurvatureConfig {
+        self.variant_curvatures
+            .get(variant_idx)
+            .unwrap_or(&self.curvature)
+    }
+}
+
+/// Shape applied on top of the petal mesh (and the static bend offsets) in the vertex shader.  The
+/// petal spans [-1, 1] in its local x and y directions, with x running along the length of the
+/// petal image.  Each amount is the displacement (relative to the half-width of the petal) at the
+/// edges of the petal, so values around 0.1-0.3 give a noticeable but natural looking shape.  These
+/// look best with a petal_mesh_resolution larger than 3.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(default)]
+pub struct PetalCurvatureConfig {
+    /// Bowl shape, curving all edges of the petal towards +z.
+    pub cup: f32,
+    /// Saddle shape, curving the ends of the petal towards +z and the sides towards -z.
+    pub saddle: f32,
+    /// Curls the tip of the petal (its +x end) towards +z, leaving the other half flat.
+    pub curl: f32,
+    /// Angle the petal twists by around its length (x axis), from its center to either end.
+    pub twist: Deg<f32>,
+}
+
+impl Default for PetalCurvatureConfig {
+    fn default() -> Self {
+        Self {
+            cup: 0.0,
+            saddle: 0.0,
+            curl: 0.0,
+            twist: Deg(0.0),
+        }
+    }
+}
+
+fn default_weight() -> f32 {
+    1.0
+}
+
+fn default_petal_mesh_resolution() -> u32 {
+    3
+}
+
+/// Settings for how each petal's rotation changes over time (see the tumbling module).  With the
+/// default values, every petal spins around a single fixed axis at a constant speed.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(default)]
+pub struct TumblingConfig {
+    /// How far the rotation axis can wander away from its starting direction (0 keeps the axis
+    /// fixed, 1 lets it wander by roughly as much as the length of the axis itself).
+    pub axis_wander: f32,
+    /// Fraction by which the rotation speed can vary up or down from its starting value.
+    pub speed_variation: f32,
+    /// Approximate time (in seconds, based on video_export_fps) over which the rotation axis and
+    /// speed change significantly.
+    pub wander_period: f32,
+    /// Average number of flips per second for each petal.
+    pub flip_probability: f32,
+    /// Time (in seconds, based on video_export_fps) that each flip takes.
+    pub flip_duration: f32,
+}
+
+impl Default for TumblingConfig {
+    fn default() -> Self {
+        Self {
+            axis_wander: 0.0,
+            speed_variation: 0.0,
+            wander_period: 10.0,
+            flip_probability: 0.0,
+            flip_duration: 1.0,
+        }
+    }
+}
+
+/// Settings for how the bend of each petal changes over time.  Each petal flexes periodically, with
+/// a frequency and amplitudes randomly chosen per petal (up to the maximums given here) and a random
+/// phase.  The animation itself is computed in the vertex shader.  With the default values, the
+/// petals keep the static shape given by petal_bend_vertex_offsets.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(default)]
+pub struct BendAnimationConfig {
+    /// Minimum number of bend cycles per second (based on video_export_fps).
+    pub min_frequency: f32,
+    /// Maximum number of bend cycles per second (based on video_export_fps).
+    pub max_frequency: f32,
+    /// Maximum fraction by which the static bend offsets grow and shrink over each cycle.  A value
+    /// of 1.0 lets the bend flatten out completely and then invert.
+    pub bend_amplitude: f32,
+    /// Maximum distance (relative to the petal's half-width) that the tips of the petal flap up and
+    /// down over each cycle.
+    pub flap_amplitude: f32,
+}
+
+impl Default for BendAnimationConfig {
+    fn default() -> Self {
+        Self {
+            min_frequency: 0.5,
+            max_frequency: 1.5,
+            bend_amplitude: 0.0,
+            flap_amplitude: 0.0,
+        }
+    }
+}
+
+/// Settings for how ffmpeg encodes the exported video (see the video_encoder module).  The preset
+/// chooses the codec and its options, which the other settings can override or add to.
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq)]
+#[serde(default)]
+pub struct VideoEncoderConfig {
+    pub preset: EncoderPreset,
+    /// Name of the ffmpeg encoder to use instead of the preset's.  The preset's codec-specific
+    /// options are dropped when this is set.
+    pub codec: Option<String>,
+    /// Pixel format to encode with instead of the preset's.
+    pub pixel_format: Option<String>,
+    /// Target bitrate (e.g. "20M"), passed to ffmpeg as -b:v.
+    pub bitrate: Option<String>,
+    /// Extra arguments passed to ffmpeg after all the other output options (just before the output
+    /// file name), e.g. for codec or container options that aren't covered by the other settings.
+    pub extra_args: Vec<String>,
+}
+
+/// Named sets of ffmpeg encoder settings.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum EncoderPreset {
+    /// H.264 with YouTube's recommended upload settings (for .mp4 files).
+    #[default]
+    Youtube,
+    /// ProRes 422 HQ, for editing and mastering (for .mov files).
+    ProresMaster,
+    /// Lossless FFV1, for archiving (for .mkv files).
+    LosslessArchive,
+    /// ProRes 4444 with an alpha channel, for compositing (for .mov files).
+    #[serde(rename = "prores_4444")]
+    Prores4444,
+    /// VP9 with an alpha channel, for transparent videos on the web (for .webm files).
+    #[serde(rename = "vp9_alpha")]
+    Vp9Alpha,
+    /// No settings of its own, so the codec must be given explicitly.
+    Custom,
 }
 
+/// Settings for the audio track of exported videos (see the video_encoder module).  The audio is
+/// trimmed (or padded with silence) to the length of the video.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(default)]
+pub struct AudioConfig {
+    /// Audio file to add to the video (videos are silent if None).
+    pub file: Option<String>,
+    /// Time (in seconds) into the audio file at which the video starts.
+    pub offset: f64,
+    /// Length (in seconds) of the fade in at the start of the video.
+    pub fade_in: f64,
+    /// Length (in seconds) of the fade out at the end of the video.  Only applied when the length
+    /// of the video is known in advance (e.g. with the render command).
+    pub fade_out: f64,
+    /// Whether to normalize the loudness of the audio (with ffmpeg's loudnorm filter).
+    pub normalize_loudness: bool,
+    /// Integrated loudness (in LUFS) to normalize to.
+    pub target_loudness: f64,
+    /// Name of the ffmpeg audio encoder to use instead of the video_encoder preset's.
+    pub codec: Option<String>,
+    /// Target audio bitrate (e.g. "320k"), passed to ffmpeg as -b:a.
+    pub bitrate: Option<String>,
+}
+
+impl Default for AudioConfig {
+    fn default() -> Self {
+        Self {
+            file: None,
+            offset: 0.0,
+            fade_in: 0.0,
+            fade_out: 0.0,
+            normalize_loudness: false,
+            // The loudness that YouTube and most streaming services normalize to.
+            target_loudness: -14.0,
+            codec: None,
+            bitrate: None,
+        }
+    }
+}
+
+/// Settings for the audio-reactive mode (see the audio_reactive module), in which the energy of the
+/// audio file in a few frequency bands drives parameters of the simulation.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(default)]
+pub struct AudioReactiveConfig {
+    pub enabled: bool,
+    /// Time (in seconds) over which the band energies decay after a peak.
+    pub smoothing: f32,
+    /// How the band energies affect the simulation.
+    pub mappings: Vec<AudioMappingConfig>,
+}
+
+impl Default for AudioReactiveConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            smoothing: 0.15,
+            mappings: Vec::new(),
+        }
+    }
+}
+
+/// Maps the energy of one frequency band to one simulation parameter.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct AudioMappingConfig {
+    pub band: AudioBand,
+    pub parameter: AudioReactiveParameter,
+    /// How strongly the band affects the parameter.  For the parameters that are multiplied, the
+    /// parameter is scaled by (1 + amount * energy), where the energy is between 0 and 1.
+    pub amount: f32,
+}
+
+/// Frequency bands of the audio.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum AudioBand {
+    /// 20 to 250 Hz
+    Bass,
+    /// 250 to 4000 Hz
+    Mid,
+    /// 4000 to 16000 Hz
+    Treble,
+}
+
+/// Simulation parameters that audio bands can drive.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum AudioReactiveParameter {
+    /// Multiplies the sinusoidal movement of the petals (the "wind").
+    GustStrength,
+    /// Multiplies the speed at which the petals fall.
+    FallSpeed,
+    /// Multiplies the rotation speed of the petals.
+    SpinRate,
+    /// Pushes the petals outward from the center of the view when the band gets suddenly louder
+    /// (e.g. on a beat).  The amount is the distance moved per frame for a full-scale onset.
+    Burst,
+}
+
+/// One of several outputs that the same frames are exported to.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct ExportTargetConfig {
+    /// Video file to write (or the file name pattern for an image sequence).
+    pub file: String,
+    pub width: u32,
+    pub height: u32,
+    /// Aspect ratio (width / height) of the view rendered to this target.  Defaults to the ratio
+    /// of the width and height (square pixels), but can be set e.g. for anamorphic formats.
+    #[serde(default)]
+    pub aspect_ratio: Option<f32>,
+    #[serde(default)]
+    pub backend: VideoExportBackend,
+    #[serde(default)]
+    pub encoder: VideoEncoderConfig,
+    #[serde(default)]
+    pub image_sequence: ImageSequenceConfig,
+    #[serde(default)]
+    pub animated_image: AnimatedImageConfig,
+    #[serde(default)]
+    pub raw_stream: RawStreamConfig,
+}
+
+/// Ways of writing the exported frames.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum VideoExportBackend {
+    /// Pipe the frames to ffmpeg, which encodes them into a video file.
+    #[default]
+    Ffmpeg,
+    /// Write each frame to its own image file (see the image_sequence module).
+    ImageSequence,
+    /// Write an animated GIF or WebP image, e.g. as a short preview to share (see the
+    /// animated_image module).
+    AnimatedImage,
+    /// Write the raw pixels of each frame to stdout or a named pipe, for other programs to read (see
+    /// the raw_stream module).
+    RawStream,
+}
+
+/// Settings for exporting frames as an image sequence.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq)]
+#[serde(default)]
+pub struct ImageSequenceConfig {
+    pub format: ImageSequenceFormat,
+    /// Number of threads encoding and writing the images (0 uses one per CPU core).
+    pub n_workers: usize,
+}
+
+/// Settings for exporting frames as an animated GIF or WebP image.  The format is chosen by the
+/// file's extension.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(default)]
+pub struct AnimatedImageConfig {
+    /// Frame rate of the animation.  Frames are dropped to get as close to it as possible (so it is
+    /// at most video_export_fps, which the simulation runs at).
+    pub frame_rate: u32,
+    /// Maximum width of the animation (0 for no limit).  Larger targets are rendered at a smaller
+    /// size that fits, with the same aspect ratio.
+    pub max_width: u32,
+    /// Maximum height of the animation (0 for no limit).
+    pub max_height: u32,
+    /// Number of colors in the palette each frame is reduced to (at most 256).
+    pub colors: u32,
+}
+
+impl Default for AnimatedImageConfig {
+    fn default() -> Self {
+        Self {
+            frame_rate: 15,
+            max_width: 480,
+            max_height: 480,
+            colors: 256,
+        }
+    }
+}
+
+/// Settings for streaming raw frames to stdout or a named pipe.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[serde(default)]
+pub struct RawStreamConfig {
+    /// Order of the color channels of each pixel.
+    pub pixel_format: RawPixelFormat,
+    /// Whether each frame is preceded by a header with its frame number and timestamp.
+    pub frame_header: bool,
+}
+
+/// Channel orders of raw frames.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum RawPixelFormat {
+    /// Blue, green, red, alpha (the order the frames are rendered in).
+    #[default]
+    Bgra,
+    /// Red, green, blue, alpha.
+    Rgba,
+}
+
+/// Settings for rendering exported frames in tiles, and for supersampling them.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(default)]
+pub struct TiledExportConfig {
+    /// Maximum width and height (in pixels) of the tiles that frames are rendered in (0 uses the
+    /// largest texture the GPU supports, so frames are only split up if they don't fit).
+    pub max_tile_size: u32,
+    /// Each frame is rendered at this multiple of its width and height, and then filtered down to
+    /// its size (1 disables supersampling).
+    pub supersampling: u32,
+    /// Filter used to downsample supersampled frames.
+    pub downsample_filter: DownsampleFilter,
+}
+
+impl Default for TiledExportConfig {
+    fn default() -> Self {
+        Self {
+            max_tile_size: 0,
+            supersampling: 1,
+            downsample_filter: DownsampleFilter::default(),
+        }
+    }
+}
+
+/// Filters for downsampling supersampled frames.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum DownsampleFilter {
+    /// Averages the pixels making up each output pixel.
+    Box,
+    /// Tent filter reaching one output pixel to each side (slightly softer than box).
+    Triangle,
+    /// Lanczos filter with 3 lobes (sharpest, at the risk of slight ringing around edges).
+    #[default]
+    Lanczos3,
+}
+
+/// Settings for exporting frames with a transparent background.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq)]
+#[serde(default)]
+pub struct TransparentBackgroundConfig {
+    /// Whether the background of the exported frames is transparent instead of black (the window
+    /// always shows a black background).
+    pub enabled: bool,
+    /// How the colors of partially transparent pixels are stored.
+    pub alpha: AlphaMode,
+}
+
+/// How the colors of exported frames with an alpha channel relate to it.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum AlphaMode {
+    /// Colors are independent of the alpha value (as in PNG files, and what most video codecs with
+    /// alpha assume).
+    #[default]
+    Straight,
+    /// Colors are multiplied by the alpha value (as usual for EXR files and in compositing).
+    Premultiplied,
+}
+
+/// Settings for the auxiliary passes exported alongside each export target (see the
+/// auxiliary_passes module).
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[serde(default)]
+pub struct AuxiliaryPassesConfig {
+    /// Whether to export the linear depth of the nearest petal in each pixel.
+    pub depth: bool,
+    /// What the ID matte pass identifies (if it is exported).
+    pub id_matte: IdMatte,
+}
+
+/// What the ID matte pass identifies.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum IdMatte {
+    /// No ID matte pass is exported.
+    #[default]
+    None,
+    /// Each petal.
+    Petal,
+    /// The variant of each petal.
+    Variant,
+}
+
+/// What is rendered to an export target.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum ExportPass {
+    /// The petals as they are seen (as shown in the window).
+    #[default]
+    Beauty,
+    /// The linear depth of the nearest petal in each pixel.
+    Depth,
+    /// The ID of the nearest petal in each pixel.
+    PetalId,
+    /// The ID of the variant of the nearest petal in each pixel.
+    VariantId,
+}
+
+/// File formats for image sequences.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum ImageSequenceFormat {
+    /// 8-bit sRGB PNG files.
+    #[default]
+    Png8,
+    /// 16-bit sRGB PNG files.
+    Png16,
+    /// OpenEXR files with 32-bit floating point values in linear color space.
+    Exr,
+}
+
+/// Distribution that the random scale factor of each petal is drawn from.  Every distribution is
+/// limited to the range [min_scale, max_scale] (values outside that range are never produced).
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ScaleDistribution {
+    /// Every scale in the range is equally likely.
+    #[default]
+    Uniform,
+    /// The logarithm of the scale is normally distributed, which gives many small petals and a long
+    /// tail of larger ones.
+    LogNormal {
+        /// The median scale (the exponential of the mean of the underlying normal distribution).
+        median: f32,
+        /// Standard deviation of the logarithm of the scale.
+        sigma: f32,
+    },
+    /// A normal distribution cut off at min_scale and max_scale.
+    TruncatedNormal { mean: f32, std_dev: f32 },
+    /// An explicit histogram, with the range split into equally wide bins having the given
+    /// relative weights.  Scales are uniformly distributed within each bin.
+    Histogram { bin_weights: Vec<f32> },
+}
+
+/// A named group of petal textures, and the share of all petals that should come from it.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct PetalGroupConfig {
+    pub name: String,
+    /// Ratio of this group relative to the other groups (only the proportions between the ratios
+    /// matter, so they do not need to add up to 1).
+    pub ratio: f32,
+}
+
+#[derive(Clone)]
 pub struct VideoExportConfig {
     pub export_enabled: bool,
+    /// What is rendered to the target (the frames themselves, or one of the auxiliary passes)
+    pub pass: ExportPass,
     pub output_file: String,
+    pub encoder: VideoEncoderConfig,
+    pub backend: VideoExportBackend,
+    pub image_sequence: ImageSequenceConfig,
+    pub animated_image: AnimatedImageConfig,
+    pub raw_stream: RawStreamConfig,
+    pub transparent_background: TransparentBackgroundConfig,
+    pub tiled_export: TiledExportConfig,
+    pub audio: AudioConfig,
+    /// Number of frames that will be exported, if known in advance (used to fade out the audio).
+    pub n_frames: Option<u64>,
+    /// Number of the first exported frame (image sequence files are numbered from it).
+    pub first_frame_number: u64,
     pub width: u32,
     pub height: u32,
+    /// Aspect ratio (width / height) of the view rendered to the target
+    pub aspect_ratio: f32,
     pub frame_rate: u32,
     pub pixel_count: u32,
+    /// Size in bytes of a frame of pixel data (as sent to ffmpeg or the image sequence writer)
     pub frame_size: u64,
     pub texture_format: wgpu::TextureFormat,
 }
@@ -126,22 +1230,140 @@ impl VideoExportConfig {
     pub fn new(
         export_enabled: bool,
         output_file: String,
+        encoder: VideoEncoderConfig,
         width: u32,
         height: u32,
         frame_rate: u32,
         texture_format: wgpu::TextureFormat,
     ) -> Self {
         let pixel_count = width * height;
+        // E.g. one u32 per pixel for Bgra8UnormSrgb
+        let bytes_per_row = u32::from(texture_format.describe().block_size) * width;
         VideoExportConfig {
             export_enabled,
+            pass: ExportPass::default(),
             output_file,
+            encoder,
+            backend: VideoExportBackend::default(),
+            image_sequence: ImageSequenceConfig::default(),
+            animated_image: AnimatedImageConfig::default(),
+            raw_stream: RawStreamConfig::default(),
+            transparent_background: TransparentBackgroundConfig::default(),
+            tiled_export: TiledExportConfig::default(),
+            audio: AudioConfig::default(),
+            n_frames: None,
+            first_frame_number: 0,
             width,
             height,
+            aspect_ratio: width as f32 / height as f32,
             frame_rate,
             pixel_count,
-            // One u32 per pixel for Bgra8unorm
-            frame_size: std::mem::size_of::<u32>() as u64 * pixel_count as u64,
+            frame_size: u64::from(bytes_per_row) * u64::from(height),
+            texture_format,
+        }
+    }
+
+    /// Creates the video export settings for each of the export targets in the config file, each
+    /// followed by the settings for its auxiliary passes (if any).
+    pub fn all_from_config(config: &FallingPetalsConfig, export_enabled: bool) -> Vec<Self> {
+        let auxiliary_passes = crate::auxiliary_passes::enabled_passes(&config.auxiliary_passes);
+        config
+            .export_targets()
+            .iter()
+            .flat_map(|target| {
+                let video_export_config = Self::from_target(config, target, export_enabled);
+                let auxiliary_pass_configs = auxiliary_passes
+                    .iter()
+                    .map(|&pass| video_export_config.for_auxiliary_pass(pass))
+                    .collect::<Vec<_>>();
+                std::iter::once(video_export_config).chain(auxiliary_pass_configs)
+            })
+            .collect()
+    }
+
+    /// Creates the video export settings for one export target.  The frames are rendered in the
+    /// texture format that the target's export backend needs.  Animated images are rendered at a
+    /// size within their maximum width and height, showing the same view as the full size target.
+    pub fn from_target(
+        config: &FallingPetalsConfig,
+        target: &ExportTargetConfig,
+        export_enabled: bool,
+    ) -> Self {
+        let texture_format = output_texture_format(target.backend, target.image_sequence);
+        let (width, height) = match target.backend {
+            VideoExportBackend::AnimatedImage => {
+                crate::animated_image::fit_size(target.width, target.height, &target.animated_image)
+            }
+            _ => (target.width, target.height),
+        };
+        let video_export_config = VideoExportConfig::new(
+            export_enabled,
+            target.file.clone(),
+            target.encoder.clone(),
+            width,
+            height,
+            config.video_export_fps,
             texture_format,
+        );
+        VideoExportConfig {
+            backend: target.backend,
+            image_sequence: target.image_sequence,
+            animated_image: target.animated_image,
+            raw_stream: target.raw_stream,
+            transparent_background: config.transparent_background,
+            tiled_export: config.tiled_export,
+            audio: config.audio.clone(),
+            aspect_ratio: target
+                .aspect_ratio
+                .unwrap_or(target.width as f32 / target.height as f32),
+            ..video_export_config
+        }
+    }
+
+    /// Creates the settings for exporting an auxiliary pass of this (beauty pass) target.  The pass
+    /// is written next to the target's file, with the same backend and encoder but without audio.
+    /// It is rendered to a texture holding the raw data of the pass, and it isn't supersampled,
+    /// since filtering depths or IDs would make up values that aren't in the frame.
+    pub fn for_auxiliary_pass(&self, pass: ExportPass) -> Self {
+        let video_export_config = VideoExportConfig::new(
+            self.export_enabled,
+            crate::auxiliary_passes::output_file(&self.output_file, pass),
+            self.encoder.clone(),
+            self.width,
+            self.height,
+            self.frame_rate,
+            crate::auxiliary_passes::texture_format(pass),
+        );
+        VideoExportConfig {
+            pass,
+            backend: self.backend,
+            image_sequence: self.image_sequence,
+            animated_image: self.animated_image,
+            raw_stream: self.raw_stream,
+            tiled_export: TiledExportConfig {
+                supersampling: 1,
+                ..self.tiled_export
+            },
+            n_frames: self.n_frames,
+            first_frame_number: self.first_frame_number,
+            aspect_ratio: self.aspect_ratio,
+            ..video_export_config
+        }
+    }
+}
+
+/// Returns the format of the frames that the backend writes: the texture format that the beauty
+/// pass is rendered in, and that the auxiliary passes are converted to.
+pub fn output_texture_format(
+    backend: VideoExportBackend,
+    image_sequence: ImageSequenceConfig,
+) -> wgpu::TextureFormat {
+    match backend {
+        VideoExportBackend::Ffmpeg
+        | VideoExportBackend::AnimatedImage
+        | VideoExportBackend::RawStream => wgpu::TextureFormat::Bgra8UnormSrgb,
+        VideoExportBackend::ImageSequence => {
+            crate::image_sequence::texture_format(image_sequence.format)
         }
     }
 }
@@ -154,4 +1376,252 @@ mod tests {
     fn default_config_parses_without_error() {
         FallingPetalsConfig::default();
     }
+
+    #[test]
+    fn default_config_is_valid() {
+        FallingPetalsConfig::default().validate().unwrap();
+    }
+
+    #[test]
+    fn export_targets_default_to_the_single_video_export_target() {
+        let config = FallingPetalsConfig::default();
+        let targets = config.export_targets();
+        assert_eq!(targets.len(), 1);
+        assert_eq!(targets[0].file, config.video_export_file);
+        assert_eq!(
+            (targets[0].width, targets[0].height),
+            (config.video_export_width, config.video_export_height)
+        );
+
+        let config: FallingPetalsConfig = toml::from_str(&format!(
+            r#"{DEFAULT_CONFIG_STR}
+            [[export_targets]]
+            file = "landscape.mp4"
+            width = 1920
+            height = 1080
+
+            [[export_targets]]
+            file = "portrait/frame_####.png"
+            width = 1080
+            height = 1920
+            backend = "image_sequence"
+
+            [[export_targets]]
+            file = "anamorphic.mov"
+            width = 1440
+            height = 1080
+            aspect_ratio = 1.7777778
+            encoder = {{ preset = "prores_master" }}
+            "#
+        ))
+        .unwrap();
+        config.validate().unwrap();
+        let video_export_configs = VideoExportConfig::all_from_config(&config, true);
+        assert_eq!(video_export_configs.len(), 3);
+        assert_eq!(video_export_configs[0].aspect_ratio, 1920.0 / 1080.0);
+        assert_eq!(
+            video_export_configs[1].backend,
+            VideoExportBackend::ImageSequence
+        );
+        assert_eq!(video_export_configs[2].aspect_ratio, 1.7777778);
+        assert_eq!(
+            video_export_configs[2].encoder.preset,
+            EncoderPreset::ProresMaster
+        );
+    }
+
+    #[test]
+    fn transparent_background_needs_an_alpha_channel() {
+        let mut config = FallingPetalsConfig::default();
+        config.transparent_background.enabled = true;
+        assert!(config.validate().is_err()); // The youtube preset encodes yuv420p
+        config.video_encoder.preset = EncoderPreset::Prores4444;
+        config.validate().unwrap();
+        config.video_encoder.pixel_format = Some(String::from("yuv444p10le"));
+        assert!(config.validate().is_err());
+
+        config.video_export_backend = VideoExportBackend::ImageSequence;
+        config.transparent_background.alpha = AlphaMode::Premultiplied;
+        assert!(config.validate().is_err()); // PNG files can't have premultiplied alpha
+        config.image_sequence.format = ImageSequenceFormat::Exr;
+        config.validate().unwrap();
+    }
+
+    #[test]
+    fn animated_images_are_scaled_down_and_validated() {
+        let mut config = FallingPetalsConfig {
+            video_export_backend: VideoExportBackend::AnimatedImage,
+            video_export_file: String::from("preview.mp4"),
+            ..Default::default()
+        };
+        assert!(config.validate().is_err()); // Not a GIF or WebP file
+        config.video_export_file = String::from("preview.webp");
+        config.validate().unwrap();
+        let video_export_config = &VideoExportConfig::all_from_config(&config, true)[0];
+        assert_eq!(
+            (video_export_config.width, video_export_config.height),
+            crate::animated_image::fit_size(
+                config.video_export_width,
+                config.video_export_height,
+                &config.animated_image
+            )
+        );
+        assert!(video_export_config.width <= config.animated_image.max_width);
+        assert_eq!(
+            video_export_config.aspect_ratio,
+            config.video_export_width as f32 / config.video_export_height as f32
+        );
+
+        config.animated_image.colors = 300;
+        assert!(config.validate().is_err());
+        config.animated_image.colors = 64;
+        config.auxiliary_passes.id_matte = IdMatte::Petal;
+        assert!(config.validate().is_err()); // The palette doesn't keep the IDs
+        config.auxiliary_passes.id_matte = IdMatte::None;
+        config.animated_image.max_width = 0;
+        config.animated_image.max_height = 0;
+        config.video_export_width = 20000;
+        assert!(config.validate().is_err()); // Too wide for a WebP image
+    }
+
+    #[test]
+    fn sizes_must_fit_the_chroma_subsampling() {
+        let mut config = FallingPetalsConfig {
+            video_export_width: 1001,
+            video_export_height: 1000,
+            ..Default::default()
+        };
+        assert!(config.validate().is_err()); // yuv420p needs an even width and height
+        config.video_encoder.preset = EncoderPreset::ProresMaster;
+        assert!(config.validate().is_err()); // yuv422p10le needs an even width
+        config.video_export_width = 1000;
+        config.video_export_height = 1001;
+        config.validate().unwrap();
+        config.video_encoder.preset = EncoderPreset::LosslessArchive;
+        config.video_export_width = 1001;
+        config.validate().unwrap();
+        // Image files aren't chroma subsampled.
+        config.video_encoder.preset = EncoderPreset::Youtube;
+        config.video_export_backend = VideoExportBackend::ImageSequence;
+        config.validate().unwrap();
+    }
+
+    #[test]
+    fn weights_and_group_ratios_must_cover_every_petal() {
+        let texture = |group: &str| PetalTextureConfig {
+            file: String::from("petals.png"),
+            scale: 1.0,
+            x_multiplier: 1.0,
+            y_multiplier: 1.0,
+            petal_coordinates: vec![[0.0, 0.0, 1.0, 1.0]; 2],
+            weight: 1.0,
+            variant_weights: Vec::new(),
+            group: Some(group.to_string()),
+            curvature: Default::default(),
+            variant_curvatures: Vec::new(),
+        };
+        let mut config = FallingPetalsConfig {
+            petal_textures: vec![texture("petals"), texture("leaves")],
+            petal_groups: vec![
+                PetalGroupConfig {
+                    name: String::from("petals"),
+                    ratio: 3.0,
+                },
+                PetalGroupConfig {
+                    name: String::from("leaves"),
+                    ratio: 1.0,
+                },
+            ],
+            ..Default::default()
+        };
+        config.validate().unwrap();
+
+        config.petal_textures[1].weight = f32::INFINITY;
+        assert!(config.validate().is_err());
+        config.petal_textures[1].weight = -1.0;
+        assert!(config.validate().is_err());
+        config.petal_textures[1].weight = 0.0;
+        assert!(config.validate().is_err()); // The leaves would get no variants
+        config.petal_textures[1].weight = 1.0;
+        config.petal_textures[1].variant_weights = vec![0.0; 2];
+        assert!(config.validate().is_err());
+        config.petal_textures[1].variant_weights.clear();
+        config.petal_textures.pop();
+        assert!(config.validate().is_err()); // No textures in the leaves group
+        config.petal_groups.pop();
+        config.validate().unwrap();
+        config.petal_groups[0].ratio = f32::NAN;
+        assert!(config.validate().is_err());
+        config.petal_groups[0].ratio = 0.0;
+        assert!(config.validate().is_err());
+    }
+
+    #[test]
+    fn only_one_target_streams_to_stdout() {
+        let mut config = FallingPetalsConfig {
+            video_export_backend: VideoExportBackend::RawStream,
+            video_export_file: String::from(crate::raw_stream::STDOUT),
+            ..Default::default()
+        };
+        config.validate().unwrap();
+        config.auxiliary_passes.depth = true;
+        assert!(config.validate().is_err()); // The depth pass has nowhere to go
+        config.auxiliary_passes.depth = false;
+        config.export_targets = config.export_targets();
+        config.validate().unwrap();
+        config.export_targets.push(config.export_targets[0].clone());
+        assert!(config.validate().is_err());
+        config.export_targets[1].file = String::from("petals.fifo");
+        config.validate().unwrap();
+    }
+
+    #[test]
+    fn species_fractions_and_overrides_resolve() {
+        let mut config: FallingPetalsConfig = toml::from_str(&format!(
+            r#"{DEFAULT_CONFIG_STR}
+            [[species]]
+            name = "petals"
+            fraction = 0.7
+            fall_speed = 0.1
+            [[species.petal_textures]]
+            file = "petals.png"
+            scale = 1.0
+            x_multiplier = 1.0
+            y_multiplier = 1.0
+            petal_coordinates = [[0, 0, 1, 1]]
+
+            [[species]]
+            name = "leaves"
+            n_petals = 25
+            [[species.petal_textures]]
+            file = "leaves.png"
+            scale = 1.0
+            x_multiplier = 1.0
+            y_multiplier = 1.0
+            petal_coordinates = [[0, 0, 1, 1], [1, 0, 1, 1]]
+            curvature = {{ curl = 0.2, twist = 10.0 }}
+            variant_curvatures = [{{ cup = 0.3 }}]
+            "#
+        ))
+        .unwrap();
+        config.validate().unwrap();
+        let species = config.resolve_species();
+        assert_eq!(
+            species[0].n_petals,
+            (0.7 * config.n_petals as f32).round() as usize
+        );
+        assert_eq!(species[0].fall_speed, 0.1);
+        assert_eq!(species[1].n_petals, 25);
+        assert_eq!(species[1].fall_speed, config.fall_speed);
+        let leaves = &species[1].petal_textures[0];
+        assert_eq!(leaves.variant_curvature(0).cup, 0.3);
+        assert_eq!(leaves.variant_curvature(0).twist, Deg(0.0));
+        assert_eq!(leaves.variant_curvature(1).twist, Deg(10.0));
+
+        config.species[1].movement_period = Some(0);
+        assert!(config.validate().is_err());
+        config.species[1].movement_period = None;
+        config.species[1].movement_n_frequencies = Some(1);
+        assert!(config.validate().is_err());
+    }
 }
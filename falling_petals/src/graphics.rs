@@ -1,13 +1,18 @@
 pub mod camera;
 pub mod gpu_types;
+pub mod mesh;
 pub mod texture;
 
-use crate::configuration::{FallingPetalsConfig, VideoExportConfig};
+use crate::configuration::{
+    ExportPass, FallingPetalsConfig, ImageSequenceFormat, VideoExportBackend, VideoExportConfig,
+};
 use crate::state::PetalState;
+use crate::tiling::TileLayout;
+use anyhow::Context;
 use camera::Camera;
 use cgmath::prelude::*;
-use gpu_types::{PositionTextureVertex, VertexBufferEntry};
-use std::io::Write;
+use gpu_types::{PetalInstanceData, PositionTextureVertex, VertexBufferEntry};
+use std::io::{BufRead, Write};
 use texture::Texture;
 use wgpu::util::DeviceExt;
 use winit::window::Window; // Needed for the device.create_buffer_init() function
@@ -31,23 +36,54 @@ unsafe fn vec_as_u8_slice<T: Sized>(array: &Vec<T>) -> &[u8] {
     )
 }
 
-/// Index list that defines the tesselation of the 3x3 grid of vertices used to render each petal.
-/// In this case, all triangles share the center vertex (it creates fan of 8 triangles around the
-/// center vertex).
-const TEXTURED_SQUARE_INDICES: &[u16; 24] = &[
-    0, 4, 1, //
-    1, 4, 2, //
-    2, 4, 5, //
-    5, 4, 8, //
-    8, 4, 7, //
-    7, 4, 6, //
-    6, 4, 3, //
-    3, 4, 0, //
-];
+/// How the petal textures are made available to the fragment shader.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum PetalTextureMode {
+    /// Each petal texture is bound separately (with its own sampler) through binding arrays.  This
+    /// requires native-only GPU features that are not available on every adapter.
+    BindingArray,
+    /// All petal textures are resized to a common size and packed into the layers of a single 2D
+    /// texture array.  This works on any adapter (including the GL backend).
+    TextureArray,
+}
+
+impl PetalTextureMode {
+    /// GPU features that must be enabled on the device to use this mode.
+    pub fn required_features(self) -> wgpu::Features {
+        match self {
+            PetalTextureMode::BindingArray => {
+                wgpu::Features::TEXTURE_BINDING_ARRAY
+                    | wgpu::Features::SAMPLED_TEXTURE_AND_STORAGE_BUFFER_ARRAY_NON_UNIFORM_INDEXING
+            }
+            PetalTextureMode::TextureArray => wgpu::Features::empty(),
+        }
+    }
+
+    /// WGSL declarations of the petal textures and the sample_petal_texture() function used by
+    /// this mode (inserted into shader.wgsl when it is loaded).
+    fn shader_source(self) -> &'static str {
+        match self {
+            PetalTextureMode::BindingArray => {
+                include_str!("graphics/petal_textures_binding_array.wgsl")
+            }
+            PetalTextureMode::TextureArray => {
+                include_str!("graphics/petal_textures_texture_array.wgsl")
+            }
+        }
+    }
+}
+
+/// Number of staging buffers that video frames are read back through.  While the oldest frame is
+/// being read back, the GPU can keep rendering the following frames into the other buffers.
+const VIDEO_READBACK_BUFFER_COUNT: usize = 3;
+
+/// Minimum time between log messages about the progress of a video export.
+const VIDEO_EXPORT_PROGRESS_LOG_INTERVAL: std::time::Duration = std::time::Duration::from_secs(10);
 
 enum RenderTarget<'a> {
     Screen(&'a wgpu::TextureView),
-    Video,
+    /// The off-screen texture of the video export target with the given index
+    Video(usize),
 }
 
 pub struct GraphicsState {
@@ -58,9 +94,11 @@ pub struct GraphicsState {
     pub queue: wgpu::Queue,
 
     // Rendering to screen -------------------------------------------------------------------------
-    /// The surface to render to (usually that of the window / screen)
-    pub surface: wgpu::Surface,
-    /// Configuration for the rendering surface
+    /// The surface to render to (usually that of the window / screen).  None when running headless,
+    /// in which case only the off-screen video export target is rendered to.
+    pub surface: Option<wgpu::Surface>,
+    /// Configuration for the rendering surface (when running headless, this just tracks the size
+    /// of the video export target)
     pub surface_config: wgpu::SurfaceConfiguration,
     /// Current size of the rendering surface
     pub size: winit::dpi::PhysicalSize<u32>,
@@ -70,17 +108,19 @@ pub struct GraphicsState {
     pub render_pipeline: wgpu::RenderPipeline,
 
     // Rendering to video --------------------------------------------------------------------------
-    pub video_export_state: Option<VideoExportState>,
+    /// One entry per export target (empty if there is nothing to render off-screen)
+    pub video_export_states: Vec<VideoExportState>,
 
     // Instance data -------------------------------------------------------------------------------
     /// For each petal, gpu compatible data specifying its location/orientation/scale
     pub petal_pose_data: Vec<gpu_types::Matrix4>,
     /// Handle to buffer for the data specifying each petal's location/orientation/scale
     pub petal_pose_buffer: wgpu::Buffer,
-    /// For each petal, the index into which variant it is
-    pub petal_variant_index_data: Vec<u32>,
-    /// Handle to buffer containing a variant index for each petal
-    pub petal_variant_index_buffer: wgpu::Buffer,
+    /// For each petal, the other per-instance data used by the shaders (variant index and bend
+    /// animation)
+    pub petal_instance_data: Vec<PetalInstanceData>,
+    /// Handle to buffer containing the other per-instance data for each petal
+    pub petal_instance_buffer: wgpu::Buffer,
     /// For each petal variant, data specifying which portion of which texture to use for that
     /// variant
     pub petal_variant_data: Vec<gpu_types::PetalVariant>,
@@ -93,9 +133,13 @@ pub struct GraphicsState {
     pub camera_uniform: gpu_types::Matrix4,
     pub camera_buffer: wgpu::Buffer,
     pub camera_bind_group: wgpu::BindGroup,
+    // Current simulation time (used to animate the petal bend in the vertex shader).  It is in the
+    // same bind group as the camera.
+    pub time_uniform: gpu_types::SimulationTimeUniform,
+    pub time_buffer: wgpu::Buffer,
 
-    // Textured square used to draw petals
-    pub textured_square_vertices: [PositionTextureVertex; 9],
+    // Textured square (grid of vertices) used to draw petals
+    pub textured_square_vertices: Vec<PositionTextureVertex>,
     pub textured_square_vertex_buffer: wgpu::Buffer,
     pub textured_square_index_buffer: wgpu::Buffer,
     pub n_textured_square_indices: u32,
@@ -105,44 +149,162 @@ pub struct GraphicsState {
 pub struct VideoExportState {
     /// Config values for video export (if doing video export)
     pub video_config: VideoExportConfig,
-    /// Texture to render each video frame to
+    /// How the frames are split into tiles (a single tile unless they are larger than the GPU
+    /// supports, or supersampled)
+    pub tile_layout: TileLayout,
+    /// Size in bytes of each row of pixel data of a rendered tile
+    pub tile_bytes_per_row: u32,
+    /// Size in bytes of each row of pixel data of a tile when copied out of the GPU, which is
+    /// padded to a multiple of wgpu::COPY_BYTES_PER_ROW_ALIGNMENT
+    pub padded_tile_bytes_per_row: u32,
+    /// The frame that the tiles read back so far are stitched into (when rendering in tiles)
+    pub assembled_frame: Vec<u8>,
+    /// The camera, with this target's aspect ratio, for the frame being rendered
+    pub camera: camera::UprightPerspectiveCamera,
+    /// Texture to render each video frame (or tile) to
     pub video_texture: Texture,
-    /// Buffer to transfer video output data from GPU to CPU
-    pub video_buffer: wgpu::Buffer,
+    /// Ring of buffers to transfer video output data from GPU to CPU
+    pub video_buffers: Vec<VideoReadbackBuffer>,
+    /// Index of the buffer in video_buffers that the next frame (or tile) will be copied to
+    pub next_video_buffer_idx: usize,
+    /// Indices of the buffers holding frames (or tiles) that have not been read back yet, from
+    /// oldest to newest
+    pub in_flight_video_buffers: std::collections::VecDeque<usize>,
     /// Depth buffer for redering to video
     pub video_depth_texture: Texture,
     /// Rendering pipeline handle for rendering to video
     pub video_render_pipeline: wgpu::RenderPipeline,
-    /// JoinHandle for the video encoding thread
-    pub video_thread_handle: Option<std::thread::JoinHandle<std::io::Result<()>>>,
+    /// View/projection matrix for this target (which can have a different aspect ratio than the
+    /// screen or the other targets)
+    pub camera_buffer: wgpu::Buffer,
+    /// Camera bind group using camera_buffer (and the shared simulation time buffer)
+    pub camera_bind_group: wgpu::BindGroup,
+    /// Converts the frames to the output's alpha mode (if the background is transparent)
+    pub alpha_conversion: Option<crate::alpha::AlphaConversion>,
+    /// Encodes the frames of an auxiliary pass into the output's format (None for the beauty pass)
+    pub pass_encoding: Option<crate::auxiliary_passes::PassEncoding>,
+    /// JoinHandle for the video encoding (or image sequence writing) thread
+    pub video_thread_handle: Option<std::thread::JoinHandle<anyhow::Result<()>>>,
     /// Transmitter to send frames to the video encoding thread
     pub video_thread_tx: Option<std::sync::mpsc::SyncSender<Vec<u8>>>,
+    /// Number of frames sent to the video encoding thread
+    pub frames_written: u64,
+    /// Time at which the video export started
+    pub export_start_time: std::time::Instant,
+    /// Time at which the export progress was last logged
+    pub last_progress_log_time: std::time::Instant,
+    /// Why the export stopped early (returned by finish_video_export())
+    pub export_error: Option<anyhow::Error>,
+}
+
+/// How far along the video export is.
+pub struct VideoExportProgress {
+    pub frames_written: u64,
+    pub elapsed: std::time::Duration,
+    /// Whether the export stopped early because of an error
+    pub failed: bool,
+}
+
+impl std::fmt::Display for VideoExportProgress {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if self.failed {
+            return write!(
+                f,
+                "video export failed after {} frames",
+                self.frames_written
+            );
+        }
+        write!(
+            f,
+            "{} frames exported, {:.1} fps, elapsed {}",
+            self.frames_written,
+            self.frames_written as f64 / self.elapsed.as_secs_f64().max(f64::EPSILON),
+            crate::render::format_duration(self.elapsed),
+        )
+    }
+}
+
+/// Staging buffer that a video frame is copied into so that it can be read by the CPU.
+pub struct VideoReadbackBuffer {
+    pub buffer: wgpu::Buffer,
+    /// Index of the tile of the frame that is in the buffer
+    pub tile_idx: usize,
+    /// Submission that copies the frame into the buffer (while a frame is in flight)
+    pub submission_index: Option<wgpu::SubmissionIndex>,
+    /// Receives the result of mapping the buffer once the frame can be read (while a frame is in
+    /// flight)
+    pub map_result_rx: Option<std::sync::mpsc::Receiver<Result<(), wgpu::BufferAsyncError>>>,
 }
 
 impl GraphicsState {
+    /// Sets up all the GPU resources.  If `window` is None, no surface is created (headless mode)
+    /// and everything is rendered only to the off-screen video export targets (one per entry in
+    /// `video_configs`, the first of which sets the size of the headless "surface").
     pub fn new(
-        window: &Window,
+        window: Option<&Window>,
         petal_texture_image_paths: &[String],
         petal_variants: Vec<gpu_types::PetalVariant>,
         petal_states: &[PetalState],
         petal_config: &FallingPetalsConfig,
-        video_config: VideoExportConfig,
-    ) -> Self {
-        let size = window.inner_size();
+        video_configs: Vec<VideoExportConfig>,
+    ) -> anyhow::Result<Self> {
+        let size = match (window, video_configs.first()) {
+            (Some(window), _) => window.inner_size(),
+            (None, Some(video_config)) => {
+                winit::dpi::PhysicalSize::new(video_config.width, video_config.height)
+            }
+            (None, None) => anyhow::bail!("Running headless requires a video export target"),
+        };
+        // Check for ffmpeg (or the image sequence's directory) up front, rather than failing once
+        // frames are being rendered.
+        for video_config in video_configs.iter().filter(|config| config.export_enabled) {
+            match video_config.backend {
+                VideoExportBackend::Ffmpeg => crate::video_encoder::check_ffmpeg_support(
+                    &video_config.encoder,
+                    &video_config.audio,
+                )
+                .with_context(|| {
+                    format!(
+                        "Video export is enabled, but ffmpeg can't encode {}",
+                        video_config.output_file
+                    )
+                })?,
+                VideoExportBackend::ImageSequence => {
+                    crate::image_sequence::prepare_output(&video_config.output_file)?
+                }
+                VideoExportBackend::AnimatedImage => {
+                    crate::animated_image::prepare_output(&video_config.output_file)?
+                }
+                // A named pipe can only be opened once something reads from it, so it's opened by
+                // the raw stream thread.
+                VideoExportBackend::RawStream => {}
+            }
+        }
 
         // -----------------------------------------------------------------------------------------
         log::debug!("WGPU setup");
         let wgpu_instance = wgpu::Instance::new(wgpu::InstanceDescriptor::default());
         //log::debug!("wgpu report:\n{:?}", wgpu_instance.generate_report());
-        let surface = unsafe { wgpu_instance.create_surface(window) }.unwrap();
-        // The adapter represents the physical instance of your hardware.
-        let gpu_adapter =
+        let surface = match window {
+            Some(window) => Some(unsafe { wgpu_instance.create_surface(window) }?),
+            None => None,
+        };
+        // The adapter represents the physical instance of your hardware.  If no hardware adapter is
+        // available (e.g. on a server or CI machine without a GPU), try a software one.
+        let request_adapter = |force_fallback_adapter| {
             pollster::block_on(wgpu_instance.request_adapter(&wgpu::RequestAdapterOptions {
                 power_preference: wgpu::PowerPreference::default(),
-                compatible_surface: Some(&surface),
-                force_fallback_adapter: false,
+                compatible_surface: surface.as_ref(),
+                force_fallback_adapter,
             }))
-            .unwrap();
+        };
+        let gpu_adapter = match request_adapter(false) {
+            Some(gpu_adapter) => gpu_adapter,
+            None => {
+                log::warn!("No GPU adapter found, trying a software (fallback) adapter");
+                request_adapter(true).context("No GPU adapter (hardware or software) found")?
+            }
+        };
         //log::debug!("Adapter features:\n{:?}", gpu_adapter.features());
         //log::debug!("Adapter limits:\n{:?}", gpu_adapter.limits());
 
@@ -150,28 +312,52 @@ impl GraphicsState {
         log::debug!("Device and queue setup");
 
         // The device represents the logical instance that you work with, and that owns all the
-        // resources.
-        let limits = wgpu::Limits {
-            // Request a larger max uniform buffer size so we can have more than ~4000 petals.  It
-            // defaults to 65536 (2^16), or 64KB.  Since each petal variant index (a single u32)
-            // ends up using up 16 bytes (the minimum uniform buffer array stride), we can only fit
-            // indices fo 4096 petals in a uniform buffer with the default limits.
-            // ACTUALLY, looks like requesting more than 65536 causes it to fail to obtain a device.
-            // Looks like that is the hard limit for my GPU.
-            //max_uniform_buffer_binding_size: 4 * 65536,
-            ..wgpu::Limits::default()
+        // resources.  Use binding arrays for the petal textures if the adapter supports them, and
+        // otherwise (or if requesting a device with them fails) fall back to a texture array.
+        let request_device = |petal_texture_mode: PetalTextureMode| {
+            let limits = match petal_texture_mode {
+                PetalTextureMode::BindingArray => wgpu::Limits::default(),
+                // Adapters without binding arrays are often downlevel ones (e.g. the GL backend),
+                // which may not support the default limits.  Still allow the largest texture size
+                // the adapter supports, since the petal texture images can be large.
+                PetalTextureMode::TextureArray => {
+                    wgpu::Limits::downlevel_defaults().using_resolution(gpu_adapter.limits())
+                }
+            };
+            pollster::block_on(gpu_adapter.request_device(
+                &wgpu::DeviceDescriptor {
+                    features: petal_texture_mode.required_features(),
+                    limits,
+                    label: None,
+                },
+                None,
+            ))
         };
-        let (device, queue) = pollster::block_on(gpu_adapter.request_device(
-            &wgpu::DeviceDescriptor {
-                features: wgpu::Features::TEXTURE_BINDING_ARRAY
-                    | wgpu::Features::SAMPLED_TEXTURE_AND_STORAGE_BUFFER_ARRAY_NON_UNIFORM_INDEXING
-                    | wgpu::Features::BUFFER_BINDING_ARRAY,
-                limits,
-                label: None,
-            },
-            None,
-        ))
-        .unwrap();
+        let mut petal_texture_mode = if gpu_adapter
+            .features()
+            .contains(PetalTextureMode::BindingArray.required_features())
+        {
+            PetalTextureMode::BindingArray
+        } else {
+            PetalTextureMode::TextureArray
+        };
+        let (device, queue) = match request_device(petal_texture_mode) {
+            Ok(device_and_queue) => device_and_queue,
+            Err(error) if petal_texture_mode == PetalTextureMode::BindingArray => {
+                log::warn!(
+                    "Failed to obtain a GPU device with texture binding arrays ({error}), \
+                    falling back to a texture array"
+                );
+                petal_texture_mode = PetalTextureMode::TextureArray;
+                request_device(petal_texture_mode).context("Failed to obtain a GPU device")?
+            }
+            Err(error) => return Err(error).context("Failed to obtain a GPU device"),
+        };
+        log::info!(
+            "Using adapter \"{}\" ({:?}) with petal texture mode {petal_texture_mode:?}",
+            gpu_adapter.get_info().name,
+            gpu_adapter.get_info().backend,
+        );
         //log::debug!("Device features:\n{:?}", device.features());
         //log::debug!("Device limits:\n{:?}", device.limits());
 
@@ -179,7 +365,10 @@ impl GraphicsState {
         log::debug!("Surface setup");
 
         // TODO: should I create a SwapChain here too?  Google "wgpu SwapChain".
-        let surface_capabilities = surface.get_capabilities(&gpu_adapter);
+        let surface_formats = surface
+            .as_ref()
+            .map(|surface| surface.get_capabilities(&gpu_adapter).formats)
+            .unwrap_or_default();
         let surface_config = wgpu::SurfaceConfiguration {
             usage: wgpu::TextureUsages::RENDER_ATTACHMENT,
             format: wgpu::TextureFormat::Bgra8UnormSrgb,
@@ -187,23 +376,13 @@ impl GraphicsState {
             height: size.height,
             present_mode: wgpu::PresentMode::Fifo,
             alpha_mode: wgpu::CompositeAlphaMode::Auto,
-            view_formats: surface_capabilities.formats.clone(),
+            view_formats: surface_formats.clone(),
         };
 
         // -----------------------------------------------------------------------------------------
         log::debug!("Depth texture setup");
-        let depth_texture = texture::Texture::create_depth_buffer_texture(
-            &device,
-            surface_config.width,
-            surface_config.height,
-            Some("depth texture"),
-        );
-        let video_depth_texture = texture::Texture::create_depth_buffer_texture(
-            &device,
-            video_config.width,
-            video_config.height,
-            Some("video depth texture"),
-        );
+        let depth_texture =
+            create_screen_depth_texture(&device, &surface_config, surface.is_some());
 
         // -----------------------------------------------------------------------------------------
         log::debug!("Uniform buffer (for view/projection matrix) setup");
@@ -213,33 +392,59 @@ impl GraphicsState {
             contents: unsafe { sized_type_as_u8_slice(&camera_uniform) },
             usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
         });
+        let time_uniform = gpu_types::SimulationTimeUniform::new(0.0);
+        let time_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+            label: Some("Simulation time uniform buffer"),
+            contents: unsafe { sized_type_as_u8_slice(&time_uniform) },
+            usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
+        });
 
         // -----------------------------------------------------------------------------------------
         log::debug!("Camera bind group setup");
         let camera_bind_group_layout =
             device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
                 label: Some("Camera bind group layout"),
-                entries: &[wgpu::BindGroupLayoutEntry {
-                    // Put the view-projection matrix at binding 0 (location within the bind group).
-                    binding: 0,
-                    visibility: wgpu::ShaderStages::VERTEX,
-                    ty: wgpu::BindingType::Buffer {
-                        ty: wgpu::BufferBindingType::Uniform,
-                        // Indicates whether this buffer will change size or not.  Can be useful if
-                        // we want to store an array of things in our uniform buffer.
-                        has_dynamic_offset: false,
-                        min_binding_size: None,
+                entries: &[
+                    wgpu::BindGroupLayoutEntry {
+                        // Put the view-projection matrix at binding 0 (location within the bind
+                        // group).
+                        binding: 0,
+                        visibility: wgpu::ShaderStages::VERTEX,
+                        ty: wgpu::BindingType::Buffer {
+                            ty: wgpu::BufferBindingType::Uniform,
+                            // Indicates whether this buffer will change size or not.  Can be useful
+                            // if we want to store an array of things in our uniform buffer.
+                            has_dynamic_offset: false,
+                            min_binding_size: None,
+                        },
+                        count: None,
                     },
-                    count: None,
-                }],
+                    // Put the simulation time at binding 1.
+                    wgpu::BindGroupLayoutEntry {
+                        binding: 1,
+                        visibility: wgpu::ShaderStages::VERTEX,
+                        ty: wgpu::BindingType::Buffer {
+                            ty: wgpu::BufferBindingType::Uniform,
+                            has_dynamic_offset: false,
+                            min_binding_size: None,
+                        },
+                        count: None,
+                    },
+                ],
             });
         let camera_bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
             label: Some("Camera bind group"),
             layout: &camera_bind_group_layout,
-            entries: &[wgpu::BindGroupEntry {
-                binding: 0,
-                resource: camera_buffer.as_entire_binding(),
-            }],
+            entries: &[
+                wgpu::BindGroupEntry {
+                    binding: 0,
+                    resource: camera_buffer.as_entire_binding(),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 1,
+                    resource: time_buffer.as_entire_binding(),
+                },
+            ],
         });
 
         // -----------------------------------------------------------------------------------------
@@ -247,8 +452,11 @@ impl GraphicsState {
         // Petal textures
         let mut petal_texture_images = petal_texture_image_paths
             .iter()
-            .map(|image_path| image::open(image_path).expect(image_path))
-            .collect::<Vec<_>>();
+            .map(|image_path| {
+                image::open(image_path)
+                    .with_context(|| format!("Failed to load petal texture {image_path}"))
+            })
+            .collect::<anyhow::Result<Vec<_>>>()?;
         // Pre-mulitpy alpha values since we're using PREMULTIPLIED_ALPHA_BLENDING mode.
         for petal_texture_image in &mut petal_texture_images {
             match petal_texture_image {
@@ -274,19 +482,42 @@ impl GraphicsState {
                 ),
             }
         }
-        let petal_textures: Vec<Texture> = petal_texture_images
-            .iter()
-            .enumerate()
-            .map(|(idx, petal_texture_image)| {
-                Texture::from_image(
-                    &device,
-                    &queue,
-                    petal_texture_image,
-                    Some(format!("Petal texture {idx}").as_str()),
-                )
-                .unwrap()
-            })
-            .collect();
+        let petal_textures: Vec<Texture> = match petal_texture_mode {
+            PetalTextureMode::BindingArray => petal_texture_images
+                .iter()
+                .enumerate()
+                .map(|(idx, petal_texture_image)| {
+                    Texture::from_image(
+                        &device,
+                        &queue,
+                        petal_texture_image,
+                        Some(format!("Petal texture {idx}").as_str()),
+                    )
+                })
+                .collect::<anyhow::Result<_>>()?,
+            PetalTextureMode::TextureArray => vec![Texture::array_from_images(
+                &device,
+                &queue,
+                &petal_texture_images,
+                Some("Petal texture array"),
+            )?],
+        };
+        let mut petal_variants = petal_variants;
+        if petal_texture_mode == PetalTextureMode::TextureArray {
+            // Smaller textures only fill part of their layer of the texture array.
+            let layer_size = Texture::array_layer_size(&petal_texture_images);
+            for petal_variant in &mut petal_variants {
+                let [u_scale, v_scale] = Texture::array_uv_scale(
+                    &petal_texture_images[petal_variant.petal_texture_index.value as usize],
+                    layer_size,
+                );
+                let bounds = &mut petal_variant.texture_u_v_width_height.vector;
+                bounds[0] *= u_scale;
+                bounds[1] *= v_scale;
+                bounds[2] *= u_scale;
+                bounds[3] *= v_scale;
+            }
+        }
 
         // -----------------------------------------------------------------------------------------
         log::debug!("Instance setup");
@@ -299,23 +530,15 @@ impl GraphicsState {
             contents: unsafe { vec_as_u8_slice(&petal_pose_data) },
             usage: wgpu::BufferUsages::VERTEX | wgpu::BufferUsages::COPY_DST,
         });
-        let mut petal_variant_index_data = petal_states
+        let petal_instance_data = petal_states
             .iter()
-            .map(|state| state.variant_index)
+            .map(PetalInstanceData::from)
             .collect::<Vec<_>>();
-        // Pad the buffer out so that its size is a multiple of 16 bytes, and is thus compatible
-        // with the 16 byte alignment requirement for uniform buffers.  The extra zeros tacked on
-        // the end as padding will get transferred to the GPU each frame, but will never be read.
-        while petal_variant_index_data.len() % 4 != 0 {
-            log::debug!("Adding a byte of padding onto petal_variant_index_data (for alignment).");
-            petal_variant_index_data.push(0);
-        }
-        let petal_variant_index_buffer =
-            device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
-                label: Some("Petal variant index buffer"),
-                contents: unsafe { vec_as_u8_slice(&petal_variant_index_data) },
-                usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
-            });
+        let petal_instance_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+            label: Some("Instance data buffer"),
+            contents: unsafe { vec_as_u8_slice(&petal_instance_data) },
+            usage: wgpu::BufferUsages::VERTEX | wgpu::BufferUsages::COPY_DST,
+        });
         let petal_variant_data = petal_variants;
         let petal_variant_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
             label: Some("Petal variant buffer"),
@@ -327,42 +550,53 @@ impl GraphicsState {
 
         // -----------------------------------------------------------------------------------------
         log::debug!("Texture bind group setup");
+        // Binding arrays have one entry per texture, while a texture array is a single binding.
+        let texture_binding_count = match petal_texture_mode {
+            PetalTextureMode::BindingArray => {
+                core::num::NonZeroU32::new(petal_textures.len() as u32)
+            }
+            PetalTextureMode::TextureArray => None,
+        };
+        let texture_views = petal_textures
+            .iter()
+            .map(|tex| &tex.view)
+            .collect::<Vec<_>>();
+        let texture_samplers = petal_textures
+            .iter()
+            .map(|tex| &tex.sampler)
+            .collect::<Vec<_>>();
         let texture_bind_group_layout =
             device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
                 entries: &[
-                    // Entry at binding 0 for the texture array
+                    // Entry at binding 0 for the textures (a binding array of textures, or a
+                    // single texture array)
                     wgpu::BindGroupLayoutEntry {
                         binding: 0,
                         visibility: wgpu::ShaderStages::FRAGMENT,
                         ty: wgpu::BindingType::Texture {
                             multisampled: false,
-                            view_dimension: wgpu::TextureViewDimension::D2,
+                            view_dimension: match petal_texture_mode {
+                                PetalTextureMode::BindingArray => wgpu::TextureViewDimension::D2,
+                                PetalTextureMode::TextureArray => {
+                                    wgpu::TextureViewDimension::D2Array
+                                }
+                            },
                             sample_type: wgpu::TextureSampleType::Float { filterable: true },
                         },
-                        count: core::num::NonZeroU32::new(petal_textures.len() as u32),
+                        count: texture_binding_count,
                     },
-                    // Entry at binding 1 for the sampler array
+                    // Entry at binding 1 for the sampler(s)
                     wgpu::BindGroupLayoutEntry {
                         binding: 1,
                         visibility: wgpu::ShaderStages::FRAGMENT,
                         ty: wgpu::BindingType::Sampler(wgpu::SamplerBindingType::Filtering),
-                        count: core::num::NonZeroU32::new(petal_textures.len() as u32),
+                        count: texture_binding_count,
                     },
-                    // Entry at binding 2 for the petal variant info
+                    // Entry at binding 2 for the petal variant info (also used by the vertex
+                    // shader to apply each variant's curvature profile)
                     wgpu::BindGroupLayoutEntry {
                         binding: 2,
-                        visibility: wgpu::ShaderStages::FRAGMENT,
-                        ty: wgpu::BindingType::Buffer {
-                            ty: wgpu::BufferBindingType::Uniform,
-                            has_dynamic_offset: false,
-                            min_binding_size: None,
-                        },
-                        count: None,
-                    },
-                    // Entry at binding 3 for the variant indices for each petal
-                    wgpu::BindGroupLayoutEntry {
-                        binding: 3,
-                        visibility: wgpu::ShaderStages::FRAGMENT,
+                        visibility: wgpu::ShaderStages::VERTEX_FRAGMENT,
                         ty: wgpu::BindingType::Buffer {
                             ty: wgpu::BufferBindingType::Uniform,
                             has_dynamic_offset: false,
@@ -378,30 +612,30 @@ impl GraphicsState {
             entries: &[
                 wgpu::BindGroupEntry {
                     binding: 0,
-                    resource: wgpu::BindingResource::TextureViewArray(
-                        &petal_textures
-                            .iter()
-                            .map(|tex| &tex.view)
-                            .collect::<Vec<_>>(),
-                    ),
+                    resource: match petal_texture_mode {
+                        PetalTextureMode::BindingArray => {
+                            wgpu::BindingResource::TextureViewArray(&texture_views)
+                        }
+                        PetalTextureMode::TextureArray => {
+                            wgpu::BindingResource::TextureView(texture_views[0])
+                        }
+                    },
                 },
                 wgpu::BindGroupEntry {
                     binding: 1,
-                    resource: wgpu::BindingResource::SamplerArray(
-                        &petal_textures
-                            .iter()
-                            .map(|tex| &tex.sampler)
-                            .collect::<Vec<_>>(),
-                    ),
+                    resource: match petal_texture_mode {
+                        PetalTextureMode::BindingArray => {
+                            wgpu::BindingResource::SamplerArray(&texture_samplers)
+                        }
+                        PetalTextureMode::TextureArray => {
+                            wgpu::BindingResource::Sampler(texture_samplers[0])
+                        }
+                    },
                 },
                 wgpu::BindGroupEntry {
                     binding: 2,
                     resource: petal_variant_buffer.as_entire_binding(),
                 },
-                wgpu::BindGroupEntry {
-                    binding: 3,
-                    resource: petal_variant_index_buffer.as_entire_binding(),
-                },
             ],
             label: Some("texture_bind_group"),
         });
@@ -410,10 +644,7 @@ impl GraphicsState {
         log::debug!("Render pipeline setup");
         let shader_source_str = include_str!("graphics/shader.wgsl")
             .replace("N_PETAL_VARIANTS", &petal_variant_data.len().to_string())
-            .replace(
-                "N_VEC4_OF_PETAL_INDICES",
-                &((petal_states.len() + 3) / 4).to_string(),
-            );
+            .replace("PETAL_TEXTURE_BINDINGS", petal_texture_mode.shader_source());
         //log::debug!("Processed shader source:\n{}", &shader_source_str);
         let shader_source = wgpu::ShaderSource::Wgsl(shader_source_str.into());
         let shader_module_descriptor = wgpu::ShaderModuleDescriptor {
@@ -424,13 +655,7 @@ impl GraphicsState {
         let render_pipeline = Self::build_render_pipeline(
             &device,
             surface_config.format,
-            &shader_module,
-            &texture_bind_group_layout,
-            &camera_bind_group_layout,
-        );
-        let video_render_pipeline = Self::build_render_pipeline(
-            &device,
-            video_config.texture_format,
+            ExportPass::Beauty,
             &shader_module,
             &texture_bind_group_layout,
             &camera_bind_group_layout,
@@ -441,138 +666,47 @@ impl GraphicsState {
         let offsets = petal_config
             .petal_bend_vertex_offsets
             .map(|offset| offset * petal_config.petal_bend_vertex_offset_multiplier);
-        let textured_square_vertices = [
-            PositionTextureVertex {
-                // 0: 0,0 -- Upper left corner
-                position: [-1.0, 1.0, offsets[0]],
-                texture_coords: [0.0, 0.0],
-            },
-            PositionTextureVertex {
-                // 1: 1,0 -- Top middle
-                position: [0.0, 1.0, offsets[1]],
-                texture_coords: [0.5, 0.0],
-            },
-            PositionTextureVertex {
-                // 2: 2,0 -- Upper right corner
-                position: [1.0, 1.0, offsets[2]],
-                texture_coords: [1.0, 0.0],
-            },
-            PositionTextureVertex {
-                // 3: 0,1 -- Left middle
-                position: [-1.0, 0.0, offsets[3]],
-                texture_coords: [0.0, 0.5],
-            },
-            PositionTextureVertex {
-                // 4: 1,1 -- Middle middle
-                position: [0.0, 0.0, offsets[4]],
-                texture_coords: [0.5, 0.5],
-            },
-            PositionTextureVertex {
-                // 5: 0,1 -- Middle middle
-                position: [1.0, 0.0, offsets[5]],
-                texture_coords: [1.0, 0.5],
-            },
-            PositionTextureVertex {
-                // 6: 0,2 -- Lower left corner
-                position: [-1.0, -1.0, offsets[6]],
-                texture_coords: [0.0, 1.0],
-            },
-            PositionTextureVertex {
-                // 7: 0,1 -- Middle middle
-                position: [0.0, -1.0, offsets[7]],
-                texture_coords: [0.5, 1.0],
-            },
-            PositionTextureVertex {
-                // 8: 2,2 -- Lower right corner
-                position: [1.0, -1.0, offsets[8]],
-                texture_coords: [1.0, 1.0],
-            },
-        ];
+        let textured_square_vertices =
+            mesh::grid_vertices(petal_config.petal_mesh_resolution, &offsets);
+        let textured_square_indices = mesh::grid_indices(petal_config.petal_mesh_resolution);
 
         // -----------------------------------------------------------------------------------------
         log::debug!("Textured square vertex & index buffer setup");
         let textured_square_vertex_buffer =
             device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
                 label: Some("Textured pentagon vertex buffer"),
-                contents: unsafe { sized_type_as_u8_slice(&textured_square_vertices) },
+                contents: unsafe { vec_as_u8_slice(&textured_square_vertices) },
                 usage: wgpu::BufferUsages::VERTEX,
             });
         let textured_square_index_buffer =
             device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
                 label: Some("Textured pentagon index buffer"),
-                contents: unsafe { sized_type_as_u8_slice(TEXTURED_SQUARE_INDICES) },
+                contents: unsafe { vec_as_u8_slice(&textured_square_indices) },
                 usage: wgpu::BufferUsages::INDEX,
             });
-        let n_textured_square_indices = TEXTURED_SQUARE_INDICES.len() as u32;
+        let n_textured_square_indices = textured_square_indices.len() as u32;
 
         // -----------------------------------------------------------------------------------------
-        let video_export_state = match video_config.export_enabled {
-            false => None,
-            true => {
-                log::debug!("Set up video output objects");
-                let video_texture_descriptor = wgpu::TextureDescriptor {
-                    label: Some("video output texture"),
-                    size: wgpu::Extent3d {
-                        width: video_config.width,
-                        height: video_config.height,
-                        depth_or_array_layers: 1,
-                    },
-                    mip_level_count: 1,
-                    sample_count: 1,
-                    dimension: wgpu::TextureDimension::D2,
-                    format: video_config.texture_format,
-                    // COPY_SRC so we can copy the texture contents to a buffer (video_output_buffer),
-                    // RENDER_ATTACHMENT so that we can attach the texture to a render pass so it can be
-                    // rendered to.
-                    usage: wgpu::TextureUsages::COPY_SRC | wgpu::TextureUsages::RENDER_ATTACHMENT,
-                    view_formats: &surface_capabilities.formats,
-                };
-                let video_texture = Texture::from_descriptor(&device, &video_texture_descriptor);
-                //device.create_texture(&video_texture_descriptor);
-                let video_buffer_descriptor = wgpu::BufferDescriptor {
-                    label: Some("video output buffer"),
-                    size: video_config.frame_size,
-                    // COPY_DST so we can copy data into the buffer, MAP_READ so that we can read the
-                    // contents of the buffer from the CPU side.
-                    usage: wgpu::BufferUsages::COPY_DST | wgpu::BufferUsages::MAP_READ,
-                    mapped_at_creation: false,
-                };
-                let video_buffer = device.create_buffer(&video_buffer_descriptor);
-
-                // -----------------------------------------------------------------------------------------
-                log::debug!("Spawn video coding thread");
-                // I tried using a std::sync::mpsc::channel() here before, but it seems to accumulate more
-                // and more memory for everything I send over it without bound until my RAM fills up and
-                // things crash. Maybe this is because frames are getting rendered faster than ffmpeg can
-                // encode them?  I'm not sure.  But switching to use a bounded channel
-                // (std::sync::mpsc::sync_channel(bound)) fixed the problem so that now my RAM usage remains
-                // stable.
-                let (video_thread_tx, video_thread_rx) = std::sync::mpsc::sync_channel(1);
-                let output_file_clone = video_config.output_file.clone();
-                let video_thread_handle = std::thread::spawn(move || {
-                    video_thread_fn(
-                        video_thread_rx,
-                        output_file_clone,
-                        video_config.width,
-                        video_config.height,
-                        video_config.frame_rate,
-                    )
-                });
-                Some(VideoExportState {
+        // When running headless, the video export targets are the only things rendered to, so they
+        // are always set up (but frames are only read back and encoded if video export is enabled).
+        let video_export_states = video_configs
+            .into_iter()
+            .filter(|video_config| video_config.export_enabled || surface.is_none())
+            .map(|video_config| {
+                VideoExportState::new(
+                    &device,
                     video_config,
-                    video_texture,
-                    video_buffer,
-                    video_depth_texture,
-                    video_render_pipeline,
-                    video_thread_handle: Some(video_thread_handle),
-                    video_thread_tx: Some(video_thread_tx),
-                })
-            }
-        };
+                    &shader_module,
+                    &texture_bind_group_layout,
+                    &camera_bind_group_layout,
+                    &time_buffer,
+                )
+            })
+            .collect::<anyhow::Result<_>>()?;
 
         // -----------------------------------------------------------------------------------------
         log::debug!("Finished graphics setup");
-        Self {
+        Ok(Self {
             device,
             queue,
             surface,
@@ -582,30 +716,34 @@ impl GraphicsState {
             depth_texture,
             render_pipeline,
 
-            video_export_state,
+            video_export_states,
 
             petal_pose_data,
             petal_pose_buffer,
-            petal_variant_index_data,
-            petal_variant_index_buffer,
+            petal_instance_data,
+            petal_instance_buffer,
             petal_variant_data,
             petal_variant_buffer,
 
             camera_uniform,
             camera_bind_group,
             camera_buffer,
+            time_uniform,
+            time_buffer,
 
             textured_square_vertices,
             textured_square_vertex_buffer,
             textured_square_index_buffer,
             n_textured_square_indices,
             texture_bind_group,
-        }
+        })
     }
 
+    /// Builds the pipeline that renders the petals for the given pass.
     fn build_render_pipeline(
         device: &wgpu::Device,
         color_format: wgpu::TextureFormat,
+        pass: ExportPass,
         shader_module: &wgpu::ShaderModule,
         texture_bind_group_layout: &wgpu::BindGroupLayout,
         camera_bind_group_layout: &wgpu::BindGroupLayout,
@@ -627,6 +765,7 @@ impl GraphicsState {
             buffers: &[
                 PositionTextureVertex::vertex_buffer_layout(),
                 gpu_types::Matrix4::vertex_buffer_layout(),
+                PetalInstanceData::vertex_buffer_layout(),
             ],
         };
         // Describes the state of primitve assembly and rasterization in a render pipeline.
@@ -656,8 +795,10 @@ impl GraphicsState {
         let color_target_state = wgpu::ColorTargetState {
             format: color_format,
             // Better alpha blending mode, but requires the color channels to be pre-multiplied by
-            // the alpha channel.
-            blend: Some(wgpu::BlendState::PREMULTIPLIED_ALPHA_BLENDING),
+            // the alpha channel.  The auxiliary passes write data, which isn't blended (the depth
+            // test leaves the nearest petal's).
+            blend: (pass == ExportPass::Beauty)
+                .then_some(wgpu::BlendState::PREMULTIPLIED_ALPHA_BLENDING),
             //blend: Some(wgpu::BlendState::ALPHA_BLENDING), // Enable alpha blending
             //blend: Some(wgpu::BlendState::REPLACE), // No alpha blending
             // Mask that enables / disables writes to different color/alpha channels
@@ -665,7 +806,7 @@ impl GraphicsState {
         };
         let fragment_state = wgpu::FragmentState {
             module: shader_module,
-            entry_point: "fs_textured_vertex",
+            entry_point: crate::auxiliary_passes::fragment_entry_point(pass),
             targets: &[Some(color_target_state)],
         };
         let render_pipeline_descriptor = wgpu::RenderPipelineDescriptor {
@@ -683,89 +824,43 @@ impl GraphicsState {
 
     pub fn render(&mut self) -> Result<(), wgpu::SurfaceError> {
         // Render to the screen --------------------------------------------------------------------
-        // Get the current SurfaceTexture that we will render to.
-        let screen_texture = self.surface.get_current_texture()?;
-        let screen_texture_view = screen_texture
-            .texture
-            .create_view(&wgpu::TextureViewDescriptor::default());
-        let command_encoder = self.render_to_target(RenderTarget::Screen(&screen_texture_view))?;
-        self.queue.submit(std::iter::once(command_encoder.finish()));
-        screen_texture.present();
-
-        // Render to the video buffer --------------------------------------------------------------
-        let mut command_encoder;
-        if self.video_export_state.is_some() {
-            command_encoder = self.render_to_target(RenderTarget::Video)?;
-            if let Some(ref mut video_export_state) = self.video_export_state {
-                // Copy the results to the buffer that is readable (mappable) by the CPU
-                command_encoder.copy_texture_to_buffer(
-                    video_export_state.video_texture.texture.as_image_copy(),
-                    wgpu::ImageCopyBuffer {
-                        buffer: &video_export_state.video_buffer,
-                        layout: wgpu::ImageDataLayout {
-                            offset: 0,
-                            // TODO: bytes_per_row must be padded to a multiple of
-                            // wgpu::COPY_BYTES_PER_ROW_ALIGNMENT, which is 256.  But most modern
-                            // standard video resolutions have x resolutions that are multiples of
-                            // 64, which satisfies that alignment without any need for padding,
-                            // assuming 4 bytes are used per pixel.  It's simpler to assume we're
-                            // using one of those resolutions where no padding is needed, since that
-                            // also means we don't have to remove the padding from the end of each
-                            // row of pixel data before piping the data to ffmpeg (see the "capture"
-                            // example in the wgpu repository for an example of how to do that:
-                            // https://github.com/gfx-rs/wgpu/tree/master/wgpu/examples/capture).
-                            // Thus, for now I'm just making a note in the default configuration
-                            // that the x resolution must be a multiple of 64, because otherwise it
-                            // will cause a crash for violating COPY_BYTES_PER_ROW_ALIGNMENT.
-                            bytes_per_row: Some(
-                                std::num::NonZeroU32::new(
-                                    video_export_state.video_config.width
-                                        * std::mem::size_of::<u32>() as u32,
-                                )
-                                .unwrap(),
-                            ),
-                            // A value for rows_per_image is only required if there are multiple images
-                            // (i.e. the depth is more than 1).
-                            rows_per_image: None, //Some(std::num::NonZeroU32::new(VIDEO_HEIGHT).unwrap()),
-                        },
-                    },
-                    wgpu::Extent3d {
-                        width: video_export_state.video_config.width,
-                        height: video_export_state.video_config.height,
-                        depth_or_array_layers: 1,
-                    },
+        if let Some(surface) = self.surface.as_ref() {
+            // Get the current SurfaceTexture that we will render to.
+            let screen_texture = surface.get_current_texture()?;
+            let screen_texture_view = screen_texture
+                .texture
+                .create_view(&wgpu::TextureViewDescriptor::default());
+            let command_encoder =
+                self.render_to_target(RenderTarget::Screen(&screen_texture_view))?;
+            self.queue.submit(std::iter::once(command_encoder.finish()));
+            screen_texture.present();
+        }
+
+        // Render to the video buffers -------------------------------------------------------------
+        // All the targets show the same simulation frame, each at its own resolution.  Frames that
+        // are rendered in tiles get each tile's part of the view written to the camera buffer
+        // before it is rendered.
+        for idx in 0..self.video_export_states.len() {
+            for tile_idx in 0..self.video_export_states[idx].tile_layout.tiles.len() {
+                let video_export_state = &self.video_export_states[idx];
+                let tile = video_export_state.tile_layout.tiles[tile_idx];
+                let (left, top, width, height) =
+                    video_export_state.tile_layout.view_rectangle(tile);
+                let camera_uniform: gpu_types::Matrix4 = video_export_state
+                    .camera
+                    .get_sub_frustum_view_projection_matrix(left, top, width, height)
+                    .into();
+                self.queue
+                    .write_buffer(&video_export_state.camera_buffer, 0, unsafe {
+                        sized_type_as_u8_slice(&camera_uniform)
+                    });
+                let command_encoder = self.render_to_target(RenderTarget::Video(idx))?;
+                self.video_export_states[idx].read_back_tile(
+                    &self.device,
+                    &self.queue,
+                    command_encoder,
+                    tile_idx,
                 );
-                let video_render_submission_index =
-                    self.queue.submit(std::iter::once(command_encoder.finish()));
-
-                let buffer_slice = video_export_state.video_buffer.slice(..);
-                let (sender, receiver) = futures_intrusive::channel::shared::oneshot_channel();
-                // This queues up the buffer to be mapped, and then calls the FnOnce with a result
-                // passed in indicated when it has been mapped and is ready to be read from (or an error
-                // has occurred).  The oneshot channel allows me to easily wait until that FnOnce has
-                // been called before accessing the buffer's contents.
-                buffer_slice.map_async(wgpu::MapMode::Read, move |result| {
-                    sender.send(result).unwrap()
-                });
-                // Wait for our submitted commands to render to the video texture to finish executing
-                self.device.poll(wgpu::Maintain::WaitForSubmissionIndex(
-                    video_render_submission_index,
-                ));
-                if let Some(Ok(())) = pollster::block_on(receiver.receive()) {
-                    let padded_buffer = buffer_slice.get_mapped_range();
-                    let frame_pixel_data = padded_buffer.to_owned();
-                    video_export_state
-                        .video_thread_tx
-                        .as_ref()
-                        .unwrap()
-                        .send(frame_pixel_data)
-                        .unwrap();
-                    // Must drop any views into the buffer before we unmap it.
-                    drop(padded_buffer);
-                    video_export_state.video_buffer.unmap();
-                } else {
-                    log::error!("Buffer failed to map");
-                }
             }
         }
         Ok(())
@@ -775,19 +870,37 @@ impl GraphicsState {
         &mut self,
         render_target: RenderTarget,
     ) -> Result<wgpu::CommandEncoder, wgpu::SurfaceError> {
-        let (color_view, depth_view) = match render_target {
+        // Exported frames can have a transparent background, for compositing them over other
+        // footage.  The textures of the auxiliary passes are cleared to the value of the
+        // background instead.
+        let black = wgpu::Color {
+            r: 0.0, //0.1,
+            g: 0.0, //0.2,
+            b: 0.0, //0.3,
+            a: 1.0,
+        };
+        let (color_view, depth_view, clear_color) = match render_target {
             RenderTarget::Screen(screen_texture_view) => {
-                (screen_texture_view, &self.depth_texture.view)
+                (screen_texture_view, &self.depth_texture.view, black)
             }
-            RenderTarget::Video => {
-                if let Some(video_export_state) = self.video_export_state.as_ref() {
-                    (
-                        &video_export_state.video_texture.view,
-                        &video_export_state.video_depth_texture.view,
-                    )
-                } else {
-                    unreachable!();
-                }
+            RenderTarget::Video(idx) => {
+                let video_export_state = &self.video_export_states[idx];
+                let video_config = &video_export_state.video_config;
+                let clear_color = match video_config.pass {
+                    ExportPass::Beauty if video_config.transparent_background.enabled => {
+                        wgpu::Color { a: 0.0, ..black }
+                    }
+                    ExportPass::Beauty => black,
+                    pass => wgpu::Color {
+                        r: crate::auxiliary_passes::clear_value(pass),
+                        ..black
+                    },
+                };
+                (
+                    &video_export_state.video_texture.view,
+                    &video_export_state.video_depth_texture.view,
+                    clear_color,
+                )
             }
         };
         let mut command_encoder =
@@ -806,12 +919,7 @@ impl GraphicsState {
                         view: color_view,
                         resolve_target: None,
                         ops: wgpu::Operations {
-                            load: wgpu::LoadOp::Clear(wgpu::Color {
-                                r: 0.0, //0.1,
-                                g: 0.0, //0.2,
-                                b: 0.0, //0.3,
-                                a: 1.0,
-                            }),
+                            load: wgpu::LoadOp::Clear(clear_color),
                             store: true,
                         },
                     }),
@@ -825,22 +933,20 @@ impl GraphicsState {
                     stencil_ops: None,
                 }),
             });
-        match render_target {
-            RenderTarget::Screen(_) => {
-                textured_vertex_render_pass.set_pipeline(&self.render_pipeline)
-            }
-            RenderTarget::Video => {
-                if let Some(video_export_state) = self.video_export_state.as_ref() {
-                    textured_vertex_render_pass
-                        .set_pipeline(&video_export_state.video_render_pipeline)
-                }
-            }
+        let (render_pipeline, camera_bind_group) = match render_target {
+            RenderTarget::Screen(_) => (&self.render_pipeline, &self.camera_bind_group),
+            RenderTarget::Video(idx) => (
+                &self.video_export_states[idx].video_render_pipeline,
+                &self.video_export_states[idx].camera_bind_group,
+            ),
         };
+        textured_vertex_render_pass.set_pipeline(render_pipeline);
         textured_vertex_render_pass.set_bind_group(0, &self.texture_bind_group, &[]);
-        textured_vertex_render_pass.set_bind_group(1, &self.camera_bind_group, &[]);
+        textured_vertex_render_pass.set_bind_group(1, camera_bind_group, &[]);
         textured_vertex_render_pass
             .set_vertex_buffer(0, self.textured_square_vertex_buffer.slice(..));
         textured_vertex_render_pass.set_vertex_buffer(1, self.petal_pose_buffer.slice(..));
+        textured_vertex_render_pass.set_vertex_buffer(2, self.petal_instance_buffer.slice(..));
         textured_vertex_render_pass.set_index_buffer(
             self.textured_square_index_buffer.slice(..),
             wgpu::IndexFormat::Uint16,
@@ -856,11 +962,12 @@ impl GraphicsState {
     }
 
     /// Update data in the GPU buffers according to the data as currently reflected in the game
-    /// state.
+    /// state.  `simulation_time` is the time (in seconds) of the frame being rendered.
     pub fn update(
         &mut self,
         camera: &camera::UprightPerspectiveCamera,
         petal_states: &[crate::state::PetalState],
+        simulation_time: f32,
     ) {
         self.camera_uniform = camera.get_view_projection_matrix().into();
         // TODO: The below is the 3rd option of the 3 listed at the end of this page:
@@ -874,25 +981,37 @@ impl GraphicsState {
         self.queue.write_buffer(&self.camera_buffer, 0, unsafe {
             sized_type_as_u8_slice(&self.camera_uniform)
         });
+        // Each video export target is viewed with its own aspect ratio (its camera buffer is
+        // written when rendering, since it differs for each tile).
+        for video_export_state in &mut self.video_export_states {
+            video_export_state.camera = camera::UprightPerspectiveCamera {
+                aspect_ratio: video_export_state.video_config.aspect_ratio,
+                ..camera.clone()
+            };
+        }
+        self.time_uniform = gpu_types::SimulationTimeUniform::new(simulation_time);
+        self.queue.write_buffer(&self.time_buffer, 0, unsafe {
+            sized_type_as_u8_slice(&self.time_uniform)
+        });
 
-        // Update the instance buffer with the current instance poses, and update the petal variant
-        // index buffer with the current variant indices (this needs to be updated each frame if
-        // the z-sorting changes).
-        for ((pose_matrix, variant_index), petal_state) in self
+        // Update the instance buffers with the current instance poses and the other instance data
+        // (including the variant index) for each petal.  The variant indices need to be updated
+        // each frame too, since the z-sorting can change the order of the petals.
+        for ((pose_matrix, instance_data), petal_state) in self
             .petal_pose_data
             .iter_mut()
-            .zip(self.petal_variant_index_data.iter_mut())
+            .zip(self.petal_instance_data.iter_mut())
             .zip(petal_states.iter())
         {
             pose_matrix.matrix = gpu_types::Matrix4::from(&petal_state.pose).matrix;
-            *variant_index = petal_state.variant_index;
+            *instance_data = PetalInstanceData::from(petal_state);
         }
         self.queue.write_buffer(&self.petal_pose_buffer, 0, unsafe {
             vec_as_u8_slice(&self.petal_pose_data)
         });
         self.queue
-            .write_buffer(&self.petal_variant_index_buffer, 0, unsafe {
-                vec_as_u8_slice(&self.petal_variant_index_data)
+            .write_buffer(&self.petal_instance_buffer, 0, unsafe {
+                vec_as_u8_slice(&self.petal_instance_data)
             });
     }
 
@@ -902,48 +1021,612 @@ impl GraphicsState {
             self.size = new_size;
             self.surface_config.width = new_size.width;
             self.surface_config.height = new_size.height;
-            self.surface.configure(&self.device, &self.surface_config);
+            if let Some(surface) = self.surface.as_ref() {
+                surface.configure(&self.device, &self.surface_config);
+            }
         }
-        self.depth_texture = texture::Texture::create_depth_buffer_texture(
-            &self.device,
-            self.surface_config.width,
-            self.surface_config.height,
-            Some("depth texture"),
-        );
+        self.depth_texture =
+            create_screen_depth_texture(&self.device, &self.surface_config, self.surface.is_some());
     }
 
     /// Return the width/height ratio for the rendering surface.
     pub fn get_aspect_ratio(&self) -> f32 {
         self.surface_config.width as f32 / self.surface_config.height as f32
     }
+
+    /// Returns true if rendered frames are currently being sent to the video coding threads (false
+    /// if there are none, or the export to any of the targets has stopped).
+    pub fn is_exporting_video(&self) -> bool {
+        !self.video_export_states.is_empty()
+            && self
+                .video_export_states
+                .iter()
+                .all(|state| state.video_thread_tx.is_some())
+    }
+
+    /// Returns the progress of the video export (None if video export isn't enabled or has
+    /// finished).  With several export targets, this is the progress of the one furthest behind.
+    pub fn video_export_progress(&self) -> Option<VideoExportProgress> {
+        let exporting_states = self
+            .video_export_states
+            .iter()
+            .filter(|state| state.video_config.export_enabled);
+        exporting_states
+            .map(VideoExportState::progress)
+            .reduce(|a, b| VideoExportProgress {
+                frames_written: a.frames_written.min(b.frames_written),
+                elapsed: a.elapsed.max(b.elapsed),
+                failed: a.failed || b.failed,
+            })
+    }
+
+    /// Waits for all the video frames that have been rendered to be read back, and sends them to
+    /// the video coding threads.
+    pub fn flush_video_frames(&mut self) {
+        for video_export_state in &mut self.video_export_states {
+            while video_export_state.send_oldest_frame(&self.device, true) {}
+        }
+    }
+
+    /// Starts a new segment of the video export: waits for the files being written to be finished,
+    /// and then goes on writing the following frames to the given files (one per export target).
+    /// Image sequences keep their file name pattern, and go on numbering their frames from
+    /// `first_frame_number`.  Returns the first error of any of the export targets.
+    pub fn start_video_segment(
+        &mut self,
+        output_files: &[String],
+        first_frame_number: u64,
+    ) -> anyhow::Result<()> {
+        self.flush_video_frames();
+        for (video_export_state, output_file) in
+            self.video_export_states.iter_mut().zip(output_files)
+        {
+            video_export_state.finish()?;
+            if !video_export_state.video_config.export_enabled {
+                continue;
+            }
+            if video_export_state.video_config.backend == VideoExportBackend::Ffmpeg {
+                video_export_state.video_config.output_file = output_file.clone();
+            }
+            video_export_state.video_config.first_frame_number = first_frame_number;
+            let (video_thread_handle, video_thread_tx) =
+                spawn_video_thread(&video_export_state.video_config);
+            video_export_state.video_thread_handle = Some(video_thread_handle);
+            video_export_state.video_thread_tx = Some(video_thread_tx);
+        }
+        Ok(())
+    }
+
+    /// Stops the video export (if any), waiting for ffmpeg to finish writing the video files.  Any
+    /// frames rendered afterwards are no longer exported.  Returns the first error of any of the
+    /// export targets.
+    pub fn finish_video_export(&mut self) -> anyhow::Result<()> {
+        self.flush_video_frames();
+        let mut result = Ok(());
+        for mut video_export_state in std::mem::take(&mut self.video_export_states) {
+            let finish_result = video_export_state.finish();
+            if result.is_ok() {
+                result = finish_result;
+            }
+        }
+        result
+    }
+}
+
+impl VideoExportState {
+    /// Sets up the off-screen textures, readback buffers, and camera for one export target, and
+    /// spawns its video coding thread (if video export is enabled).
+    fn new(
+        device: &wgpu::Device,
+        video_config: VideoExportConfig,
+        shader_module: &wgpu::ShaderModule,
+        texture_bind_group_layout: &wgpu::BindGroupLayout,
+        camera_bind_group_layout: &wgpu::BindGroupLayout,
+        time_buffer: &wgpu::Buffer,
+    ) -> anyhow::Result<Self> {
+        log::debug!(
+            "Set up video output objects for {}",
+            video_config.output_file
+        );
+        let tile_layout = TileLayout::new(
+            video_config.width,
+            video_config.height,
+            &video_config.tiled_export,
+            device.limits().max_texture_dimension_2d,
+        )
+        .with_context(|| format!("Can't render {}", video_config.output_file))?;
+        if !tile_layout.is_single_tile() {
+            log::info!(
+                "Rendering {} in {} tiles of {}x{} pixels",
+                video_config.output_file,
+                tile_layout.tiles.len(),
+                tile_layout.render_width,
+                tile_layout.render_height,
+            );
+        }
+        let tile_bytes_per_row =
+            u32::from(video_config.texture_format.describe().block_size) * tile_layout.render_width;
+        let padded_tile_bytes_per_row =
+            tile_bytes_per_row.next_multiple_of(wgpu::COPY_BYTES_PER_ROW_ALIGNMENT);
+        let video_texture_descriptor = wgpu::TextureDescriptor {
+            label: Some("video output texture"),
+            size: wgpu::Extent3d {
+                width: tile_layout.render_width,
+                height: tile_layout.render_height,
+                depth_or_array_layers: 1,
+            },
+            mip_level_count: 1,
+            sample_count: 1,
+            dimension: wgpu::TextureDimension::D2,
+            format: video_config.texture_format,
+            // COPY_SRC so we can copy the texture contents to a buffer (video_output_buffer),
+            // RENDER_ATTACHMENT so that we can attach the texture to a render pass so it can be
+            // rendered to.
+            usage: wgpu::TextureUsages::COPY_SRC | wgpu::TextureUsages::RENDER_ATTACHMENT,
+            // Only viewed in its own format (which may differ from the surface's, e.g. for 16-bit
+            // image sequences).
+            view_formats: &[],
+        };
+        let video_texture = Texture::from_descriptor(device, &video_texture_descriptor);
+        let video_depth_texture = texture::Texture::create_depth_buffer_texture(
+            device,
+            tile_layout.render_width,
+            tile_layout.render_height,
+            Some("video depth texture"),
+        );
+        let video_buffer_descriptor = wgpu::BufferDescriptor {
+            label: Some("video output buffer"),
+            size: u64::from(padded_tile_bytes_per_row) * u64::from(tile_layout.render_height),
+            // COPY_DST so we can copy data into the buffer, MAP_READ so that we can read the
+            // contents of the buffer from the CPU side.
+            usage: wgpu::BufferUsages::COPY_DST | wgpu::BufferUsages::MAP_READ,
+            mapped_at_creation: false,
+        };
+        let video_buffers = (0..VIDEO_READBACK_BUFFER_COUNT)
+            .map(|_| VideoReadbackBuffer {
+                buffer: device.create_buffer(&video_buffer_descriptor),
+                tile_idx: 0,
+                submission_index: None,
+                map_result_rx: None,
+            })
+            .collect();
+        let video_render_pipeline = GraphicsState::build_render_pipeline(
+            device,
+            video_config.texture_format,
+            video_config.pass,
+            shader_module,
+            texture_bind_group_layout,
+            camera_bind_group_layout,
+        );
+        let camera_uniform: gpu_types::Matrix4 = cgmath::Matrix4::one().into();
+        let camera_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+            label: Some("Video camera uniform buffer"),
+            contents: unsafe { sized_type_as_u8_slice(&camera_uniform) },
+            usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
+        });
+        let camera_bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
+            label: Some("Video camera bind group"),
+            layout: camera_bind_group_layout,
+            entries: &[
+                wgpu::BindGroupEntry {
+                    binding: 0,
+                    resource: camera_buffer.as_entire_binding(),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 1,
+                    resource: time_buffer.as_entire_binding(),
+                },
+            ],
+        });
+        let alpha_conversion = video_config.transparent_background.enabled.then(|| {
+            crate::alpha::AlphaConversion::new(
+                video_config.transparent_background.alpha,
+                video_config.texture_format,
+            )
+        });
+
+        let pass_encoding = (video_config.pass != ExportPass::Beauty).then(|| {
+            // EXR files can hold depths in world units, while the other formats need them scaled.
+            let world_units = video_config.backend == VideoExportBackend::ImageSequence
+                && video_config.image_sequence.format == ImageSequenceFormat::Exr;
+            crate::auxiliary_passes::PassEncoding::new(
+                video_config.pass,
+                crate::configuration::output_texture_format(
+                    video_config.backend,
+                    video_config.image_sequence,
+                ),
+                world_units,
+            )
+        });
+
+        // -----------------------------------------------------------------------------------------
+        let (video_thread_handle, video_thread_tx) = if video_config.export_enabled {
+            let (video_thread_handle, video_thread_tx) = spawn_video_thread(&video_config);
+            (Some(video_thread_handle), Some(video_thread_tx))
+        } else {
+            (None, None)
+        };
+        Ok(VideoExportState {
+            video_config,
+            tile_layout,
+            tile_bytes_per_row,
+            padded_tile_bytes_per_row,
+            assembled_frame: Vec::new(),
+            camera: camera::UprightPerspectiveCamera::default(),
+            video_texture,
+            video_buffers,
+            next_video_buffer_idx: 0,
+            in_flight_video_buffers: std::collections::VecDeque::new(),
+            video_depth_texture,
+            video_render_pipeline,
+            camera_buffer,
+            camera_bind_group,
+            alpha_conversion,
+            pass_encoding,
+            video_thread_handle,
+            video_thread_tx,
+            frames_written: 0,
+            export_start_time: std::time::Instant::now(),
+            last_progress_log_time: std::time::Instant::now(),
+            export_error: None,
+        })
+    }
+
+    /// Submits the commands that rendered a frame (or one of its tiles) to the video texture,
+    /// together with a copy of it to the next readback buffer, and sends off any earlier frames
+    /// that are ready.
+    fn read_back_tile(
+        &mut self,
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        mut command_encoder: wgpu::CommandEncoder,
+        tile_idx: usize,
+    ) {
+        // Only read the frame back if there is a video coding thread to send it to (there isn't
+        // when running headless without video export enabled).
+        if self.video_thread_tx.is_none() {
+            queue.submit(std::iter::once(command_encoder.finish()));
+            return;
+        }
+        // If the next buffer in the ring still holds a frame, it is the oldest frame in flight, so
+        // wait for it and send it off before reusing the buffer.
+        if self.in_flight_video_buffers.len() == VIDEO_READBACK_BUFFER_COUNT {
+            self.send_oldest_frame(device, true);
+        }
+        let buffer_idx = self.next_video_buffer_idx;
+        let video_buffer = &mut self.video_buffers[buffer_idx];
+        video_buffer.tile_idx = tile_idx;
+        // Copy the results to the buffer that is readable (mappable) by the CPU
+        command_encoder.copy_texture_to_buffer(
+            self.video_texture.texture.as_image_copy(),
+            wgpu::ImageCopyBuffer {
+                buffer: &video_buffer.buffer,
+                layout: wgpu::ImageDataLayout {
+                    offset: 0,
+                    // bytes_per_row must be padded to a multiple of
+                    // wgpu::COPY_BYTES_PER_ROW_ALIGNMENT, which is 256.  With 4 bytes per pixel,
+                    // no padding is needed for x resolutions that are multiples of 64 (like most
+                    // standard video resolutions).  Otherwise, the padding is removed from the end
+                    // of each row when the frame is read back (see the "capture" example in the
+                    // wgpu repository:
+                    // https://github.com/gfx-rs/wgpu/tree/master/wgpu/examples/capture).
+                    bytes_per_row: std::num::NonZeroU32::new(self.padded_tile_bytes_per_row),
+                    // A value for rows_per_image is only required if there are multiple images
+                    // (i.e. the depth is more than 1).
+                    rows_per_image: None, //Some(std::num::NonZeroU32::new(VIDEO_HEIGHT).unwrap()),
+                },
+            },
+            wgpu::Extent3d {
+                width: self.tile_layout.render_width,
+                height: self.tile_layout.render_height,
+                depth_or_array_layers: 1,
+            },
+        );
+        video_buffer.submission_index =
+            Some(queue.submit(std::iter::once(command_encoder.finish())));
+        // This queues up the buffer to be mapped, and then calls the FnOnce with a result passed
+        // in indicating when it has been mapped and is ready to be read from (or an error has
+        // occurred).  The channel lets us check later whether that has happened yet, without
+        // having to wait for the GPU now.
+        let (map_result_tx, map_result_rx) = std::sync::mpsc::channel();
+        video_buffer
+            .buffer
+            .slice(..)
+            .map_async(wgpu::MapMode::Read, move |result| {
+                // The receiver is only gone if the export was stopped in the meantime.
+                let _ = map_result_tx.send(result);
+            });
+        video_buffer.map_result_rx = Some(map_result_rx);
+        self.in_flight_video_buffers.push_back(buffer_idx);
+        self.next_video_buffer_idx = (buffer_idx + 1) % VIDEO_READBACK_BUFFER_COUNT;
+
+        // Send off any frames that have finished rendering in the meantime (without waiting for
+        // the ones that haven't), oldest first so that they stay in order.
+        device.poll(wgpu::Maintain::Poll);
+        while self.send_oldest_frame(device, false) {}
+    }
+
+    fn progress(&self) -> VideoExportProgress {
+        VideoExportProgress {
+            frames_written: self.frames_written,
+            elapsed: self.export_start_time.elapsed(),
+            failed: self.export_error.is_some(),
+        }
+    }
+
+    /// Closes the channel to the video coding thread and waits for it to finish writing the
+    /// output.  The frames must have been flushed already.
+    fn finish(&mut self) -> anyhow::Result<()> {
+        if let Some(error) = self.export_error.take() {
+            return Err(error);
+        }
+        if !self.video_config.export_enabled {
+            return Ok(());
+        }
+        // Close the channel so that the video coding thread will exit normally.
+        drop(self.video_thread_tx.take());
+        // Wait for the video coding thread to exit normally.
+        self.join_video_thread()?;
+        log::info!(
+            "Video export of {} finished: {}",
+            self.video_config.output_file,
+            self.progress()
+        );
+        Ok(())
+    }
+
+    /// Reads back the oldest frame (or tile of a frame) in flight and sends the frame to the video
+    /// coding thread (once all its tiles are in).  If `wait` is false, it is only read if the GPU
+    /// has already finished with it.  Returns true if a frame or tile was taken out of flight
+    /// (false if there was none, or it wasn't ready yet).
+    fn send_oldest_frame(&mut self, device: &wgpu::Device, wait: bool) -> bool {
+        let Some(&buffer_idx) = self.in_flight_video_buffers.front() else {
+            return false;
+        };
+        let video_buffer = &mut self.video_buffers[buffer_idx];
+        if wait {
+            if let Some(submission_index) = video_buffer.submission_index.take() {
+                device.poll(wgpu::Maintain::WaitForSubmissionIndex(submission_index));
+            }
+        }
+        let map_result = match video_buffer.map_result_rx.as_ref().map(|rx| rx.try_recv()) {
+            Some(Err(std::sync::mpsc::TryRecvError::Empty)) if !wait => return false,
+            Some(Ok(map_result)) => map_result,
+            _ => Err(wgpu::BufferAsyncError),
+        };
+        self.in_flight_video_buffers.pop_front();
+        video_buffer.submission_index = None;
+        video_buffer.map_result_rx = None;
+        if map_result.is_err() {
+            // Skipping the frame would silently leave a gap in the video.
+            self.stop_export(anyhow::anyhow!(
+                "Failed to read a video frame back from the GPU"
+            ));
+            return true;
+        }
+        let mapped_buffer = video_buffer.buffer.slice(..).get_mapped_range();
+        let tile_pixel_data = remove_row_padding(
+            &mapped_buffer,
+            self.tile_bytes_per_row as usize,
+            self.padded_tile_bytes_per_row as usize,
+        );
+        // Must drop any views into the buffer before we unmap it.
+        drop(mapped_buffer);
+        video_buffer.buffer.unmap();
+        let tile_idx = video_buffer.tile_idx;
+        let mut frame_pixel_data = if self.tile_layout.is_single_tile() {
+            tile_pixel_data
+        } else {
+            if tile_idx == 0 {
+                self.assembled_frame = vec![0; self.video_config.frame_size as usize];
+            }
+            self.tile_layout.add_tile(
+                self.tile_layout.tiles[tile_idx],
+                &tile_pixel_data,
+                self.video_config.texture_format,
+                &mut self.assembled_frame,
+            );
+            if tile_idx + 1 < self.tile_layout.tiles.len() {
+                return true;
+            }
+            std::mem::take(&mut self.assembled_frame)
+        };
+        if let Some(alpha_conversion) = &self.alpha_conversion {
+            alpha_conversion.convert(&mut frame_pixel_data);
+        }
+        if let Some(pass_encoding) = &self.pass_encoding {
+            frame_pixel_data = pass_encoding.encode(&frame_pixel_data, &self.camera);
+        }
+        if let Some(video_thread_tx) = self.video_thread_tx.as_ref() {
+            if video_thread_tx.send(frame_pixel_data).is_err() {
+                // The video coding thread only exits early if something went wrong, so report why
+                // right away rather than once the export is finished.
+                let error = match self.join_video_thread() {
+                    Ok(()) => anyhow::anyhow!("Video coding thread stopped unexpectedly"),
+                    Err(error) => error,
+                };
+                self.stop_export(error);
+                return true;
+            }
+            self.frames_written += 1;
+            let now = std::time::Instant::now();
+            if now - self.last_progress_log_time >= VIDEO_EXPORT_PROGRESS_LOG_INTERVAL {
+                self.last_progress_log_time = now;
+                log::info!(
+                    "Video export: {}",
+                    VideoExportProgress {
+                        frames_written: self.frames_written,
+                        elapsed: now - self.export_start_time,
+                        failed: false,
+                    }
+                );
+            }
+        }
+        true
+    }
+
+    /// Stops sending frames to the video coding thread, keeping the error for
+    /// finish_video_export().
+    fn stop_export(&mut self, error: anyhow::Error) {
+        log::error!("Video export stopped: {error:#}");
+        self.video_thread_tx = None;
+        if self.export_error.is_none() {
+            self.export_error = Some(error);
+        }
+    }
+
+    /// Waits for the video coding thread to exit, and returns its result.
+    fn join_video_thread(&mut self) -> anyhow::Result<()> {
+        match self.video_thread_handle.take() {
+            Some(thread_handle) => match thread_handle.join() {
+                Ok(result) => result.context("Video export failed"),
+                Err(_) => anyhow::bail!("Video coding thread panicked"),
+            },
+            None => Ok(()),
+        }
+    }
 }
 
 impl Drop for GraphicsState {
     fn drop(&mut self) {
         log::debug!("Dropping GraphicsState");
-        if let Some(mut video_export_state) = self.video_export_state.take() {
-            let tx = video_export_state.video_thread_tx.take();
-            // Close the channel so that the video coding thread will exit normally.
-            drop(tx);
-            // Wait for the video coding thread to exit normally.
-            if let Some(thread_handle) = video_export_state.video_thread_handle.take() {
-                thread_handle.join().unwrap().unwrap();
-            }
+        if let Err(error) = self.finish_video_export() {
+            log::error!("{error:#}");
         }
     }
 }
 
+/// Copies the pixel data out of `padded_data`, whose rows are `padded_bytes_per_row` long, dropping
+/// the padding at the end of each row so that each row is only `bytes_per_row` long.
+fn remove_row_padding(
+    padded_data: &[u8],
+    bytes_per_row: usize,
+    padded_bytes_per_row: usize,
+) -> Vec<u8> {
+    if bytes_per_row == padded_bytes_per_row {
+        return padded_data.to_vec();
+    }
+    padded_data
+        .chunks_exact(padded_bytes_per_row)
+        .flat_map(|padded_row| &padded_row[..bytes_per_row])
+        .copied()
+        .collect()
+}
+
+/// Creates the depth texture used when rendering to the screen.  Without a surface nothing is
+/// rendered to the screen (exported frames have their own depth textures, split into tiles if
+/// needed), so a minimal texture is created rather than one the size of the export, which may be
+/// larger than the GPU allows.
+fn create_screen_depth_texture(
+    device: &wgpu::Device,
+    surface_config: &wgpu::SurfaceConfiguration,
+    has_surface: bool,
+) -> Texture {
+    let (width, height) = if has_surface {
+        (surface_config.width, surface_config.height)
+    } else {
+        (1, 1)
+    };
+    Texture::create_depth_buffer_texture(device, width, height, Some("depth texture"))
+}
+
+/// Spawns the thread that encodes the exported frames with ffmpeg (or writes them as an image
+/// sequence or animated image, or streams them raw), and returns it along with the channel to send
+/// it the frames.
+fn spawn_video_thread(
+    video_config: &VideoExportConfig,
+) -> (
+    std::thread::JoinHandle<anyhow::Result<()>>,
+    std::sync::mpsc::SyncSender<Vec<u8>>,
+) {
+    log::debug!("Spawn video coding thread");
+    // I tried using a std::sync::mpsc::channel() here before, but it seems to accumulate
+    // more and more memory for everything I send over it without bound until my RAM fills
+    // up and things crash. Maybe this is because frames are getting rendered faster than
+    // ffmpeg can encode them?  I'm not sure.  But switching to use a bounded channel
+    // (std::sync::mpsc::sync_channel(bound)) fixed the problem so that now my RAM usage
+    // remains stable.
+    let (video_thread_tx, video_thread_rx) = std::sync::mpsc::sync_channel(1);
+    let output_file_clone = video_config.output_file.clone();
+    let (width, height) = (video_config.width, video_config.height);
+    let video_thread_handle = match video_config.backend {
+        VideoExportBackend::Ffmpeg => {
+            let (audio_input_args, output_args) =
+                crate::video_encoder::ffmpeg_export_args(video_config);
+            let frame_rate = video_config.frame_rate;
+            std::thread::spawn(move || {
+                video_thread_fn(
+                    video_thread_rx,
+                    output_file_clone,
+                    audio_input_args,
+                    output_args,
+                    width,
+                    height,
+                    frame_rate,
+                )
+            })
+        }
+        VideoExportBackend::ImageSequence => {
+            let image_sequence_config = video_config.image_sequence;
+            let first_frame_number = video_config.first_frame_number;
+            // The auxiliary passes hold data rather than colors.
+            let encode_srgb = video_config.pass == ExportPass::Beauty;
+            std::thread::spawn(move || {
+                crate::image_sequence::image_sequence_thread_fn(
+                    video_thread_rx,
+                    output_file_clone,
+                    image_sequence_config,
+                    width,
+                    height,
+                    first_frame_number,
+                    encode_srgb,
+                )
+            })
+        }
+        VideoExportBackend::AnimatedImage => {
+            let animated_image_config = video_config.animated_image;
+            let frame_rate = video_config.frame_rate;
+            std::thread::spawn(move || {
+                crate::animated_image::animated_image_thread_fn(
+                    video_thread_rx,
+                    output_file_clone,
+                    animated_image_config,
+                    width,
+                    height,
+                    frame_rate,
+                )
+            })
+        }
+        VideoExportBackend::RawStream => {
+            let raw_stream_config = video_config.raw_stream;
+            let first_frame_number = video_config.first_frame_number;
+            let frame_rate = video_config.frame_rate;
+            std::thread::spawn(move || {
+                crate::raw_stream::raw_stream_thread_fn(
+                    video_thread_rx,
+                    output_file_clone,
+                    raw_stream_config,
+                    first_frame_number,
+                    frame_rate,
+                )
+            })
+        }
+    };
+    (video_thread_handle, video_thread_tx)
+}
+
 fn video_thread_fn(
     receiver: std::sync::mpsc::Receiver<Vec<u8>>,
     output_file: String,
+    audio_input_args: Vec<String>,
+    output_args: Vec<String>,
     video_width: u32,
     video_height: u32,
     video_fps: u32,
-) -> std::io::Result<()> {
+) -> anyhow::Result<()> {
     log::debug!("Video thread starting.");
     let size_str = format!("{video_width}x{video_height}");
     let frame_rate_str = video_fps.to_string();
-    let gop_str = (video_fps / 2).to_string();
     // Info on ffmpeg: https://ffmpeg.org/ffmpeg.html
     //   input file(s) --> [demuxer] --> encoded data packets --> [decoder] --> decoded frames
     //     --> [optional filter graph] --> filtered frames --> [encoder] --> encoded data packets
@@ -954,7 +1637,9 @@ fn video_thread_fn(
     let mut ffmpeg_process = std::process::Command::new("ffmpeg")
         .args([
             // Global options
-            "-y", // Overwrite output files if they already exist
+            "-y",           // Overwrite output files if they already exist
+            "-hide_banner", // Don't print the version and build options
+            "-nostats",     // Don't print encoding progress (the program reports its own)
             // Input options
             "-f",            // Format
             "rawvideo",      //   raw (no header information, just raw pixel values)
@@ -966,42 +1651,163 @@ fn video_thread_fn(
             &frame_rate_str, //
             "-i",            // Input file
             "-",             //   Input is coming from stdin
-            // Output options
-            "-c:v",       // Video codec
-            "libx264",    //   The default H.264 codec
-            "-preset",    // Preset for video codec
-            "slow",       //   Slower gives better compression
-            "-crf",       // Quality setting for libx264 codec
-            "18",         //   0=51, lower is higher quality, 23 is default
-            "-pix_fmt",   // Pixel format
-            "yuv420p",    //   yuv420p (recommended by YouTube, needed for many devices)
-            "-g",         // Set GOP size (group of pictures / # frames between keyframes)
-            &gop_str,     //   YouTube recommends a GOP of half the frame rate
-            "-bf",        // Limit consecutive B-frames (bidirectionally predicted frames)
-            "2",          //   Youtube recommends a limit of 2 consecutive B-frames.
-            "-movflags",  // Muxer flags
-            "+faststart", //   YouTube recommends MOOV atom at the beginning of the file
-            "-an",        // No audio
-            &output_file, // Output file
         ])
+        // The audio file (if any), as set by the audio config
+        .args(&audio_input_args)
+        // Output options (codec, pixel format, audio, etc.), as set by the video encoder and audio
+        // configs
+        .args(&output_args)
+        .arg(&output_file)
         .stdin(std::process::Stdio::piped())
-        .spawn()?;
+        .stderr(std::process::Stdio::piped())
+        .spawn()
+        .context("Could not start ffmpeg")?;
     let mut ffmpeg_stdin = ffmpeg_process.stdin.take().unwrap();
+    // Read ffmpeg's messages as they come, so that it can't block on a full pipe.
+    let ffmpeg_stderr = ffmpeg_process.stderr.take().unwrap();
+    let stderr_thread = std::thread::spawn(move || read_ffmpeg_stderr(ffmpeg_stderr));
     let mut frame_count = 0;
+    let mut write_result = Ok(());
     while let Ok(message) = receiver.recv() {
-        ffmpeg_stdin.write_all(&message).unwrap();
+        // Writing fails if ffmpeg has exited (e.g. because of a bad option or a full disk).
+        write_result = ffmpeg_stdin.write_all(&message);
+        if write_result.is_err() {
+            break;
+        }
         frame_count += 1;
         if frame_count % video_fps == 0 {
             log::debug!("Video duration = {}s", frame_count / video_fps);
         }
     }
-    log::debug!("Flushing out last frames");
-    ffmpeg_stdin.flush().unwrap();
-    log::debug!("Done flushing frames.  Closing the pipe and waiting for ffmpeg to finish...");
+    // Stop accepting frames right away if writing failed.
+    drop(receiver);
+    if write_result.is_ok() {
+        log::debug!("Flushing out last frames");
+        write_result = ffmpeg_stdin.flush();
+    }
+    log::debug!("Done sending frames.  Closing the pipe and waiting for ffmpeg to finish...");
     // Close the pipe to ffmpeg so that ffmpeg will finish and exit
     drop(ffmpeg_stdin);
     // Wait for ffmpeg to finish and exit
-    let ffmpeg_output = ffmpeg_process.wait_with_output().unwrap();
-    log::debug!("ffmpeg finished! Output: {:?}", ffmpeg_output);
-    Ok(())
+    let status = ffmpeg_process.wait().context("Failed to wait for ffmpeg")?;
+    let stderr_tail = stderr_thread.join().unwrap_or_default();
+    log::debug!("ffmpeg finished with {status}");
+    if !status.success() {
+        anyhow::bail!("ffmpeg exited with {status}:\n{stderr_tail}");
+    }
+    write_result.with_context(|| format!("Failed to send frames to ffmpeg:\n{stderr_tail}"))
+}
+
+/// Number of lines at the end of ffmpeg's output that are included in errors.
+const FFMPEG_STDERR_TAIL_LINES: usize = 20;
+
+/// Logs everything ffmpeg writes to stderr (at the debug level), and returns the last few lines.
+fn read_ffmpeg_stderr(stderr: impl std::io::Read) -> String {
+    let mut tail = std::collections::VecDeque::with_capacity(FFMPEG_STDERR_TAIL_LINES);
+    for line in std::io::BufReader::new(stderr).split(b'\n') {
+        let Ok(line) = line else {
+            break;
+        };
+        let line = String::from_utf8_lossy(&line).trim_end().to_string();
+        log::debug!("ffmpeg: {line}");
+        if tail.len() == FFMPEG_STDERR_TAIL_LINES {
+            tail.pop_front();
+        }
+        tail.push_back(line);
+    }
+    Vec::from(tail).join("\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{export_frames, gpu_test, headless_test_config, headless_test_state};
+
+    #[test]
+    fn row_padding_is_removed() {
+        // 2 rows of 3 bytes, each padded to 5 bytes.
+        let padded_data = [1, 2, 3, 0, 0, 4, 5, 6, 0, 0];
+        assert_eq!(remove_row_padding(&padded_data, 3, 5), [1, 2, 3, 4, 5, 6]);
+        assert_eq!(remove_row_padding(&padded_data, 5, 5), padded_data);
+    }
+
+    #[test]
+    fn ffmpeg_stderr_keeps_last_lines() {
+        let stderr = (1..=30)
+            .map(|line| format!("line {line}\n"))
+            .collect::<String>();
+        let tail = read_ffmpeg_stderr(stderr.as_bytes());
+        assert_eq!(tail.lines().count(), FFMPEG_STDERR_TAIL_LINES);
+        assert!(tail.starts_with("line 11\n"));
+        assert!(tail.ends_with("line 30"));
+    }
+
+    #[test]
+    fn exports_to_several_targets() {
+        use crate::configuration::{ExportTargetConfig, VideoExportBackend};
+        let Some((_gpu_lock, directory)) = gpu_test("export_targets") else {
+            return;
+        };
+        let mut config = headless_test_config("export_targets", Some(6));
+        let sizes = [("landscape", 48, 27), ("portrait", 20, 36)];
+        config.export_targets = sizes
+            .iter()
+            .map(|&(name, width, height)| ExportTargetConfig {
+                file: directory
+                    .join(format!("{name}_##.png"))
+                    .to_string_lossy()
+                    .into_owned(),
+                width,
+                height,
+                aspect_ratio: None,
+                backend: VideoExportBackend::ImageSequence,
+                encoder: Default::default(),
+                image_sequence: Default::default(),
+                animated_image: Default::default(),
+                raw_stream: Default::default(),
+            })
+            .collect();
+        export_frames(config, 2);
+        for (name, width, height) in sizes {
+            for frame_number in 0..2 {
+                let frame =
+                    image::open(directory.join(format!("{name}_{frame_number:02}.png"))).unwrap();
+                assert_eq!((frame.width(), frame.height()), (width, height));
+            }
+        }
+    }
+
+    #[test]
+    fn failed_video_export_stops_gracefully() {
+        let Some((_gpu_lock, _)) = gpu_test("export_failure") else {
+            return;
+        };
+        let mut simulation_state = headless_test_state("export_failure", Some(9));
+        // Stands in for an encoder that fails after the first frame (e.g. because the disk filled
+        // up).
+        let (tx, rx) = std::sync::mpsc::sync_channel::<Vec<u8>>(1);
+        let video_thread_handle = std::thread::spawn(move || {
+            rx.recv()?;
+            anyhow::bail!("disk full")
+        });
+        let video_export_state = &mut simulation_state.graphics_state.video_export_states[0];
+        video_export_state.video_config.export_enabled = true;
+        video_export_state.video_thread_tx = Some(tx);
+        video_export_state.video_thread_handle = Some(video_thread_handle);
+        for _ in 0..10 {
+            simulation_state.update();
+            simulation_state.render().unwrap();
+        }
+        assert!(!simulation_state.graphics_state.is_exporting_video());
+        let progress = simulation_state
+            .graphics_state
+            .video_export_progress()
+            .unwrap();
+        assert!(progress.failed);
+        let error = simulation_state
+            .graphics_state
+            .finish_video_export()
+            .unwrap_err();
+        assert!(format!("{error:#}").contains("disk full"), "{error:#}");
+    }
 }
@@ -22,7 +22,7 @@ pub const CGMATH_NDC_TO_WGPU_NDC_MATRIX: cgmath::Matrix4<f32> = cgmath::Matrix4:
 /// Represents a camera that can turn side to side and look up and down, but cannot roll.  With zero
 /// rotation, the camera points along the -z axis with the x axis pointing to the right and the y
 /// axis pointing up.
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct UprightPerspectiveCamera {
     /// Coordinate of the focal point of the camera.
     pub location: cgmath::Point3<f32>,
@@ -97,6 +97,43 @@ impl UprightPerspectiveCamera {
         }
     }
 
+    /// Returns the view-projection matrix for just a part of the camera's view: the rectangle with
+    /// the given left and top edges, width, and height (as fractions of the width and height of the
+    /// full view, from its top left corner).  The rectangle fills the whole clip space, which is
+    /// used to render large frames in tiles.  Parts of the rectangle can lie outside the full view.
+    pub fn get_sub_frustum_view_projection_matrix(
+        &self,
+        left: f32,
+        top: f32,
+        width: f32,
+        height: f32,
+    ) -> cgmath::Matrix4<f32> {
+        // Range of the rectangle in normalized device coordinates (where y points up).
+        let (x_min, x_max) = (2.0 * left - 1.0, 2.0 * (left + width) - 1.0);
+        let (y_min, y_max) = (1.0 - 2.0 * (top + height), 1.0 - 2.0 * top);
+        // Scale and translate x and y (in clip space, so the translation is multiplied by w) so that
+        // the rectangle maps to [-1, 1].
+        let crop = cgmath::Matrix4::new(
+            2.0 / (x_max - x_min),
+            0.0,
+            0.0,
+            0.0, // col 1
+            0.0,
+            2.0 / (y_max - y_min),
+            0.0,
+            0.0, // col 2
+            0.0,
+            0.0,
+            1.0,
+            0.0, // col 3
+            -(x_max + x_min) / (x_max - x_min),
+            -(y_max + y_min) / (y_max - y_min),
+            0.0,
+            1.0, // col 4
+        );
+        crop * self.get_view_projection_matrix()
+    }
+
     /// Moves the camera relative to it's current pan orientation (but not relative to it's tilt).
     pub fn move_relative_to_pan_angle(&mut self, forward: f32, right: f32, up: f32) {
         let pan_rotation = cgmath::Quaternion::<f32>::from_angle_y(self.pan_angle);
@@ -1,6 +1,7 @@
 //! This module defines structs that have memory layouts that are compatible with being placed into
 //! GPU buffers.
 
+use crate::configuration::PetalCurvatureConfig;
 use cgmath::prelude::*;
 
 /// Trait for objects that can be placed in vertex buffers in wgpu.  Defines an associated function
@@ -273,11 +274,14 @@ impl From<u32> for UniformU32 {
 /// Struct to store the texture index and the u/v coordinate and width and height of the section of
 /// the texture to use when rendering a particular petal.  This allows me to pick between multiple
 /// textures and slice out individual petals from textures that contain multiple images of petals.
+/// It also holds the curvature profile (cup, saddle, curl, and twist amounts) applied to the mesh of
+/// petals using this variant.
 #[repr(C)]
 #[derive(Debug, Copy, Clone)]
 pub struct PetalVariant {
     pub petal_texture_index: UniformU32,
     pub texture_u_v_width_height: Vector4,
+    pub curvature: Vector4,
 }
 
 impl PetalVariant {
@@ -287,12 +291,88 @@ impl PetalVariant {
         tex_v: f32,
         tex_width: f32,
         tex_height: f32,
+        curvature: &PetalCurvatureConfig,
     ) -> Self {
         PetalVariant {
             petal_texture_index: texture_index.into(),
             texture_u_v_width_height: Vector4 {
                 vector: [tex_u, tex_v, tex_width, tex_height],
             },
+            curvature: Vector4 {
+                vector: [
+                    curvature.cup,
+                    curvature.saddle,
+                    curvature.curl,
+                    cgmath::Rad::from(curvature.twist).0,
+                ],
+            },
+        }
+    }
+}
+
+/// Per-instance data for each petal (other than its pose), passed to the shaders through a vertex
+/// buffer with an instance step mode.
+#[repr(C)]
+#[derive(Debug, Copy, Clone)]
+pub struct PetalInstanceData {
+    /// Phase (in radians) of the petal's bend animation.
+    pub bend_phase: f32,
+    /// Angular frequency (in radians per second) of the petal's bend animation.
+    pub bend_frequency: f32,
+    /// Fraction by which the static petal bend offsets grow and shrink over the animation cycle.
+    pub bend_amplitude: f32,
+    /// How far the tips of the petal (along its x axis) flap up and down over the animation cycle.
+    pub flap_amplitude: f32,
+    /// Index of the petal variant (which part of which texture, and which curvature profile) used
+    /// to render the petal.
+    pub variant_index: u32,
+    /// Number identifying the petal in the ID matte pass (see PetalState::id).
+    pub petal_id: u32,
+}
+
+impl VertexBufferEntry for PetalInstanceData {
+    fn vertex_buffer_layout<'a>() -> wgpu::VertexBufferLayout<'a> {
+        wgpu::VertexBufferLayout {
+            array_stride: std::mem::size_of::<PetalInstanceData>() as wgpu::BufferAddress,
+            step_mode: wgpu::VertexStepMode::Instance,
+            attributes: &[
+                // All four bend animation parameters are packed into a single vec4 in the shader.
+                wgpu::VertexAttribute {
+                    offset: 0,
+                    shader_location: 9,
+                    format: wgpu::VertexFormat::Float32x4,
+                },
+                wgpu::VertexAttribute {
+                    offset: std::mem::size_of::<[f32; 4]>() as wgpu::BufferAddress,
+                    shader_location: 10,
+                    format: wgpu::VertexFormat::Uint32,
+                },
+                wgpu::VertexAttribute {
+                    offset: std::mem::size_of::<[f32; 5]>() as wgpu::BufferAddress,
+                    shader_location: 11,
+                    format: wgpu::VertexFormat::Uint32,
+                },
+            ],
+        }
+    }
+}
+
+/// Struct used to pass the current simulation time into shaders through a uniform buffer.
+#[repr(C)]
+#[derive(Debug, Copy, Clone)]
+pub struct SimulationTimeUniform {
+    /// Simulation time in seconds, based on the video export frame rate (so that it advances by the
+    /// same amount each frame regardless of how fast frames are actually rendered).
+    pub seconds: f32,
+    /// Needed to give this struct the minimum 16-byte size required by uniform buffers.
+    _pad: [f32; 3],
+}
+
+impl SimulationTimeUniform {
+    pub fn new(seconds: f32) -> Self {
+        Self {
+            seconds,
+            _pad: [0.0; 3],
         }
     }
 }
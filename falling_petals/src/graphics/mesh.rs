@@ -0,0 +1,130 @@
+//! Generation of the grid of vertices (and the triangles connecting them) used to render each petal.
+//! A petal spans [-1, 1] in both x and y (before it is scaled by its pose), with x along the length
+//! of the petal images in the default texture.  Any curvature profile of the petal's variant is
+//! applied on top of this mesh in the vertex shader.
+
+use super::gpu_types::PositionTextureVertex;
+
+/// Index list that defines the tesselation of the 3x3 grid of vertices used to render each petal.
+/// In this case, all triangles share the center vertex (it creates fan of 8 triangles around the
+/// center vertex).
+const FAN_3X3_INDICES: [u16; 24] = [
+    0, 4, 1, //
+    1, 4, 2, //
+    2, 4, 5, //
+    5, 4, 8, //
+    8, 4, 7, //
+    7, 4, 6, //
+    6, 4, 3, //
+    3, 4, 0, //
+];
+
+/// Largest supported grid resolution (so that all vertex indices fit in a u16).
+pub const MAX_RESOLUTION: u32 = 256;
+
+/// Generates a `resolution` x `resolution` grid of vertices in row-major order (top row first),
+/// spanning [-1, 1] in x and y.  The z coordinates are bilinearly interpolated from the 9 static
+/// bend offsets, which are given in row-major order for a 3x3 grid.
+pub fn grid_vertices(resolution: u32, bend_offsets: &[f32; 9]) -> Vec<PositionTextureVertex> {
+    let last = (resolution - 1) as f32;
+    let mut vertices = Vec::with_capacity((resolution * resolution) as usize);
+    for row in 0..resolution {
+        for col in 0..resolution {
+            let u = col as f32 / last;
+            let v = row as f32 / last;
+            vertices.push(PositionTextureVertex {
+                position: [
+                    2.0 * u - 1.0,
+                    1.0 - 2.0 * v,
+                    interpolate_offset(bend_offsets, u, v),
+                ],
+                texture_coords: [u, v],
+            });
+        }
+    }
+    vertices
+}
+
+/// Bilinearly interpolates the 3x3 grid of `offsets` at texture coordinates (u, v).
+fn interpolate_offset(offsets: &[f32; 9], u: f32, v: f32) -> f32 {
+    // Find which quarter of the 3x3 grid the point is in, and where it lies within that quarter.
+    let col = ((2.0 * u) as usize).min(1);
+    let row = ((2.0 * v) as usize).min(1);
+    let s = 2.0 * u - col as f32;
+    let t = 2.0 * v - row as f32;
+    let offset = |r: usize, c: usize| offsets[3 * r + c];
+    let top = offset(row, col) * (1.0 - s) + offset(row, col + 1) * s;
+    let bottom = offset(row + 1, col) * (1.0 - s) + offset(row + 1, col + 1) * s;
+    top * (1.0 - t) + bottom * t
+}
+
+/// Generates the triangle indices for a grid of vertices created by `grid_vertices`.  A 3x3 grid
+/// uses the original fan of 8 triangles around the center vertex.  Larger grids split each cell
+/// into two triangles, alternating the direction of the diagonal between neighboring cells so that
+/// the triangulation is symmetric.
+pub fn grid_indices(resolution: u32) -> Vec<u16> {
+    if resolution == 3 {
+        return FAN_3X3_INDICES.to_vec();
+    }
+    let n_cells = (resolution - 1) as usize;
+    let mut indices = Vec::with_capacity(6 * n_cells * n_cells);
+    for row in 0..resolution - 1 {
+        for col in 0..resolution - 1 {
+            let top_left = (row * resolution + col) as u16;
+            let top_right = top_left + 1;
+            let bottom_left = top_left + resolution as u16;
+            let bottom_right = bottom_left + 1;
+            if (row + col) % 2 == 0 {
+                indices.extend([top_left, bottom_left, bottom_right]);
+                indices.extend([top_left, bottom_right, top_right]);
+            } else {
+                indices.extend([top_left, bottom_left, top_right]);
+                indices.extend([top_right, bottom_left, bottom_right]);
+            }
+        }
+    }
+    indices
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const OFFSETS: [f32; 9] = [0.0, 1.0, 0.0, -1.0, 0.0, -1.0, 0.0, 1.0, 0.0];
+
+    #[test]
+    fn grid_passes_through_static_offsets() {
+        let vertices = grid_vertices(5, &OFFSETS);
+        assert_eq!(vertices.len(), 25);
+        for (row, col, offset_idx) in [(0, 0, 0), (0, 2, 1), (2, 0, 3), (2, 2, 4), (4, 4, 8)] {
+            let vertex = &vertices[row * 5 + col];
+            assert_eq!(vertex.position[2], OFFSETS[offset_idx]);
+        }
+        // Halfway between the top middle (1.0) and center (0.0) vertices.
+        assert_eq!(vertices[5 + 2].position[2], 0.5);
+        assert_eq!(vertices[24].position[..2], [1.0, -1.0]);
+        assert_eq!(vertices[24].texture_coords, [1.0, 1.0]);
+    }
+
+    #[test]
+    fn indices_cover_grid_with_counter_clockwise_triangles() {
+        for resolution in [2, 3, 4, 9] {
+            let vertices = grid_vertices(resolution, &OFFSETS);
+            let indices = grid_indices(resolution);
+            let n_triangles = if resolution == 3 {
+                8
+            } else {
+                2 * (resolution - 1).pow(2)
+            };
+            assert_eq!(indices.len() as u32, 3 * n_triangles);
+            let mut total_area = 0.0;
+            for triangle in indices.chunks(3) {
+                let [a, b, c] = [0, 1, 2].map(|i| vertices[triangle[i] as usize].position);
+                let area = 0.5 * ((b[0] - a[0]) * (c[1] - a[1]) - (c[0] - a[0]) * (b[1] - a[1]));
+                assert!(area > 0.0, "clockwise triangle {triangle:?}");
+                total_area += area;
+            }
+            assert!((total_area - 4.0).abs() < 1e-5);
+        }
+    }
+}
@@ -0,0 +1,14 @@
+// Petal texture bindings used when the GPU supports binding arrays of textures and samplers (with
+// non-uniform indexing).  Each petal texture keeps its own size and sampler.
+@group(0) @binding(0)
+var texture_pipeline_petal_textures: binding_array<texture_2d<f32>>;
+@group(0) @binding(1)
+var texture_pipeline_petal_samplers: binding_array<sampler>;
+
+fn sample_petal_texture(tex_idx: u32, coords: vec2<f32>) -> vec4<f32> {
+    return textureSample(
+        texture_pipeline_petal_textures[tex_idx],
+        texture_pipeline_petal_samplers[tex_idx],
+        coords,
+    );
+}
@@ -0,0 +1,17 @@
+// Petal texture bindings used as a fallback when the GPU does not support binding arrays.  All the
+// petal textures are packed into the layers of a single 2D texture array (resized to a common size
+// when they are loaded), which is sampled with a single sampler.
+@group(0) @binding(0)
+var texture_pipeline_petal_textures: texture_2d_array<f32>;
+@group(0) @binding(1)
+var texture_pipeline_petal_sampler: sampler;
+
+fn sample_petal_texture(tex_idx: u32, coords: vec2<f32>) -> vec4<f32> {
+    return textureSample(
+        texture_pipeline_petal_textures,
+        texture_pipeline_petal_sampler,
+        coords,
+        // This version of naga only accepts a signed array index.
+        i32(tex_idx),
+    );
+}
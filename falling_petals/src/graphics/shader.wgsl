@@ -61,6 +61,7 @@ struct PositionTextureIndexVertexOutput {
     @builtin(position) clip_position: vec4<f32>,
     @location(0) texture_coords: vec2<f32>,
     @location(1) @interpolate(flat) index: u32,
+    @location(2) @interpolate(flat) petal_id: u32,
 };
 
 struct PoseInput {
@@ -70,10 +71,24 @@ struct PoseInput {
     @location(8) pose_matrix_c3: vec4<f32>,
 };
 
+// Per-instance parameters of the petal bend animation:  phase (radians), angular frequency (radians
+// per second), bend amplitude, and flap amplitude, in that order.  Also the index of the petal
+// variant used to render the petal, and the number identifying the petal in the ID matte pass.
+struct PetalInstanceInput {
+    @location(9) bend_phase_frequency_amplitudes: vec4<f32>,
+    @location(10) variant_index: u32,
+    @location(11) petal_id: u32,
+};
+
 struct Matrix4Uniform {
     matrix4: mat4x4<f32>,
 };
 
+// Uniform buffers must be at least 16 bytes, so the time is padded out to a vec4 on the Rust side.
+struct SimulationTimeUniform {
+    seconds: f32,
+};
+
 // Define uniforms passed in through the bind group.  Note that shaders that do not access these 
 // uniform variables should not need the bind group with them to be present (I think).  If I
 // understand correctly, these bindings will only apply to shaders where the variable with that
@@ -82,6 +97,8 @@ struct Matrix4Uniform {
 var<uniform> color_pipeline_camera: Matrix4Uniform;
 @group(1) @binding(0)
 var<uniform> texture_pipeline_camera: Matrix4Uniform;
+@group(1) @binding(1)
+var<uniform> texture_pipeline_time: SimulationTimeUniform;
 
 @vertex
 fn vs_colored_vertex(vertex_in: PositionColorVertexInput) -> PositionColorVertexOutput {
@@ -95,7 +112,7 @@ fn vs_colored_vertex(vertex_in: PositionColorVertexInput) -> PositionColorVertex
 fn vs_textured_vertex(
     model: PositionTextureVertexInput,
     pose: PoseInput, 
-    @builtin(instance_index) instance_index: u32,
+    instance: PetalInstanceInput,
 ) -> PositionTextureIndexVertexOutput {
     let pose_matrix = mat4x4<f32>(
         pose.pose_matrix_c0,
@@ -103,10 +120,33 @@ fn vs_textured_vertex(
         pose.pose_matrix_c2,
         pose.pose_matrix_c3,
     );
+    // Animate the petal bend:  scale the static bend offsets up and down, and flap the left and
+    // right tips of the petal (a quarter cycle out of phase with the bend, so the shape of the
+    // petal changes over the cycle rather than just its amount of bend).
+    let bend = instance.bend_phase_frequency_amplitudes;
+    let angle = bend[0] + bend[1] * texture_pipeline_time.seconds;
+    var position = model.position;
+    position.z = position.z * (1.0 + bend[2] * sin(angle))
+        + bend[3] * cos(angle) * position.x * position.x;
+    // Apply the curvature profile (cup, saddle, curl, twist) of this petal's variant.
+    let curvature = texture_pipeline_petal_variants.petal_variants[instance.variant_index].curvature;
+    let x2 = position.x * position.x;
+    let y2 = position.y * position.y;
+    let tip = max(position.x, 0.0);
+    position.z = position.z + curvature[0] * (x2 + y2) + curvature[1] * (x2 - y2)
+        + curvature[2] * tip * tip;
+    let twist_angle = curvature[3] * position.x;
+    position = vec3<f32>(
+        position.x,
+        position.y * cos(twist_angle) - position.z * sin(twist_angle),
+        position.y * sin(twist_angle) + position.z * cos(twist_angle),
+    );
     var out: PositionTextureIndexVertexOutput;
     out.texture_coords = model.texture_coords;
-    out.clip_position = texture_pipeline_camera.matrix4 * pose_matrix * vec4<f32>(model.position, 1.0);
-    out.index = instance_index;
+    out.clip_position = texture_pipeline_camera.matrix4 * pose_matrix * vec4<f32>(position, 1.0);
+    // Pass the variant index and petal ID on to the fragment shader (without interpolation).
+    out.index = instance.variant_index;
+    out.petal_id = instance.petal_id;
     return out;
 }
 
@@ -138,6 +178,7 @@ struct PositionTextureIndexFragmentInput {
     @builtin(position) screen_position: vec4<f32>,
     @location(0) texture_coords: vec2<f32>,
     @location(1) @interpolate(flat) index: u32,
+    @location(2) @interpolate(flat) petal_id: u32,
 };
 
 @fragment
@@ -155,18 +196,6 @@ fn fs_colored_vertex(fragment_in: PositionColorFragmentInput) -> @location(0) ve
     //return vec4<f32>(r, g, b, 1.0);
 }
 
-// Uniform buffers require a stride of at least 16 between elements of an array.  To pass an array
-// with elements with a smaller stride than that, we must wrap them to give them a stride of 16.
-// See https://www.w3.org/TR/WGSL/#address-space-layout-constraints, particularly the examples.
-// Or we can pack and unpack them from an array of vec4s so we don't waste space.
-//struct UniformU32{
-//    @size(16) value: u32,
-//};
-// Note:  N_VEC4_OF_PETAL_INDICES gets textually replaced with the appropriate value when the shader
-// code is loaded, and before the shader gets compiled.
-struct PetalVariantIndexArray{
-    petal_variant_indices: array<vec4<u32>, N_VEC4_OF_PETAL_INDICES>,
-}
 struct PetalVariant {
     // Note:  This used to be UniformU32, but I changed it when I thought that things within a
     // uniform buffer might not have to be 16-byte aligned and that just the start of the buffer
@@ -177,6 +206,8 @@ struct PetalVariant {
     // though I am no longer using a UniformU32 (with explicit padding) here.
     petal_texture_index: u32,
     texture_u_v_width_height: vec4<f32>,
+    // Amounts of cup, saddle, and curl, followed by the twist angle (in radians).
+    curvature: vec4<f32>,
 };
 // Note:  N_PETAL_VARIANTS gets textually replaced with the appropriate value when the shader code
 // is loaded, and before the shader gets compiled.
@@ -188,26 +219,22 @@ struct PetalVariantArray {
 // uniform variables should not need the bind group with them to be present (I think).  If I
 // understand correctly, these bindings will only apply to shaders where the variable with that
 // specified binding is used (anywhere in the shader's function hierarchy).
-@group(0) @binding(0)
-var texture_pipeline_petal_textures: binding_array<texture_2d<f32>>;
-@group(0) @binding(1)
-var texture_pipeline_petal_samplers: binding_array<sampler>;
+// Note:  The line below gets textually replaced with the declarations of the petal textures (at
+// bindings 0 and 1) and of a sample_petal_texture() function, taken from either
+// petal_textures_binding_array.wgsl or petal_textures_texture_array.wgsl depending on what the GPU
+// supports.
+PETAL_TEXTURE_BINDINGS
 @group(0) @binding(2)
 var<uniform> texture_pipeline_petal_variants: PetalVariantArray;
-@group(0) @binding(3)
-var<uniform> texture_pipeline_petal_variant_indices: PetalVariantIndexArray;
 
-@fragment
-fn fs_textured_vertex(in: PositionTextureIndexFragmentInput) -> @location(0) vec4<f32> {
-    // Cast in.index from u32 to i32 because apparently either wgsl or naga does not allow the
-    // division and modulo operators to be used on u32, but they do work with i32.
-    let idx: i32 = bitcast<i32>(in.index);
-    let variant_idx = texture_pipeline_petal_variant_indices.petal_variant_indices[idx / 4][idx % 4];
+// Returns the color of the petal at the fragment (premultiplied by its alpha).
+fn petal_color(in: PositionTextureIndexFragmentInput) -> vec4<f32> {
+    // The index passed in from the vertex shader is the petal's variant index.
+    let variant_idx = in.index;
     let tex_idx = texture_pipeline_petal_variants.petal_variants[variant_idx].petal_texture_index;
     let tex_bounds = texture_pipeline_petal_variants.petal_variants[variant_idx].texture_u_v_width_height;
-    var texture_sample = textureSample(
-        texture_pipeline_petal_textures[tex_idx], 
-        texture_pipeline_petal_samplers[tex_idx], 
+    var texture_sample = sample_petal_texture(
+        tex_idx,
         vec2<f32>(
             tex_bounds[0] + in.texture_coords[0] * tex_bounds[2],
             tex_bounds[1] + in.texture_coords[1] * tex_bounds[3],
@@ -221,9 +248,46 @@ fn fs_textured_vertex(in: PositionTextureIndexFragmentInput) -> @location(0) vec
         let alpha = (1.0 - in.screen_position[2]) / 0.0015;
         texture_sample = texture_sample * alpha;
     }
+    return texture_sample;
+}
+
+@fragment
+fn fs_textured_vertex(in: PositionTextureIndexFragmentInput) -> @location(0) vec4<f32> {
+    let texture_sample = petal_color(in);
     if texture_sample[3] < 0.01{
         discard;
     } else {
         return texture_sample;
     }
 }
+
+// Fragment shaders of the auxiliary passes.  They cover the same pixels as the petals do in the
+// beauty pass (and the depth test keeps the nearest petal), but write data instead of colors, so
+// they are rendered without blending.
+
+// Writes the value that goes into the depth buffer (which is linearized on the CPU).
+@fragment
+fn fs_depth(in: PositionTextureIndexFragmentInput) -> @location(0) vec4<f32> {
+    if petal_color(in)[3] < 0.01 {
+        discard;
+    }
+    return vec4<f32>(in.screen_position[2], 0.0, 0.0, 1.0);
+}
+
+// Writes the petal's ID plus one (zero is left for the background).
+@fragment
+fn fs_petal_id(in: PositionTextureIndexFragmentInput) -> @location(0) vec4<u32> {
+    if petal_color(in)[3] < 0.01 {
+        discard;
+    }
+    return vec4<u32>(in.petal_id + 1u, 0u, 0u, 1u);
+}
+
+// Writes the index of the petal's variant plus one (zero is left for the background).
+@fragment
+fn fs_variant_id(in: PositionTextureIndexFragmentInput) -> @location(0) vec4<u32> {
+    if petal_color(in)[3] < 0.01 {
+        discard;
+    }
+    return vec4<u32>(in.index + 1u, 0u, 0u, 1u);
+}
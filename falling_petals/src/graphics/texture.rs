@@ -148,6 +148,123 @@ impl Texture {
         })
     }
 
+    /// Returns the size of the layers of a texture array holding the given images: the largest
+    /// width and height among them.
+    pub fn array_layer_size(imgs: &[image::DynamicImage]) -> (u32, u32) {
+        (
+            imgs.iter().map(|img| img.width()).max().unwrap_or(1),
+            imgs.iter().map(|img| img.height()).max().unwrap_or(1),
+        )
+    }
+
+    /// Returns the factors that scale texture coordinates relative to the given image to texture
+    /// coordinates relative to its layer of a texture array created by array_from_images.
+    pub fn array_uv_scale(img: &image::DynamicImage, layer_size: (u32, u32)) -> [f32; 2] {
+        [
+            img.width() as f32 / layer_size.0 as f32,
+            img.height() as f32 / layer_size.1 as f32,
+        ]
+    }
+
+    /// Creates a single 2D texture array with one layer per image, for GPUs that do not support
+    /// binding arrays of textures.  All layers of a texture array must be the same size (see
+    /// array_layer_size), so each image is placed in the top left corner of its layer and the rest
+    /// of the layer is left transparent.  Texture coordinates must be scaled with array_uv_scale to
+    /// refer to the same part of the image.
+    pub fn array_from_images(
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        imgs: &[image::DynamicImage],
+        label: Option<&str>,
+    ) -> Result<Self> {
+        let (width, height) = Self::array_layer_size(imgs);
+        let limits = device.limits();
+        if width > limits.max_texture_dimension_2d
+            || height > limits.max_texture_dimension_2d
+            || imgs.len() as u32 > limits.max_texture_array_layers
+        {
+            bail!(
+                "{} petal textures of up to {width}x{height} pixels do not fit in a texture array \
+                (max {}x{} pixels, {} layers)",
+                imgs.len(),
+                limits.max_texture_dimension_2d,
+                limits.max_texture_dimension_2d,
+                limits.max_texture_array_layers,
+            );
+        }
+        let size = wgpu::Extent3d {
+            width,
+            height,
+            // The GL backend creates textures with a single layer as plain 2D textures, which can't
+            // be sampled as a texture array.  So always allocate at least 2 layers.
+            depth_or_array_layers: (imgs.len() as u32).max(2),
+        };
+        let texture_label = label.map(String::from);
+        let texture = device.create_texture(&wgpu::TextureDescriptor {
+            label: texture_label.as_ref().map(|label| label as &str),
+            size,
+            mip_level_count: 1,
+            sample_count: 1,
+            dimension: wgpu::TextureDimension::D2,
+            format: wgpu::TextureFormat::Rgba8UnormSrgb,
+            usage: wgpu::TextureUsages::TEXTURE_BINDING | wgpu::TextureUsages::COPY_DST,
+            view_formats: &[],
+        });
+        // New textures are zeroed, so the padding around smaller images is already transparent.
+        for (layer, img) in imgs.iter().enumerate() {
+            let rgba_image = img.to_rgba8();
+            let (image_width, image_height) = rgba_image.dimensions();
+            queue.write_texture(
+                wgpu::ImageCopyTexture {
+                    texture: &texture,
+                    mip_level: 0,
+                    origin: wgpu::Origin3d {
+                        x: 0,
+                        y: 0,
+                        z: layer as u32,
+                    },
+                    aspect: wgpu::TextureAspect::All,
+                },
+                &rgba_image,
+                wgpu::ImageDataLayout {
+                    offset: 0,
+                    bytes_per_row: std::num::NonZeroU32::new(4 * image_width),
+                    rows_per_image: std::num::NonZeroU32::new(image_height),
+                },
+                wgpu::Extent3d {
+                    width: image_width,
+                    height: image_height,
+                    depth_or_array_layers: 1,
+                },
+            );
+        }
+
+        let view = texture.create_view(&wgpu::TextureViewDescriptor {
+            dimension: Some(wgpu::TextureViewDimension::D2Array),
+            ..Default::default()
+        });
+
+        let sampler_label = label.map(|texture_label| format!("{texture_label} sampler"));
+        let sampler = device.create_sampler(&wgpu::SamplerDescriptor {
+            label: sampler_label.as_ref().map(|label| label as &str),
+            // Same settings as for the individual textures created by from_image()
+            address_mode_u: wgpu::AddressMode::ClampToEdge,
+            address_mode_v: wgpu::AddressMode::ClampToEdge,
+            address_mode_w: wgpu::AddressMode::ClampToEdge,
+            mag_filter: wgpu::FilterMode::Linear,
+            min_filter: wgpu::FilterMode::Nearest,
+            mipmap_filter: wgpu::FilterMode::Nearest,
+            ..Default::default()
+        });
+        Ok(Self {
+            texture,
+            texture_label,
+            view,
+            sampler,
+            sampler_label,
+        })
+    }
+
     #[allow(dead_code)]
     pub fn from_bytes(
         device: &wgpu::Device,
@@ -191,3 +308,20 @@ impl Texture {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn texture_array_layers_are_padded_rather_than_stretched() {
+        let imgs = [
+            image::DynamicImage::new_rgba8(64, 8),
+            image::DynamicImage::new_rgba8(8, 32),
+        ];
+        let layer_size = Texture::array_layer_size(&imgs);
+        assert_eq!(layer_size, (64, 32));
+        assert_eq!(Texture::array_uv_scale(&imgs[0], layer_size), [1.0, 0.25]);
+        assert_eq!(Texture::array_uv_scale(&imgs[1], layer_size), [0.125, 1.0]);
+    }
+}
@@ -0,0 +1,273 @@
+//! Export of the rendered frames as a sequence of numbered image files (PNG or EXR), as an
+//! alternative to encoding them into a video with ffmpeg.  The frames are encoded and written by a
+//! pool of worker threads, since encoding a PNG file takes much longer than rendering a frame.
+
+use crate::configuration::{ImageSequenceConfig, ImageSequenceFormat};
+use anyhow::{bail, Context};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{Receiver, SyncSender};
+use std::sync::{Arc, Mutex};
+
+/// Format of the off-screen texture that frames need to be rendered to for the given image format.
+/// Frames for 16-bit PNG and EXR files are rendered with 16-bit floating point colors (in linear
+/// color space), so that the extra precision isn't lost.
+pub fn texture_format(format: ImageSequenceFormat) -> wgpu::TextureFormat {
+    match format {
+        ImageSequenceFormat::Png8 => wgpu::TextureFormat::Bgra8UnormSrgb,
+        ImageSequenceFormat::Png16 | ImageSequenceFormat::Exr => wgpu::TextureFormat::Rgba16Float,
+    }
+}
+
+/// Checks that the file name pattern contains a place for the frame number, and creates the
+/// directory the files will be written to (if needed).
+pub fn prepare_output(file_pattern: &str) -> anyhow::Result<()> {
+    if !file_pattern.contains('#') {
+        bail!(
+            "The image sequence file name \"{file_pattern}\" needs a run of # characters to be \
+            replaced by the frame number (e.g. \"frames/petals_######.png\")"
+        );
+    }
+    match std::path::Path::new(file_pattern).parent() {
+        Some(directory) if !directory.as_os_str().is_empty() => std::fs::create_dir_all(directory)
+            .with_context(|| format!("Failed to create directory {}", directory.display())),
+        _ => Ok(()),
+    }
+}
+
+/// Returns the name of the file for the given frame.  The first run of # characters in the pattern
+/// is replaced by the frame number, zero-padded to the length of the run.
+fn frame_file_name(file_pattern: &str, frame_number: u64) -> String {
+    let start = file_pattern.find('#').unwrap_or(file_pattern.len());
+    let width = file_pattern[start..]
+        .find(|c| c != '#')
+        .unwrap_or(file_pattern.len() - start);
+    format!(
+        "{}{frame_number:0width$}{}",
+        &file_pattern[..start],
+        &file_pattern[start + width..],
+    )
+}
+
+/// Receives frames (in the order they were rendered) and hands them out to a pool of worker threads
+/// that write them to image files, numbered from `first_frame_number`.  If `encode_srgb` is false,
+/// the values of 16-bit PNG files are written as they are (for data such as depths) rather than sRGB
+/// encoded.  Stops early if writing any of the images fails.
+pub fn image_sequence_thread_fn(
+    receiver: Receiver<Vec<u8>>,
+    file_pattern: String,
+    config: ImageSequenceConfig,
+    width: u32,
+    height: u32,
+    first_frame_number: u64,
+    encode_srgb: bool,
+) -> anyhow::Result<()> {
+    let n_workers = match config.n_workers {
+        0 => std::thread::available_parallelism().map_or(1, |n| n.get()),
+        n_workers => n_workers,
+    };
+    log::debug!("Image sequence thread starting with {n_workers} workers.");
+    // Bound the queue so that frames don't pile up in memory when the workers fall behind.
+    let (job_tx, job_rx): (SyncSender<(u64, Vec<u8>)>, _) =
+        std::sync::mpsc::sync_channel(n_workers);
+    let job_rx = Arc::new(Mutex::new(job_rx));
+    let failed = Arc::new(AtomicBool::new(false));
+    let workers = (0..n_workers)
+        .map(|_| {
+            let job_rx = job_rx.clone();
+            let failed = failed.clone();
+            let file_pattern = file_pattern.clone();
+            std::thread::spawn(move || -> anyhow::Result<()> {
+                loop {
+                    let job = job_rx.lock().unwrap().recv();
+                    let Ok((frame_number, frame)) = job else {
+                        return Ok(());
+                    };
+                    let file_name = frame_file_name(&file_pattern, frame_number);
+                    let result = save_frame(
+                        &frame,
+                        config.format,
+                        width,
+                        height,
+                        encode_srgb,
+                        &file_name,
+                    );
+                    if let Err(error) = result {
+                        failed.store(true, Ordering::Relaxed);
+                        return Err(error);
+                    }
+                }
+            })
+        })
+        .collect::<Vec<_>>();
+
+    for (frame_number, frame) in (first_frame_number..).zip(receiver.iter()) {
+        if failed.load(Ordering::Relaxed) || job_tx.send((frame_number, frame)).is_err() {
+            break;
+        }
+        if frame_number % 100 == 0 {
+            log::debug!("Writing image sequence frame {frame_number}");
+        }
+    }
+    // Let the workers finish writing the frames that are still queued, and report the first error.
+    drop(job_tx);
+    let mut result = Ok(());
+    for worker in workers {
+        let worker_result = worker
+            .join()
+            .unwrap_or_else(|_| Err(anyhow::anyhow!("Image writing thread panicked")));
+        if result.is_ok() {
+            result = worker_result;
+        }
+    }
+    log::debug!("Image sequence thread finished.");
+    result
+}
+
+/// Converts a frame of pixel data (in the texture format used for the image format) to an image
+/// and saves it.
+fn save_frame(
+    frame: &[u8],
+    format: ImageSequenceFormat,
+    width: u32,
+    height: u32,
+    encode_srgb: bool,
+    file_name: &str,
+) -> anyhow::Result<()> {
+    let result = match format {
+        ImageSequenceFormat::Png8 => bgra8_to_rgba8(frame, width, height)
+            .save_with_format(file_name, image::ImageFormat::Png),
+        ImageSequenceFormat::Png16 => rgba16f_to_rgba16(frame, width, height, encode_srgb)
+            .save_with_format(file_name, image::ImageFormat::Png),
+        ImageSequenceFormat::Exr => rgba16f_to_rgba32f(frame, width, height)
+            .save_with_format(file_name, image::ImageFormat::OpenExr),
+    };
+    result.with_context(|| format!("Failed to write {file_name}"))
+}
+
+fn bgra8_to_rgba8(frame: &[u8], width: u32, height: u32) -> image::RgbaImage {
+    let pixels = frame
+        .chunks_exact(4)
+        .flat_map(|bgra| [bgra[2], bgra[1], bgra[0], bgra[3]])
+        .collect();
+    image::RgbaImage::from_raw(width, height, pixels).unwrap()
+}
+
+/// Returns the channel values of a frame rendered to an Rgba16Float texture.
+fn rgba16f_values(frame: &[u8]) -> impl Iterator<Item = f32> + '_ {
+    frame
+        .chunks_exact(2)
+        .map(|bytes| half::f16::from_le_bytes([bytes[0], bytes[1]]).to_f32())
+}
+
+/// Converts linear colors to 16-bit sRGB (the color space PNG files are normally displayed in), or
+/// just to 16-bit values if `encode_srgb` is false.
+fn rgba16f_to_rgba16(
+    frame: &[u8],
+    width: u32,
+    height: u32,
+    encode_srgb: bool,
+) -> image::ImageBuffer<image::Rgba<u16>, Vec<u16>> {
+    let pixels = rgba16f_values(frame)
+        .enumerate()
+        .map(|(idx, value)| {
+            // The alpha channel (every 4th value) is not gamma encoded.
+            let value = if idx % 4 == 3 || !encode_srgb {
+                value
+            } else {
+                linear_to_srgb(value)
+            };
+            (value.clamp(0.0, 1.0) * 65535.0).round() as u16
+        })
+        .collect();
+    image::ImageBuffer::from_raw(width, height, pixels).unwrap()
+}
+
+/// EXR files store linear colors, so the values are written as they were rendered.
+fn rgba16f_to_rgba32f(frame: &[u8], width: u32, height: u32) -> image::Rgba32FImage {
+    image::Rgba32FImage::from_raw(width, height, rgba16f_values(frame).collect()).unwrap()
+}
+
+/// The sRGB transfer function.
+pub fn linear_to_srgb(value: f32) -> f32 {
+    if value <= 0.0031308 {
+        12.92 * value
+    } else {
+        1.055 * value.powf(1.0 / 2.4) - 0.055
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{export_frames, gpu_test, headless_test_config};
+
+    #[test]
+    fn frame_numbers_replace_first_run_of_hashes() {
+        assert_eq!(
+            frame_file_name("frames/petals_####.png", 42),
+            "frames/petals_0042.png"
+        );
+        assert_eq!(frame_file_name("#.exr", 12345), "12345.exr");
+        assert_eq!(frame_file_name("a##b#.png", 7), "a07b#.png");
+        assert!(prepare_output("petals.png").is_err());
+    }
+
+    #[test]
+    fn converts_rendered_pixels() {
+        let rgba8 = bgra8_to_rgba8(&[1, 2, 3, 4, 5, 6, 7, 8], 2, 1);
+        assert_eq!(rgba8.into_raw(), [3, 2, 1, 4, 7, 6, 5, 8]);
+
+        let frame = [0.0f32, 0.5, 1.0, 0.5]
+            .iter()
+            .flat_map(|&value| half::f16::from_f32(value).to_le_bytes())
+            .collect::<Vec<_>>();
+        let rgba16 = rgba16f_to_rgba16(&frame, 1, 1, true).into_raw();
+        // Linear 0.5 is about 0.735 in sRGB, but alpha stays linear.
+        assert_eq!(rgba16[0], 0);
+        assert!((48_000..48_400).contains(&rgba16[1]), "{}", rgba16[1]);
+        assert_eq!(rgba16[2], 65535);
+        assert_eq!(rgba16[3], 32768);
+        assert_eq!(
+            rgba16f_to_rgba16(&frame, 1, 1, false).into_raw(),
+            [0, 32768, 65535, 32768]
+        );
+        assert_eq!(
+            rgba16f_to_rgba32f(&frame, 1, 1).into_raw(),
+            [0.0, 0.5, 1.0, 0.5]
+        );
+    }
+
+    #[test]
+    fn exports_image_sequences() {
+        use crate::configuration::VideoExportBackend;
+        let Some((_gpu_lock, test_directory)) = gpu_test("image_sequence") else {
+            return;
+        };
+        for (format, extension) in [
+            (ImageSequenceFormat::Png8, "png"),
+            (ImageSequenceFormat::Png16, "png"),
+            (ImageSequenceFormat::Exr, "exr"),
+        ] {
+            let directory = test_directory.join(format!("{format:?}"));
+            let mut config = headless_test_config("image_sequence", Some(5));
+            config.video_export_backend = VideoExportBackend::ImageSequence;
+            config.image_sequence.format = format;
+            config.video_export_width = 40;
+            config.video_export_height = 30;
+            let pattern = directory.join(format!("frame_###.{extension}"));
+            config.video_export_file = pattern.to_string_lossy().into_owned();
+            export_frames(config, 3);
+            for frame_number in 0..3 {
+                let file = directory.join(format!("frame_{frame_number:03}.{extension}"));
+                let frame = image::open(&file).unwrap();
+                assert_eq!((frame.width(), frame.height()), (40, 30));
+                match format {
+                    ImageSequenceFormat::Png8 => assert!(frame.as_rgba8().is_some()),
+                    ImageSequenceFormat::Png16 => assert!(frame.as_rgba16().is_some()),
+                    ImageSequenceFormat::Exr => assert!(frame.as_rgba32f().is_some()),
+                }
+            }
+            assert!(!directory.join(format!("frame_003.{extension}")).exists());
+        }
+    }
+}
@@ -1,8 +1,25 @@
 //mod ecs;
+mod alpha;
+mod animated_image;
+mod audio_reactive;
+mod auxiliary_passes;
+mod checkpoint;
+mod cli;
 mod configuration;
 mod graphics;
+mod image_sequence;
 mod input;
+mod raw_stream;
+mod render;
+mod scale_distribution;
+mod sidecar;
 mod state;
+#[cfg(test)]
+mod test_support;
+mod tiling;
+mod tumbling;
+mod variant_selection;
+mod video_encoder;
 
 use winit::{
     event::{ElementState, Event, KeyboardInput, VirtualKeyCode, WindowEvent},
@@ -10,50 +27,125 @@ use winit::{
     window::WindowBuilder,
 };
 
+const WINDOW_TITLE: &str = "falling_petals";
+
+/// Minimum time between updates of the video export progress shown in the window title.
+const TITLE_UPDATE_INTERVAL: std::time::Duration = std::time::Duration::from_secs(1);
+
 pub fn run() {
+    // Parse command-line options
+    let cli_options = match cli::CliOptions::parse(std::env::args().skip(1)) {
+        Ok(cli_options) => cli_options,
+        Err(error) => {
+            eprintln!("Error parsing command-line arguments: {error}");
+            eprintln!("{}", cli::USAGE);
+            return;
+        }
+    };
+    if cli_options.help {
+        println!("{}", cli::USAGE);
+        return;
+    }
+    if let Some(reproduce_options) = &cli_options.reproduce {
+        // Everything comes from the sidecar, so config.toml isn't needed.
+        env_logger::init();
+        if let Err(error) = render::run_reproduce(reproduce_options) {
+            eprintln!("Error reproducing render: {error:#}");
+            std::process::exit(1);
+        }
+        return;
+    }
+    if let Some(checkpoint_directory) = &cli_options.resume {
+        // The checkpoint holds the config of the render being resumed.
+        env_logger::init();
+        if let Err(error) = render::run_resume(checkpoint_directory) {
+            eprintln!("Error resuming render: {error:#}");
+            std::process::exit(1);
+        }
+        return;
+    }
+
     // Load or generate config file
     let config_path = std::path::Path::new("config.toml");
     if !config_path.exists() {
-        println!("No config.toml file found in current directory.");
-        println!("Generating a default config.toml file and exiting...");
+        eprintln!("No config.toml file found in current directory.");
+        eprintln!("Generating a default config.toml file and exiting...");
         if let Err(error) = std::fs::write(config_path, configuration::DEFAULT_CONFIG_STR) {
-            println!("Error writing default config.toml file: {error}");
+            eprintln!("Error writing default config.toml file: {error}");
             return;
         }
-        println!("Default config.toml generated.  Edit it if desired and run the program again to use it.");
+        eprintln!("Default config.toml generated.  Edit it if desired and run the program again to use it.");
         return;
     }
     let config_str = match std::fs::read_to_string(config_path) {
         Ok(file_contents) => file_contents,
         Err(error) => {
-            println!("Error reading config.toml: {error}");
+            eprintln!("Error reading config.toml: {error}");
             return;
         }
     };
-    let config: configuration::FallingPetalsConfig = match toml::from_str(&config_str) {
+    let mut config: configuration::FallingPetalsConfig = match toml::from_str(&config_str) {
         Ok(parsed_config) => parsed_config,
         Err(error) => {
-            println!("Error parsing config.toml: {error}");
-            println!("Rename or remove config.toml and rerun to generate a new config.toml with default settings.");
+            eprintln!("Error parsing config.toml: {error}");
+            eprintln!("Rename or remove config.toml and rerun to generate a new config.toml with default settings.");
             return;
         }
     };
+    if let Err(error) = config.validate() {
+        eprintln!("Invalid config.toml: {error}");
+        return;
+    }
+
+    if let Some(seed) = cli_options.seed {
+        config.random_seed = Some(seed);
+    }
 
-    // Window setup
     env_logger::init();
-    let event_loop = EventLoop::new();
-    let window = WindowBuilder::new().build(&event_loop).unwrap();
-    let video_export_config = crate::configuration::VideoExportConfig::new(
+    if let Some(render_options) = &cli_options.render {
+        if let Err(error) = render::run_render(config, render_options, Vec::new()) {
+            eprintln!("Error rendering video: {error:#}");
+            std::process::exit(1);
+        }
+        return;
+    }
+    let mut video_export_configs = crate::configuration::VideoExportConfig::all_from_config(
+        &config,
         config.enable_ffmpeg_video_export,
-        config.video_export_file.clone(),
-        config.video_export_width,
-        config.video_export_height,
-        config.video_export_fps,
-        wgpu::TextureFormat::Bgra8UnormSrgb,
     );
-    let mut simulation_state = state::FallingPetalsState::new(&window, config, video_export_config);
+    for video_export_config in &mut video_export_configs {
+        video_export_config.n_frames = cli_options.frames;
+    }
+    if cli_options.headless {
+        // CliOptions::parse() guarantees that a frame count is given in headless mode.
+        if let Err(error) = run_headless(
+            config,
+            video_export_configs,
+            cli_options.frames.unwrap_or(0),
+        ) {
+            eprintln!("Error rendering headless: {error:#}");
+            std::process::exit(1);
+        }
+        return;
+    }
+
+    // Window setup
+    let event_loop = EventLoop::new();
+    let window = WindowBuilder::new()
+        .with_title(WINDOW_TITLE)
+        .build(&event_loop)
+        .unwrap();
+    let mut simulation_state =
+        match state::FallingPetalsState::new(Some(&window), config, video_export_configs) {
+            Ok(simulation_state) => simulation_state,
+            Err(error) => {
+                eprintln!("Error setting up graphics: {error:#}");
+                return;
+            }
+        };
 
     // Event loop
+    let mut last_title_update_time = std::time::Instant::now();
     event_loop.run(move |event, _, control_flow| {
         match event {
             Event::DeviceEvent { ref event, .. } => {
@@ -80,6 +172,15 @@ pub fn run() {
                 }
             }
             Event::MainEventsCleared => {
+                // Exit once the requested number of frames has been rendered (if a frame count was
+                // given on the command line).
+                if let Some(frames) = cli_options.frames {
+                    if simulation_state.frame_idx >= frames {
+                        *control_flow = ControlFlow::Exit;
+                        return;
+                    }
+                }
+
                 // Application update code goes here
                 // Update buffers with any new data from the game state.
                 simulation_state.update();
@@ -105,6 +206,15 @@ pub fn run() {
                     }
                 }
 
+                // Show the progress of any video export in the title bar.
+                if last_title_update_time.elapsed() >= TITLE_UPDATE_INTERVAL {
+                    last_title_update_time = std::time::Instant::now();
+                    if let Some(progress) = simulation_state.graphics_state.video_export_progress()
+                    {
+                        window.set_title(&format!("{WINDOW_TITLE} - {progress}"));
+                    }
+                }
+
                 // Continually request redraws by calling request_redraw() in response to this
                 // event.  Or could just render here instead for things like games that are
                 // continuously redrawing (as mentioned by the documentation).
@@ -125,3 +235,143 @@ pub fn run() {
         }
     });
 }
+
+/// Runs the simulation without a window for `n_frames` frames, rendering only to the off-screen
+/// video export target (and encoding the frames to video if video export is enabled).
+fn run_headless(
+    config: configuration::FallingPetalsConfig,
+    video_export_configs: Vec<configuration::VideoExportConfig>,
+    n_frames: u64,
+) -> anyhow::Result<()> {
+    let mut simulation_state = state::FallingPetalsState::new(None, config, video_export_configs)?;
+    log::info!("Rendering {n_frames} frames headless");
+    for frame in 0..n_frames {
+        simulation_state.update();
+        simulation_state
+            .render()
+            .map_err(|error| anyhow::anyhow!("Error rendering frame {frame}: {error}"))?;
+        let export_failed = simulation_state
+            .graphics_state
+            .video_export_progress()
+            .is_some_and(|progress| progress.failed);
+        if export_failed {
+            // There's no point in rendering the rest of the frames (the error is returned below).
+            break;
+        }
+    }
+    // Closes the pipe to ffmpeg, waits for it to finish writing the video, and writes the sidecar.
+    simulation_state.finish_video_export()?;
+    log::info!("Finished rendering headless");
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{gpu_test, headless_test_state, headless_test_state_with_size};
+
+    #[test]
+    fn headless_render_runs_without_a_window() {
+        let Some((_gpu_lock, _)) = gpu_test("headless") else {
+            return;
+        };
+        let mut simulation_state = headless_test_state("headless", None);
+        for _ in 0..3 {
+            simulation_state.update();
+            simulation_state.render().unwrap();
+        }
+        assert_eq!(simulation_state.frame_idx, 3);
+        assert_eq!(
+            simulation_state.graphics_state.get_aspect_ratio(),
+            64.0 / 48.0
+        );
+    }
+
+    #[test]
+    fn seeded_simulation_is_reproducible_from_any_start_frame() {
+        let Some((_gpu_lock, _)) = gpu_test("reproducible") else {
+            return;
+        };
+        // Only one simulation exists at a time, since the GL backend doesn't cope with dropping
+        // one of several devices.
+        let poses = |simulation_state: state::FallingPetalsState| {
+            simulation_state
+                .petal_states
+                .iter()
+                .map(|petal_state| petal_state.pose)
+                .collect::<Vec<_>>()
+        };
+        let mut rendered = headless_test_state("reproducible", Some(42));
+        for _ in 0..30 {
+            rendered.update();
+        }
+        let rendered_poses = poses(rendered);
+        let mut skipped = headless_test_state("reproducible", Some(42));
+        skipped.skip_frames(29);
+        skipped.update();
+        assert_eq!(skipped.frame_idx, 30);
+        assert_eq!(poses(skipped), rendered_poses);
+    }
+
+    #[test]
+    fn pipelined_video_readback_keeps_frame_order() {
+        let Some((_gpu_lock, _)) = gpu_test("readback") else {
+            return;
+        };
+        const N_FRAMES: usize = 8;
+        // Collects the exported frames in place of the video coding thread.  Flushing after every
+        // frame reads each frame back before the next one is rendered (i.e. without pipelining).
+        let export_frames = |flush_every_frame: bool| {
+            let mut simulation_state = headless_test_state("readback", Some(7));
+            let (tx, rx) = std::sync::mpsc::sync_channel(N_FRAMES);
+            let graphics_state = &mut simulation_state.graphics_state;
+            graphics_state.video_export_states[0].video_thread_tx = Some(tx);
+            for _ in 0..N_FRAMES {
+                simulation_state.update();
+                simulation_state.render().unwrap();
+                if flush_every_frame {
+                    simulation_state.graphics_state.flush_video_frames();
+                }
+            }
+            simulation_state
+                .graphics_state
+                .finish_video_export()
+                .unwrap();
+            rx.try_iter().collect::<Vec<_>>()
+        };
+        let pipelined_frames = export_frames(false);
+        assert_eq!(pipelined_frames.len(), N_FRAMES);
+        assert_ne!(pipelined_frames[0], pipelined_frames[N_FRAMES - 1]);
+        assert!(pipelined_frames == export_frames(true));
+    }
+
+    #[test]
+    fn video_export_supports_widths_that_need_row_padding() {
+        let Some((_gpu_lock, _)) = gpu_test("row_padding") else {
+            return;
+        };
+        for (width, height) in [(65, 9), (33, 17), (1000, 3)] {
+            let mut simulation_state =
+                headless_test_state_with_size("row_padding", Some(3), width, height);
+            let (tx, rx) = std::sync::mpsc::sync_channel(2);
+            let graphics_state = &mut simulation_state.graphics_state;
+            graphics_state.video_export_states[0].video_thread_tx = Some(tx);
+            for _ in 0..2 {
+                simulation_state.update();
+                simulation_state.render().unwrap();
+            }
+            simulation_state
+                .graphics_state
+                .finish_video_export()
+                .unwrap();
+            let frames = rx.try_iter().collect::<Vec<_>>();
+            assert_eq!(frames.len(), 2);
+            for frame in frames {
+                assert_eq!(frame.len() as u32, 4 * width * height);
+                // Everything is drawn over an opaque background, so any padding left in the frame
+                // would show up as transparent pixels.
+                assert!(frame.chunks(4).all(|pixel| pixel[3] == 255));
+            }
+        }
+    }
+}
@@ -0,0 +1,166 @@
+//! Streaming of the rendered frames as raw pixels to stdout or a named pipe (FIFO), so that they can
+//! be piped into any encoder or processing tool (e.g. a VJ pipeline) instead of the fixed ffmpeg
+//! command of the ffmpeg backend.  Each frame is written as width * height pixels of 4 bytes (BGRA
+//! or RGBA, with 8-bit sRGB values), row by row from the top.  Optionally, each frame is preceded by
+//! a 16-byte header holding its frame number and its timestamp in microseconds, both as
+//! little-endian u64 values.
+
+use crate::configuration::{RawPixelFormat, RawStreamConfig};
+use anyhow::Context;
+use std::io::Write;
+use std::sync::mpsc::Receiver;
+
+/// Output file name that stands for stdout.
+pub const STDOUT: &str = "-";
+/// Size in bytes of the header before each frame (if enabled).
+pub const FRAME_HEADER_SIZE: usize = 16;
+
+/// Returns whether the frames of the given output file are written to stdout.
+pub fn is_stdout(file: &str) -> bool {
+    file == STDOUT
+}
+
+/// Returns the header of the given frame: its frame number and the time it is shown at.
+fn frame_header(frame_number: u64, frame_rate: u32) -> [u8; FRAME_HEADER_SIZE] {
+    let timestamp = frame_number * 1_000_000 / u64::from(frame_rate);
+    let mut header = [0; FRAME_HEADER_SIZE];
+    header[..8].copy_from_slice(&frame_number.to_le_bytes());
+    header[8..].copy_from_slice(&timestamp.to_le_bytes());
+    header
+}
+
+/// Writes a frame of BGRA pixels (preceded by its header, if enabled) in the configured pixel
+/// format.
+fn write_frame(
+    output: &mut impl Write,
+    mut frame: Vec<u8>,
+    frame_number: u64,
+    config: RawStreamConfig,
+    frame_rate: u32,
+) -> std::io::Result<()> {
+    if config.frame_header {
+        output.write_all(&frame_header(frame_number, frame_rate))?;
+    }
+    if config.pixel_format == RawPixelFormat::Rgba {
+        for pixel in frame.chunks_exact_mut(4) {
+            pixel.swap(0, 2);
+        }
+    }
+    output.write_all(&frame)?;
+    // Send each frame off right away, rather than leaving part of it in a buffer until the next one.
+    output.flush()
+}
+
+/// Receives frames (in the order they were rendered) and writes them to stdout, or to the given
+/// file or named pipe, numbered from `first_frame_number`.  Opening a named pipe waits until
+/// another program opens it for reading.  Stops early if writing fails (e.g. because the reading
+/// program exited).
+pub fn raw_stream_thread_fn(
+    receiver: Receiver<Vec<u8>>,
+    output_file: String,
+    config: RawStreamConfig,
+    first_frame_number: u64,
+    frame_rate: u32,
+) -> anyhow::Result<()> {
+    log::debug!("Raw stream thread starting.");
+    let mut output: Box<dyn Write> = if is_stdout(&output_file) {
+        Box::new(std::io::stdout().lock())
+    } else {
+        log::info!("Opening {output_file} (waits for a reader if it is a named pipe)");
+        Box::new(
+            std::fs::File::create(&output_file)
+                .with_context(|| format!("Failed to open {output_file}"))?,
+        )
+    };
+    for (frame_number, frame) in (first_frame_number..).zip(receiver.iter()) {
+        write_frame(&mut output, frame, frame_number, config, frame_rate)
+            .with_context(|| format!("Failed to write frame {frame_number} to {output_file}"))?;
+    }
+    log::debug!("Raw stream thread finished.");
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::configuration::{ExportTargetConfig, VideoExportBackend};
+    use crate::test_support::{export_frames, gpu_test, headless_test_config};
+
+    #[test]
+    fn frames_are_written_with_headers_and_swizzled() {
+        let frame = vec![1, 2, 3, 4, 5, 6, 7, 8];
+        let mut output = Vec::new();
+        let config = RawStreamConfig {
+            pixel_format: RawPixelFormat::Rgba,
+            frame_header: true,
+        };
+        write_frame(&mut output, frame.clone(), 90, config, 60).unwrap();
+        assert_eq!(output.len(), FRAME_HEADER_SIZE + frame.len());
+        assert_eq!(u64::from_le_bytes(output[..8].try_into().unwrap()), 90);
+        assert_eq!(
+            u64::from_le_bytes(output[8..16].try_into().unwrap()),
+            1_500_000
+        );
+        assert_eq!(output[16..], [3, 2, 1, 4, 7, 6, 5, 8]);
+
+        let mut output = Vec::new();
+        write_frame(&mut output, frame.clone(), 0, Default::default(), 60).unwrap();
+        assert_eq!(output, frame);
+    }
+
+    #[test]
+    fn streams_raw_frames_with_headers() {
+        let Some((_gpu_lock, directory)) = gpu_test("raw_stream") else {
+            return;
+        };
+        let mut config = headless_test_config("raw_stream", Some(5));
+        config.n_petals = 300;
+        config.video_export_fps = 50;
+        let (width, height) = (64, 48);
+        config.export_targets = [
+            ("bgra.raw", RawPixelFormat::Bgra, true),
+            ("rgba.raw", RawPixelFormat::Rgba, false),
+        ]
+        .iter()
+        .map(|&(file, pixel_format, frame_header)| ExportTargetConfig {
+            file: directory.join(file).to_string_lossy().into_owned(),
+            width,
+            height,
+            aspect_ratio: None,
+            backend: VideoExportBackend::RawStream,
+            encoder: Default::default(),
+            image_sequence: Default::default(),
+            animated_image: Default::default(),
+            raw_stream: RawStreamConfig {
+                pixel_format,
+                frame_header,
+            },
+        })
+        .collect();
+        config.validate().unwrap();
+        export_frames(config, 3);
+
+        let frame_size = width as usize * height as usize * 4;
+        let frame_stride = FRAME_HEADER_SIZE + frame_size;
+        let bgra = std::fs::read(directory.join("bgra.raw")).unwrap();
+        let rgba = std::fs::read(directory.join("rgba.raw")).unwrap();
+        assert_eq!((bgra.len(), rgba.len()), (3 * frame_stride, 3 * frame_size));
+        for frame_number in 0..3 {
+            let header = &bgra[frame_number * frame_stride..][..FRAME_HEADER_SIZE];
+            let read_u64 = |bytes: &[u8]| u64::from_le_bytes(bytes.try_into().unwrap());
+            assert_eq!(read_u64(&header[..8]), frame_number as u64);
+            assert_eq!(read_u64(&header[8..]), frame_number as u64 * 20_000);
+            let bgra_frame = &bgra[frame_number * frame_stride + FRAME_HEADER_SIZE..][..frame_size];
+            let rgba_frame = &rgba[frame_number * frame_size..][..frame_size];
+            assert!(bgra_frame.iter().any(|&value| value > 0));
+            for (bgra_pixel, rgba_pixel) in
+                bgra_frame.chunks_exact(4).zip(rgba_frame.chunks_exact(4))
+            {
+                assert_eq!(
+                    [bgra_pixel[2], bgra_pixel[1], bgra_pixel[0], bgra_pixel[3]],
+                    rgba_pixel
+                );
+            }
+        }
+    }
+}
@@ -0,0 +1,468 @@
+//! Offline rendering of an exact number of frames to a video file.  Unlike exporting video from the
+//! live window, this runs without a window and without any frame rate limit (so as fast as the GPU
+//! and ffmpeg allow), shows its progress, and exits once ffmpeg has finished writing the video (or
+//! all the images of an image sequence have been written).  Messages go to stderr, so that stdout
+//! is left for raw frames streamed to it.
+
+use crate::checkpoint::{self, Checkpoint};
+use crate::cli::{RenderLength, RenderOptions, ReproduceOptions};
+use crate::configuration::{FallingPetalsConfig, VideoExportBackend, VideoExportConfig};
+use crate::sidecar::{CameraKeyframe, RenderSidecar};
+use crate::state::FallingPetalsState;
+use anyhow::Context;
+use std::io::Write;
+use std::time::{Duration, Instant};
+
+/// Minimum time between progress updates.
+const PROGRESS_INTERVAL: Duration = Duration::from_millis(500);
+
+/// Renders an earlier export again from the config, seed, frame range, and camera path recorded in
+/// its sidecar.
+pub fn run_reproduce(options: &ReproduceOptions) -> anyhow::Result<()> {
+    let render_sidecar = RenderSidecar::load(&options.sidecar)?;
+    render_sidecar
+        .config
+        .validate()
+        .with_context(|| format!("Invalid config in {}", options.sidecar))?;
+    for difference in render_sidecar.differences()? {
+        eprintln!("Warning: the render may not be identical: {difference}");
+    }
+    let render_options = RenderOptions {
+        length: RenderLength::Frames(render_sidecar.n_frames),
+        start_frame: render_sidecar.start_frame,
+        output: options.output.clone(),
+        checkpoint_interval: None,
+    };
+    run_render(
+        render_sidecar.config,
+        &render_options,
+        render_sidecar.camera_path,
+    )
+}
+
+/// Renders the video described by `options` and waits for it to be written.  The camera follows
+/// `camera_path` if it isn't empty.
+pub fn run_render(
+    mut config: FallingPetalsConfig,
+    options: &RenderOptions,
+    camera_path: Vec<CameraKeyframe>,
+) -> anyhow::Result<()> {
+    let n_frames = options.length.n_frames(config.video_export_fps);
+    // Set the output file in the config itself, so that the render sidecar records it.  A .gif or
+    // .webp file is written as an animated image, and "-" streams raw frames to stdout, so that
+    // these can be used without editing the config.
+    if let Some(output) = &options.output {
+        let backend = if crate::animated_image::AnimatedImageFormat::from_file(output).is_some() {
+            Some(VideoExportBackend::AnimatedImage)
+        } else if crate::raw_stream::is_stdout(output) {
+            Some(VideoExportBackend::RawStream)
+        } else {
+            None
+        };
+        match config.export_targets.as_mut_slice() {
+            [] => {
+                config.video_export_file = output.clone();
+                if let Some(backend) = backend {
+                    config.video_export_backend = backend;
+                }
+            }
+            [target] => {
+                target.file = output.clone();
+                if let Some(backend) = backend {
+                    target.backend = backend;
+                }
+            }
+            _ => anyhow::bail!(
+                "--output can't be used with several export_targets (set each target's file in \
+                the config file instead)"
+            ),
+        }
+        config
+            .validate()
+            .with_context(|| format!("Can't write {output}"))?;
+    }
+    let video_export_configs = video_export_configs(&config, options.start_frame, n_frames);
+    let Some(checkpoint_interval) = options.checkpoint_interval else {
+        let mut simulation_state =
+            FallingPetalsState::new(None, config, video_export_configs.clone())?;
+        simulation_state.camera_path = camera_path;
+        skip_to_start_frame(&mut simulation_state, options.start_frame);
+        return render_frames(
+            simulation_state,
+            options.start_frame,
+            n_frames,
+            &video_export_configs,
+            None,
+        );
+    };
+
+    // Write the videos in segments, saving a checkpoint at the start of each one.
+    let checkpoints = Checkpoints {
+        directory: checkpoint::checkpoint_directory(&video_export_configs[0].output_file),
+        segment_frames: ((checkpoint_interval * f64::from(config.video_export_fps)).round() as u64)
+            .max(1),
+    };
+    check_segment_joining(&video_export_configs)?;
+    std::fs::create_dir_all(&checkpoints.directory)
+        .with_context(|| format!("Failed to create directory {}", checkpoints.directory))?;
+    let segment_configs =
+        segment_configs(video_export_configs.clone(), &checkpoints.directory, 0, 0);
+    let mut simulation_state = FallingPetalsState::new(None, config, segment_configs)?;
+    // The sidecar describes the final videos rather than the segments.
+    simulation_state.render_sidecar = Some(RenderSidecar::new(
+        &simulation_state.config,
+        simulation_state.seed,
+        &video_export_configs,
+    )?);
+    simulation_state.camera_path = camera_path;
+    skip_to_start_frame(&mut simulation_state, options.start_frame);
+    eprintln!("Saving checkpoints in {}", checkpoints.directory);
+    render_frames(
+        simulation_state,
+        options.start_frame,
+        n_frames,
+        &video_export_configs,
+        Some(&checkpoints),
+    )
+}
+
+/// Resumes a render from the last checkpoint saved in the given checkpoint directory.
+pub fn run_resume(directory: &str) -> anyhow::Result<()> {
+    let checkpoint = Checkpoint::load(directory)?;
+    let config = checkpoint.render_sidecar.config.clone();
+    config
+        .validate()
+        .with_context(|| format!("Invalid config in the checkpoint in {directory}"))?;
+    for difference in checkpoint.render_sidecar.differences()? {
+        eprintln!("Warning: the render may not be identical: {difference}");
+    }
+    let frames_done = checkpoint.snapshot.frame_idx - checkpoint.start_frame;
+    if frames_done != checkpoint.n_segments * checkpoint.segment_frames {
+        anyhow::bail!("The checkpoint in {directory} doesn't match its segments");
+    }
+    let video_export_configs =
+        video_export_configs(&config, checkpoint.start_frame, checkpoint.n_frames);
+    check_segment_joining(&video_export_configs)?;
+    let segment_configs = segment_configs(
+        video_export_configs.clone(),
+        directory,
+        checkpoint.n_segments,
+        frames_done,
+    );
+    eprintln!(
+        "Resuming at frame {frames_done}/{} (segment {}/{})",
+        checkpoint.n_frames,
+        checkpoint.n_segments + 1,
+        checkpoint.total_segments(),
+    );
+    let mut simulation_state = FallingPetalsState::new(None, config, segment_configs)?;
+    simulation_state.restore(checkpoint.snapshot)?;
+    simulation_state.render_sidecar = Some(checkpoint.render_sidecar);
+    simulation_state.camera_path = checkpoint.camera_path;
+    let checkpoints = Checkpoints {
+        directory: directory.to_string(),
+        segment_frames: checkpoint.segment_frames,
+    };
+    render_frames(
+        simulation_state,
+        checkpoint.start_frame,
+        checkpoint.n_frames,
+        &video_export_configs,
+        Some(&checkpoints),
+    )
+}
+
+/// Where a render saves its checkpoints, and how often.
+struct Checkpoints {
+    directory: String,
+    /// Number of frames between checkpoints (the length of each segment of the videos).
+    segment_frames: u64,
+}
+
+/// Creates the export settings of each target for rendering `n_frames` frames, starting at
+/// simulation frame `start_frame`.
+fn video_export_configs(
+    config: &FallingPetalsConfig,
+    start_frame: u64,
+    n_frames: u64,
+) -> Vec<VideoExportConfig> {
+    let mut video_export_configs = VideoExportConfig::all_from_config(config, true);
+    for video_export_config in &mut video_export_configs {
+        video_export_config.n_frames = Some(n_frames);
+        // Keep the audio in sync with the frames that are skipped.
+        video_export_config.audio.offset += start_frame as f64 / f64::from(config.video_export_fps);
+    }
+    video_export_configs
+}
+
+/// Changes the export settings to write the given segment of the videos (without audio, which is
+/// added when the segments are joined).  Image sequences are numbered from `first_frame_number`.
+fn segment_configs(
+    mut video_export_configs: Vec<VideoExportConfig>,
+    directory: &str,
+    segment_idx: u64,
+    first_frame_number: u64,
+) -> Vec<VideoExportConfig> {
+    let segment_files = checkpoint::segment_files(directory, &video_export_configs, segment_idx);
+    for (video_export_config, segment_file) in video_export_configs.iter_mut().zip(segment_files) {
+        video_export_config.first_frame_number = first_frame_number;
+        if video_export_config.backend == VideoExportBackend::Ffmpeg {
+            video_export_config.output_file = segment_file;
+            video_export_config.audio.file = None;
+        }
+    }
+    video_export_configs
+}
+
+/// Checks that ffmpeg can add the audio track when the segments are joined, before any of them are
+/// rendered (the segments themselves are checked when the export starts).  Animated images and raw
+/// streams can't be joined, so they can't be rendered in segments.
+fn check_segment_joining(video_export_configs: &[VideoExportConfig]) -> anyhow::Result<()> {
+    for video_export_config in video_export_configs {
+        match video_export_config.backend {
+            VideoExportBackend::Ffmpeg => crate::video_encoder::check_ffmpeg_support(
+                &video_export_config.encoder,
+                &video_export_config.audio,
+            )?,
+            VideoExportBackend::ImageSequence => {}
+            VideoExportBackend::AnimatedImage => anyhow::bail!(
+                "Checkpoints can't be used with the animated image {}",
+                video_export_config.output_file
+            ),
+            VideoExportBackend::RawStream => anyhow::bail!(
+                "Checkpoints can't be used with the raw stream {}",
+                video_export_config.output_file
+            ),
+        }
+    }
+    Ok(())
+}
+
+/// Simulates the frames before the first one that is rendered.
+fn skip_to_start_frame(simulation_state: &mut FallingPetalsState, start_frame: u64) {
+    if start_frame > 0 {
+        eprintln!("Simulating up to frame {start_frame}...");
+        simulation_state.skip_frames(start_frame);
+    }
+}
+
+/// Renders the frames from the simulation's current frame up to the end of the render, and waits
+/// for them to be written.  With checkpoints, a new segment is started (and a checkpoint saved)
+/// every `segment_frames` frames, and the segments are joined into the final videos at the end.
+fn render_frames(
+    mut simulation_state: FallingPetalsState,
+    start_frame: u64,
+    n_frames: u64,
+    video_export_configs: &[VideoExportConfig],
+    checkpoints: Option<&Checkpoints>,
+) -> anyhow::Result<()> {
+    let output_files = video_export_configs
+        .iter()
+        .map(|video_export_config| video_export_config.output_file.as_str())
+        .collect::<Vec<_>>()
+        .join(", ");
+    let uses_ffmpeg = video_export_configs
+        .iter()
+        .any(|video_export_config| video_export_config.backend == VideoExportBackend::Ffmpeg);
+    let first_frame = simulation_state.frame_idx - start_frame;
+    eprintln!(
+        "Rendering {} frames to {output_files}",
+        n_frames - first_frame
+    );
+    let mut progress = Progress::new(n_frames, first_frame);
+    let result = (|| {
+        if let Some(checkpoints) = checkpoints {
+            save_checkpoint(&simulation_state, start_frame, n_frames, checkpoints)?;
+        }
+        for frame in first_frame..n_frames {
+            if let Some(checkpoints) = checkpoints.filter(|checkpoints| {
+                frame > first_frame && frame % checkpoints.segment_frames == 0
+            }) {
+                let segment_files = checkpoint::segment_files(
+                    &checkpoints.directory,
+                    video_export_configs,
+                    frame / checkpoints.segment_frames,
+                );
+                simulation_state
+                    .graphics_state
+                    .start_video_segment(&segment_files, frame)?;
+                save_checkpoint(&simulation_state, start_frame, n_frames, checkpoints)?;
+            }
+            simulation_state.update();
+            simulation_state
+                .render()
+                .map_err(|error| anyhow::anyhow!("Error rendering frame {frame}: {error}"))?;
+            if !simulation_state.graphics_state.is_exporting_video() {
+                break;
+            }
+            progress.report(frame + 1);
+        }
+        anyhow::Ok(())
+    })();
+    eprintln!();
+    if result.is_err() || !simulation_state.graphics_state.is_exporting_video() {
+        // The export failed part way through, so there's nothing left to wait for (and nothing
+        // to write a sidecar for).
+        simulation_state.render_sidecar = None;
+        result?;
+        return simulation_state.finish_video_export();
+    }
+    if uses_ffmpeg {
+        eprintln!("Waiting for ffmpeg to finish writing {output_files}...")
+    } else {
+        eprintln!("Waiting for the last frames to be written...")
+    }
+    let Some(checkpoints) = checkpoints else {
+        simulation_state.finish_video_export()?;
+        eprintln!(
+            "Finished rendering {n_frames} frames in {}",
+            format_duration(progress.start_time.elapsed())
+        );
+        return Ok(());
+    };
+
+    // The sidecar is written once the segments have been joined into the final videos.
+    let render_sidecar = simulation_state.render_sidecar.take();
+    simulation_state.finish_video_export()?;
+    let n_segments = n_frames.div_ceil(checkpoints.segment_frames);
+    for (target_idx, video_export_config) in video_export_configs.iter().enumerate() {
+        if video_export_config.backend != VideoExportBackend::Ffmpeg {
+            continue;
+        }
+        eprintln!(
+            "Joining the segments of {}...",
+            video_export_config.output_file
+        );
+        let segment_files = (0..n_segments)
+            .map(|segment_idx| {
+                checkpoint::segment_file(
+                    &checkpoints.directory,
+                    target_idx,
+                    segment_idx,
+                    &video_export_config.output_file,
+                )
+            })
+            .collect::<Vec<_>>();
+        crate::video_encoder::concatenate_segments(&segment_files, video_export_config)?;
+    }
+    if let Some(render_sidecar) = render_sidecar {
+        render_sidecar.write()?;
+    }
+    std::fs::remove_dir_all(&checkpoints.directory)
+        .with_context(|| format!("Failed to remove {}", checkpoints.directory))?;
+    eprintln!(
+        "Finished rendering {n_frames} frames in {}",
+        format_duration(progress.start_time.elapsed())
+    );
+    Ok(())
+}
+
+/// Saves a checkpoint at the simulation's current frame, which must be the start of a segment (with
+/// all the frames before it written).
+fn save_checkpoint(
+    simulation_state: &FallingPetalsState,
+    start_frame: u64,
+    n_frames: u64,
+    checkpoints: &Checkpoints,
+) -> anyhow::Result<()> {
+    let render_sidecar = simulation_state
+        .render_sidecar
+        .clone()
+        .context("The render has no sidecar to save in the checkpoint")?;
+    Checkpoint {
+        segment_frames: checkpoints.segment_frames,
+        start_frame,
+        n_frames,
+        n_segments: (simulation_state.frame_idx - start_frame) / checkpoints.segment_frames,
+        camera_path: simulation_state.camera_path.clone(),
+        render_sidecar,
+        snapshot: simulation_state.snapshot(),
+    }
+    .save(&checkpoints.directory)
+}
+
+/// Prints the progress of a render (on a single, repeatedly overwritten line of stderr).
+struct Progress {
+    n_frames: u64,
+    /// Number of frames that were already rendered before this run (when resuming).
+    first_frame: u64,
+    start_time: Instant,
+    last_report_time: Option<Instant>,
+}
+
+impl Progress {
+    fn new(n_frames: u64, first_frame: u64) -> Self {
+        Self {
+            n_frames,
+            first_frame,
+            start_time: Instant::now(),
+            last_report_time: None,
+        }
+    }
+
+    /// Reports that `frames_done` frames have been rendered.  Reports are rate limited, except for
+    /// the last frame.
+    fn report(&mut self, frames_done: u64) {
+        let now = Instant::now();
+        if frames_done < self.n_frames
+            && self
+                .last_report_time
+                .is_some_and(|time| now - time < PROGRESS_INTERVAL)
+        {
+            return;
+        }
+        self.last_report_time = Some(now);
+        let elapsed = now - self.start_time;
+        let frames_done_now = frames_done - self.first_frame;
+        eprint!(
+            "\rFrame {frames_done}/{} ({:.1}%), {:.1} fps, elapsed {}, ETA {}   ",
+            self.n_frames,
+            100.0 * frames_done as f64 / self.n_frames as f64,
+            frames_done_now as f64 / elapsed.as_secs_f64(),
+            format_duration(elapsed),
+            format_duration(estimate_remaining_time(
+                elapsed,
+                frames_done_now,
+                self.n_frames - self.first_frame
+            )),
+        );
+        let _ = std::io::stderr().flush();
+    }
+}
+
+/// Estimates how much longer the render will take, assuming the remaining frames render at the
+/// average rate so far.
+fn estimate_remaining_time(elapsed: Duration, frames_done: u64, n_frames: u64) -> Duration {
+    if frames_done == 0 {
+        return Duration::ZERO;
+    }
+    elapsed.mul_f64(n_frames.saturating_sub(frames_done) as f64 / frames_done as f64)
+}
+
+/// Formats a duration as hours:minutes:seconds.
+pub fn format_duration(duration: Duration) -> String {
+    let seconds = duration.as_secs();
+    format!(
+        "{}:{:02}:{:02}",
+        seconds / 3600,
+        seconds / 60 % 60,
+        seconds % 60
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn estimates_remaining_time_from_average_rate() {
+        let elapsed = Duration::from_secs(30);
+        assert_eq!(
+            estimate_remaining_time(elapsed, 600, 36000),
+            Duration::from_secs(1770)
+        );
+        assert_eq!(estimate_remaining_time(elapsed, 0, 36000), Duration::ZERO);
+        assert_eq!(format_duration(Duration::from_secs(1770)), "0:29:30");
+        assert_eq!(format_duration(Duration::from_secs(36610)), "10:10:10");
+    }
+}
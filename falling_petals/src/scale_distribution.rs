@@ -0,0 +1,261 @@
+//! Sampling of petal scale factors from the distributions that can be selected in the config file,
+//! plus a text histogram for checking what the resulting distribution actually looks like.
+//!
+//! All distributions are sampled through their quantile function (inverse CDF).  This makes it
+//! easy to keep every distribution within [min_scale, max_scale], and to correlate the scale with
+//! depth without changing the overall distribution of scales (see `sample_scale`).
+
+use crate::configuration::ScaleDistribution;
+use rand::prelude::*;
+use rand_distr::StandardNormal;
+
+/// Cumulative distribution function of the standard normal distribution.
+pub fn normal_cdf(x: f64) -> f64 {
+    0.5 * (1.0 + erf(x / std::f64::consts::SQRT_2))
+}
+
+/// Error function, using the approximation from Abramowitz and Stegun (formula 7.1.26), which has a
+/// maximum error of about 1.5e-7.
+fn erf(x: f64) -> f64 {
+    let t = 1.0 / (1.0 + 0.3275911 * x.abs());
+    let polynomial = t
+        * (0.254829592
+            + t * (-0.284496736 + t * (1.421413741 + t * (-1.453152027 + t * 1.061405429))));
+    let result = 1.0 - polynomial * (-x * x).exp();
+    if x >= 0.0 {
+        result
+    } else {
+        -result
+    }
+}
+
+/// Quantile function (inverse CDF) of the standard normal distribution, using Peter Acklam's
+/// rational approximation (relative error of about 1.15e-9).  `p` is clamped to (0, 1).
+pub fn normal_quantile(p: f64) -> f64 {
+    const A: [f64; 6] = [
+        -3.969683028665376e+01,
+        2.209460984245205e+02,
+        -2.759285104469687e+02,
+        1.38357751867269e+02,
+        -3.066479806614716e+01,
+        2.506628277459239e+00,
+    ];
+    const B: [f64; 5] = [
+        -5.447609879822406e+01,
+        1.615858368580409e+02,
+        -1.556989798598866e+02,
+        6.680131188771972e+01,
+        -1.328068155288572e+01,
+    ];
+    const C: [f64; 6] = [
+        -7.784894002430293e-03,
+        -3.223964580411365e-01,
+        -2.400758277161838e+00,
+        -2.549732539343734e+00,
+        4.374664141464968e+00,
+        2.938163982698783e+00,
+    ];
+    const D: [f64; 4] = [
+        7.784695709041462e-03,
+        3.224671290700398e-01,
+        2.445134137142996e+00,
+        3.754408661907416e+00,
+    ];
+    const P_LOW: f64 = 0.02425;
+    let p = p.clamp(1e-12, 1.0 - 1e-12);
+    if p < P_LOW {
+        let q = (-2.0 * p.ln()).sqrt();
+        (((((C[0] * q + C[1]) * q + C[2]) * q + C[3]) * q + C[4]) * q + C[5])
+            / ((((D[0] * q + D[1]) * q + D[2]) * q + D[3]) * q + 1.0)
+    } else if p <= 1.0 - P_LOW {
+        let q = p - 0.5;
+        let r = q * q;
+        (((((A[0] * r + A[1]) * r + A[2]) * r + A[3]) * r + A[4]) * r + A[5]) * q
+            / (((((B[0] * r + B[1]) * r + B[2]) * r + B[3]) * r + B[4]) * r + 1.0)
+    } else {
+        let q = (-2.0 * (1.0 - p).ln()).sqrt();
+        -(((((C[0] * q + C[1]) * q + C[2]) * q + C[3]) * q + C[4]) * q + C[5])
+            / ((((D[0] * q + D[1]) * q + D[2]) * q + D[3]) * q + 1.0)
+    }
+}
+
+/// Quantile of a normal distribution (with the given mean and standard deviation) truncated to the
+/// range [min, max].
+fn truncated_normal_quantile(u: f64, mean: f64, std_dev: f64, min: f64, max: f64) -> f64 {
+    let cdf_min = normal_cdf((min - mean) / std_dev);
+    let cdf_max = normal_cdf((max - mean) / std_dev);
+    (mean + std_dev * normal_quantile(cdf_min + u * (cdf_max - cdf_min))).clamp(min, max)
+}
+
+impl ScaleDistribution {
+    /// Returns the scale at quantile `u` (in [0, 1]) of this distribution, limited to the range
+    /// [min_scale, max_scale].
+    pub fn quantile(&self, u: f32, min_scale: f32, max_scale: f32) -> f32 {
+        let u = f64::from(u.clamp(0.0, 1.0));
+        let (min, max) = (f64::from(min_scale), f64::from(max_scale));
+        if max <= min {
+            return min_scale;
+        }
+        let scale = match self {
+            ScaleDistribution::Uniform => min + u * (max - min),
+            ScaleDistribution::LogNormal { median, sigma } => truncated_normal_quantile(
+                u,
+                f64::from(*median).ln(),
+                f64::from(*sigma),
+                min.ln(),
+                max.ln(),
+            )
+            .exp(),
+            ScaleDistribution::TruncatedNormal { mean, std_dev } => {
+                truncated_normal_quantile(u, f64::from(*mean), f64::from(*std_dev), min, max)
+            }
+            ScaleDistribution::Histogram { bin_weights } => {
+                let total: f64 = bin_weights.iter().map(|&weight| f64::from(weight)).sum();
+                let bin_width = (max - min) / bin_weights.len() as f64;
+                let mut cumulative = 0.0;
+                let mut scale = max;
+                for (bin_idx, &weight) in bin_weights.iter().enumerate() {
+                    let bin_fraction = f64::from(weight) / total;
+                    if bin_fraction > 0.0 && u <= cumulative + bin_fraction {
+                        let within_bin = (u - cumulative) / bin_fraction;
+                        scale = min + (bin_idx as f64 + within_bin) * bin_width;
+                        break;
+                    }
+                    cumulative += bin_fraction;
+                }
+                scale
+            }
+        };
+        (scale as f32).clamp(min_scale, max_scale)
+    }
+}
+
+/// Draws a random scale from `distribution`.  `depth_fraction` is the petal's position between the
+/// back (0) and the front (1) of the simulation volume, and `depth_correlation` (in [-1, 1]) sets
+/// how strongly the scale follows it.  The correlation is introduced with a Gaussian copula:  the
+/// quantile used is built from a standard normal that mixes the (normal-transformed) depth with
+/// independent noise.  Since the depths are uniformly distributed, that mixture is still a standard
+/// normal, so the overall distribution of scales is unchanged by the correlation.
+pub fn sample_scale<R: Rng + ?Sized>(
+    distribution: &ScaleDistribution,
+    min_scale: f32,
+    max_scale: f32,
+    depth_correlation: f32,
+    depth_fraction: f32,
+    rng: &mut R,
+) -> f32 {
+    let correlation = f64::from(depth_correlation.clamp(-1.0, 1.0));
+    let noise: f64 = rng.sample(StandardNormal);
+    let depth = normal_quantile(f64::from(depth_fraction));
+    let mixed = correlation * depth + (1.0 - correlation * correlation).sqrt() * noise;
+    distribution.quantile(normal_cdf(mixed) as f32, min_scale, max_scale)
+}
+
+/// Formats a text histogram of `scales` (split into `n_bins` equally wide bins between min_scale
+/// and max_scale), along with some summary statistics, for printing in the debug log.
+pub fn format_scale_histogram(
+    scales: &[f32],
+    min_scale: f32,
+    max_scale: f32,
+    n_bins: usize,
+) -> String {
+    const MAX_BAR_LENGTH: usize = 50;
+    if scales.is_empty() {
+        return String::from("  (no petals)\n");
+    }
+    let mut bin_counts = vec![0_usize; n_bins];
+    let bin_width = (max_scale - min_scale) / n_bins as f32;
+    for &scale in scales {
+        let bin_idx = if bin_width > 0.0 {
+            (((scale - min_scale) / bin_width) as usize).min(n_bins - 1)
+        } else {
+            0
+        };
+        bin_counts[bin_idx] += 1;
+    }
+    let max_count = *bin_counts.iter().max().unwrap();
+    let mean = scales.iter().sum::<f32>() / scales.len() as f32;
+    let variance = scales
+        .iter()
+        .map(|scale| (scale - mean).powi(2))
+        .sum::<f32>()
+        / scales.len() as f32;
+    let mut sorted_scales = scales.to_vec();
+    sorted_scales.sort_unstable_by(|a, b| a.partial_cmp(b).unwrap());
+    let mut text = format!(
+        "  n = {}, mean = {mean:.3}, std dev = {:.3}, median = {:.3}\n",
+        scales.len(),
+        variance.sqrt(),
+        sorted_scales[sorted_scales.len() / 2],
+    );
+    for (bin_idx, &count) in bin_counts.iter().enumerate() {
+        let bin_start = min_scale + bin_idx as f32 * bin_width;
+        text += &format!(
+            "  [{:7.3}, {:7.3}) {:6} {}\n",
+            bin_start,
+            bin_start + bin_width,
+            count,
+            "#".repeat(count * MAX_BAR_LENGTH / max_count),
+        );
+    }
+    text
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn normal_quantile_inverts_normal_cdf() {
+        for p in [0.001, 0.02, 0.2, 0.5, 0.8, 0.98, 0.999] {
+            assert!((normal_cdf(normal_quantile(p)) - p).abs() < 1e-6);
+        }
+    }
+
+    #[test]
+    fn quantiles_stay_within_range_and_increase() {
+        let distributions = [
+            ScaleDistribution::Uniform,
+            ScaleDistribution::LogNormal {
+                median: 1.2,
+                sigma: 0.4,
+            },
+            ScaleDistribution::TruncatedNormal {
+                mean: 1.5,
+                std_dev: 0.2,
+            },
+            ScaleDistribution::Histogram {
+                bin_weights: vec![1.0, 0.0, 3.0, 2.0],
+            },
+        ];
+        for distribution in distributions {
+            let mut previous = 0.0;
+            for step in 0..=100 {
+                let scale = distribution.quantile(step as f32 / 100.0, 1.0, 2.0);
+                assert!((1.0..=2.0).contains(&scale), "{distribution:?}: {scale}");
+                assert!(scale >= previous, "{distribution:?}: {scale} < {previous}");
+                previous = scale;
+            }
+        }
+    }
+
+    #[test]
+    fn histogram_skips_empty_bins() {
+        let distribution = ScaleDistribution::Histogram {
+            bin_weights: vec![1.0, 0.0, 1.0],
+        };
+        for step in 0..=100 {
+            let scale = distribution.quantile(step as f32 / 100.0, 0.0, 3.0);
+            assert!(scale <= 1.0 || scale >= 2.0, "{scale}");
+        }
+    }
+
+    #[test]
+    fn full_depth_correlation_orders_scales_by_depth() {
+        let mut rng = rand::thread_rng();
+        let back = sample_scale(&ScaleDistribution::Uniform, 1.0, 2.0, 1.0, 0.1, &mut rng);
+        let front = sample_scale(&ScaleDistribution::Uniform, 1.0, 2.0, 1.0, 0.9, &mut rng);
+        assert!((back - 1.1).abs() < 1e-3);
+        assert!((front - 1.9).abs() < 1e-3);
+    }
+}
@@ -0,0 +1,355 @@
+//! Reproducibility sidecars: a TOML file written next to every exported video (or image sequence)
+//! that records everything needed to render it again.  This is the full effective config (with the
+//! random seed filled in), the frame range, the camera path, the ffmpeg arguments, hashes of the
+//! texture files, and the program's version and git revision.  `falling_petals --reproduce
+//! <SIDECAR>` renders the same frames again, optionally after editing the config in the sidecar.
+
+use crate::configuration::{FallingPetalsConfig, VideoExportBackend, VideoExportConfig};
+use crate::graphics::camera::UprightPerspectiveCamera;
+use anyhow::Context;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+/// Appended to the output file name to get the name of its sidecar.
+const SIDECAR_SUFFIX: &str = ".render.toml";
+
+/// Everything needed to reproduce an export.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct RenderSidecar {
+    /// Version of the program that rendered the export.
+    pub program_version: String,
+    /// Git revision the program was built from (with "-dirty" appended if there were uncommitted
+    /// changes), if it was built from a git checkout.
+    #[serde(default)]
+    pub git_revision: Option<String>,
+    /// Seed of the random number generator.  The config's random_seed is also set to it.
+    pub seed: u64,
+    /// Frame of the simulation that the export starts at.
+    pub start_frame: u64,
+    /// Number of frames that were exported.
+    pub n_frames: u64,
+    /// The files that were written.
+    pub outputs: Vec<OutputRecord>,
+    /// The texture files the petals were cut from, and hashes of their contents.
+    pub textures: Vec<TextureRecord>,
+    /// Position and direction of the camera, whenever it changed while exporting.
+    pub camera_path: Vec<CameraKeyframe>,
+    /// The full effective config the export was rendered with.
+    pub config: FallingPetalsConfig,
+}
+
+/// One of the files (or image sequences) written by an export.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct OutputRecord {
+    pub file: String,
+    pub width: u32,
+    pub height: u32,
+    /// ffmpeg options for the audio input and the output (empty for image sequences).
+    pub ffmpeg_args: Vec<String>,
+}
+
+/// A texture file, and the SHA-256 hash of its contents (as a hex string).
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct TextureRecord {
+    pub file: String,
+    pub sha256: String,
+}
+
+/// Position and direction of the camera from the given simulation frame on.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+pub struct CameraKeyframe {
+    pub frame: u64,
+    pub location: [f32; 3],
+    /// Pan angle in degrees.
+    pub pan: f32,
+    /// Tilt angle in degrees.
+    pub tilt: f32,
+}
+
+impl CameraKeyframe {
+    fn new(frame: u64, camera: &UprightPerspectiveCamera) -> Self {
+        Self {
+            frame,
+            location: camera.location.into(),
+            pan: camera.pan_angle.0,
+            tilt: camera.tilt_angle.0,
+        }
+    }
+
+    fn same_pose(&self, other: &Self) -> bool {
+        self.location == other.location && self.pan == other.pan && self.tilt == other.tilt
+    }
+
+    /// Moves and turns the camera to this keyframe's pose.
+    pub fn apply_to(&self, camera: &mut UprightPerspectiveCamera) {
+        camera.location = self.location.into();
+        camera.pan_angle = cgmath::Deg(self.pan);
+        camera.tilt_angle = cgmath::Deg(self.tilt);
+    }
+}
+
+/// Returns the keyframe of the camera path that applies at the given frame (the last one that
+/// starts at or before it).
+pub fn camera_keyframe_at(camera_path: &[CameraKeyframe], frame: u64) -> Option<&CameraKeyframe> {
+    camera_path
+        .iter()
+        .take_while(|keyframe| keyframe.frame <= frame)
+        .last()
+}
+
+impl RenderSidecar {
+    /// Starts the record of an export to the given targets, hashing the texture files.  The frame
+    /// range and camera path are filled in while the frames are rendered.
+    pub fn new(
+        config: &FallingPetalsConfig,
+        seed: u64,
+        video_export_configs: &[VideoExportConfig],
+    ) -> anyhow::Result<Self> {
+        let mut config = config.clone();
+        config.random_seed = Some(seed);
+        let outputs = video_export_configs
+            .iter()
+            .map(|video_export_config| OutputRecord {
+                file: video_export_config.output_file.clone(),
+                width: video_export_config.width,
+                height: video_export_config.height,
+                ffmpeg_args: match video_export_config.backend {
+                    VideoExportBackend::Ffmpeg => {
+                        let (audio_input_args, output_args) =
+                            crate::video_encoder::ffmpeg_export_args(video_export_config);
+                        [audio_input_args, output_args].concat()
+                    }
+                    VideoExportBackend::ImageSequence
+                    | VideoExportBackend::AnimatedImage
+                    | VideoExportBackend::RawStream => Vec::new(),
+                },
+            })
+            .collect();
+        Ok(Self {
+            program_version: env!("CARGO_PKG_VERSION").to_string(),
+            git_revision: option_env!("FALLING_PETALS_GIT_REVISION").map(str::to_string),
+            seed,
+            start_frame: 0,
+            n_frames: 0,
+            outputs,
+            textures: hash_textures(&config)?,
+            camera_path: Vec::new(),
+            config,
+        })
+    }
+
+    /// Records that the given frame is being exported with the camera at its current pose.  A
+    /// keyframe is only added when the camera has moved since the last one.
+    pub fn record_frame(&mut self, frame: u64, camera: &UprightPerspectiveCamera) {
+        if self.n_frames == 0 {
+            self.start_frame = frame;
+        }
+        self.n_frames = frame + 1 - self.start_frame;
+        let keyframe = CameraKeyframe::new(frame, camera);
+        if !self
+            .camera_path
+            .last()
+            .is_some_and(|last| last.same_pose(&keyframe))
+        {
+            self.camera_path.push(keyframe);
+        }
+    }
+
+    /// Writes the sidecar next to each of the export's output files (except for frames streamed to
+    /// stdout, which have no file to put it next to).
+    pub fn write(&self) -> anyhow::Result<()> {
+        let contents = toml::to_string(self).context("Failed to serialize the render sidecar")?;
+        for output in self
+            .outputs
+            .iter()
+            .filter(|output| !crate::raw_stream::is_stdout(&output.file))
+        {
+            let path = sidecar_path(&output.file);
+            std::fs::write(&path, &contents)
+                .with_context(|| format!("Failed to write render sidecar {path}"))?;
+            log::info!("Wrote render sidecar {path}");
+        }
+        Ok(())
+    }
+
+    /// Reads a sidecar written by a previous export.
+    pub fn load(path: &str) -> anyhow::Result<Self> {
+        let contents = std::fs::read_to_string(path)
+            .with_context(|| format!("Failed to read render sidecar {path}"))?;
+        toml::from_str(&contents).with_context(|| format!("Failed to parse render sidecar {path}"))
+    }
+
+    /// Returns a description of every way the current program and texture files differ from the
+    /// ones that rendered the export (so the reproduced frames may not be identical).
+    pub fn differences(&self) -> anyhow::Result<Vec<String>> {
+        let mut differences = Vec::new();
+        let git_revision = option_env!("FALLING_PETALS_GIT_REVISION");
+        if self.git_revision.as_deref() != git_revision {
+            differences.push(format!(
+                "rendered by git revision {}, but this is {}",
+                self.git_revision.as_deref().unwrap_or("(unknown)"),
+                git_revision.unwrap_or("(unknown)"),
+            ));
+        } else if self.program_version != env!("CARGO_PKG_VERSION") {
+            differences.push(format!(
+                "rendered by version {}, but this is {}",
+                self.program_version,
+                env!("CARGO_PKG_VERSION"),
+            ));
+        }
+        let current_textures = hash_textures(&self.config)?;
+        for texture in &current_textures {
+            match self.textures.iter().find(|old| old.file == texture.file) {
+                Some(old) if old.sha256 != texture.sha256 => {
+                    differences.push(format!("{} has changed", texture.file))
+                }
+                Some(_) => {}
+                None => differences.push(format!("{} was not recorded", texture.file)),
+            }
+        }
+        Ok(differences)
+    }
+}
+
+/// Returns the name of the sidecar for the given output file (or image sequence pattern).
+pub fn sidecar_path(output_file: &str) -> String {
+    format!("{output_file}{SIDECAR_SUFFIX}")
+}
+
+/// Hashes each of the texture files used by the config (once, even if several species share it).
+fn hash_textures(config: &FallingPetalsConfig) -> anyhow::Result<Vec<TextureRecord>> {
+    let mut textures: Vec<TextureRecord> = Vec::new();
+    for species in config.resolve_species() {
+        for texture in &species.petal_textures {
+            if textures.iter().any(|record| record.file == texture.file) {
+                continue;
+            }
+            let contents = std::fs::read(&texture.file)
+                .with_context(|| format!("Failed to read texture {}", texture.file))?;
+            textures.push(TextureRecord {
+                file: texture.file.clone(),
+                sha256: format!("{:x}", Sha256::digest(&contents)),
+            });
+        }
+    }
+    Ok(textures)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{gpu_test, headless_test_config};
+    use crate::{cli, render, state};
+
+    #[test]
+    fn records_camera_path_and_frame_range() {
+        let config = FallingPetalsConfig::default();
+        let mut sidecar = RenderSidecar {
+            program_version: String::from("0.1.0"),
+            git_revision: None,
+            seed: 7,
+            start_frame: 0,
+            n_frames: 0,
+            outputs: Vec::new(),
+            textures: Vec::new(),
+            camera_path: Vec::new(),
+            config,
+        };
+        let mut camera = UprightPerspectiveCamera::default();
+        sidecar.record_frame(120, &camera);
+        sidecar.record_frame(121, &camera);
+        camera.pan_and_tilt(cgmath::Deg(10.0), cgmath::Deg(-5.0));
+        sidecar.record_frame(122, &camera);
+        sidecar.record_frame(123, &camera);
+        assert_eq!(sidecar.start_frame, 120);
+        assert_eq!(sidecar.n_frames, 4);
+        assert_eq!(sidecar.camera_path.len(), 2);
+        assert_eq!(sidecar.camera_path[1].frame, 122);
+        assert_eq!(sidecar.camera_path[1].pan, 10.0);
+
+        assert_eq!(camera_keyframe_at(&sidecar.camera_path, 119), None);
+        assert_eq!(
+            camera_keyframe_at(&sidecar.camera_path, 121).unwrap().frame,
+            120
+        );
+        assert_eq!(
+            camera_keyframe_at(&sidecar.camera_path, 500).unwrap().frame,
+            122
+        );
+
+        // The sidecar survives a round trip through TOML, config and all.
+        let parsed: RenderSidecar = toml::from_str(&toml::to_string(&sidecar).unwrap()).unwrap();
+        assert_eq!(parsed.camera_path, sidecar.camera_path);
+        assert_eq!(parsed.n_frames, 4);
+        assert_eq!(parsed.config.n_petals, sidecar.config.n_petals);
+    }
+
+    #[test]
+    fn sidecar_sits_next_to_the_output() {
+        assert_eq!(sidecar_path("loop.mp4"), "loop.mp4.render.toml");
+        assert_eq!(
+            sidecar_path("frames/petals_######.png"),
+            "frames/petals_######.png.render.toml"
+        );
+    }
+
+    #[test]
+    fn reproduces_export_from_its_sidecar() {
+        let Some((_gpu_lock, directory)) = gpu_test("render_sidecar") else {
+            return;
+        };
+        // No seed is set, so the sidecar has to record the one that was chosen.
+        let mut config = headless_test_config("render_sidecar", None);
+        config.n_petals = 500;
+        config.video_export_backend = VideoExportBackend::ImageSequence;
+        config.video_export_width = 32;
+        config.video_export_height = 24;
+        config.video_export_file = directory
+            .join("original_#.png")
+            .to_string_lossy()
+            .into_owned();
+        let video_export_configs = VideoExportConfig::all_from_config(&config, true);
+        let mut simulation_state =
+            state::FallingPetalsState::new(None, config, video_export_configs).unwrap();
+        for frame in 0..3 {
+            if frame == 1 {
+                // Turn the camera partway through, as if by mouse look.
+                simulation_state
+                    .camera
+                    .pan_and_tilt(cgmath::Deg(5.0), cgmath::Deg(2.0));
+            }
+            simulation_state.update();
+            simulation_state.render().unwrap();
+        }
+        simulation_state.finish_video_export().unwrap();
+        let seed = simulation_state.seed;
+        drop(simulation_state);
+
+        let sidecar_file = directory.join("original_#.png.render.toml");
+        let render_sidecar = RenderSidecar::load(sidecar_file.to_str().unwrap()).unwrap();
+        assert_eq!(render_sidecar.seed, seed);
+        assert_eq!(render_sidecar.config.random_seed, Some(seed));
+        assert_eq!(
+            (render_sidecar.start_frame, render_sidecar.n_frames),
+            (0, 3)
+        );
+        assert_eq!(render_sidecar.camera_path.len(), 2);
+        assert_eq!(render_sidecar.textures.len(), 1);
+        assert!(render_sidecar.differences().unwrap().is_empty());
+
+        let reproduce_options = cli::ReproduceOptions {
+            sidecar: sidecar_file.to_string_lossy().into_owned(),
+            output: Some(directory.join("copy_#.png").to_string_lossy().into_owned()),
+        };
+        render::run_reproduce(&reproduce_options).unwrap();
+        for frame_number in 0..3 {
+            let original = image::open(directory.join(format!("original_{frame_number}.png")))
+                .unwrap()
+                .into_rgba8();
+            let copy = image::open(directory.join(format!("copy_{frame_number}.png")))
+                .unwrap()
+                .into_rgba8();
+            assert!(original == copy, "frame {frame_number} differs");
+        }
+    }
+}
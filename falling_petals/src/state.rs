@@ -1,20 +1,33 @@
-use crate::configuration::{FallingPetalsConfig, VideoExportConfig};
+use crate::audio_reactive::{AudioReactivity, Modulation};
+use crate::configuration::{
+    BendAnimationConfig, FallingPetalsConfig, TumblingConfig, VideoExportConfig,
+};
 use crate::graphics::{camera::UprightPerspectiveCamera, gpu_types::PetalVariant, GraphicsState};
 use crate::input::InputState;
+use crate::scale_distribution::{format_scale_histogram, sample_scale};
+use crate::sidecar::{camera_keyframe_at, CameraKeyframe, RenderSidecar};
+use crate::tumbling::{scale_rotation, PetalSpin};
+use crate::variant_selection::choose_variant_indices;
 
 use cgmath::prelude::*;
 use cgmath::{Deg, Rad};
-//use noise::{NoiseFn, Seedable};
+use noise::Perlin;
 use rand::prelude::*;
+use rand_chacha::ChaCha12Rng;
 use rand_distr::StandardNormal;
+use serde::{Deserialize, Serialize};
 use winit::event::{DeviceEvent, ElementState, MouseButton, WindowEvent};
 use winit::window::Window;
 
 pub struct FallingPetalsState {
     /// Config values for the game
     pub config: FallingPetalsConfig,
-    /// Random number generator for this thread
-    pub rng: rand::rngs::ThreadRng,
+    /// Random number generator for the simulation (seeded so that runs can be reproduced).  This
+    /// is the generator behind rand's StdRng, used directly so that its position in the stream of
+    /// random numbers can be saved in checkpoints.
+    pub rng: ChaCha12Rng,
+    /// Seed the random number generator was created with
+    pub seed: u64,
     /// Time at which the previous state update occurred
     pub previous_time: std::time::Instant,
     /// Time at which the current state update occurred
@@ -31,143 +44,242 @@ pub struct FallingPetalsState {
     pub mouse_look_enabled: bool,
     // Petals
     pub petal_states: Vec<PetalState>,
-    pub x_movement: Vec<f32>,
-    pub y_movement: Vec<f32>,
-    pub z_movement: Vec<f32>,
-    pub movement_frame_idx: u32,
-    pub movement_period: u32,
+    /// Per-species movement state, indexed by PetalState::species_index.
+    pub species_states: Vec<PetalSpeciesState>,
+    /// Noise field used to vary the rotation of each petal over time.
+    pub tumbling_noise: Perlin,
+    /// Number of simulation steps taken so far.
+    pub frame_idx: u64,
+    /// Band energies of the audio file driving the simulation (if audio-reactive mode is enabled).
+    pub audio_reactivity: Option<AudioReactivity>,
+    /// Record of the video export, written next to the exported files once they're finished.
+    pub render_sidecar: Option<RenderSidecar>,
+    /// Camera path to follow instead of the user's input (when reproducing an export).
+    pub camera_path: Vec<CameraKeyframe>,
+}
+
+/// The parts of the simulation that change from frame to frame, saved in checkpoints so that an
+/// export can be resumed.  Everything else is set up again from the config and seed.
+#[derive(Serialize, Deserialize)]
+pub struct SimulationSnapshot {
+    pub frame_idx: u64,
+    /// Position of the random number generator in its stream (in 32-bit words).
+    pub rng_word_pos: u64,
+    /// Index into the movement of each species.
+    pub movement_frame_idxs: Vec<u32>,
+    /// The petals, in their current (depth sorted) order.
+    pub petal_states: Vec<PetalState>,
 }
 
 impl FallingPetalsState {
+    /// Sets up the simulation and the graphics.  If `window` is None, runs headless (rendering only
+    /// to the off-screen video export target).
     pub fn new(
-        window: &Window,
+        window: Option<&Window>,
         config: FallingPetalsConfig,
-        video_export_config: VideoExportConfig,
-    ) -> Self {
-        let mut rng = rand::thread_rng();
+        video_export_configs: Vec<VideoExportConfig>,
+    ) -> anyhow::Result<Self> {
+        // Everything random about the simulation comes from this one generator, so that the same
+        // seed reproduces the same frames.  Random seeds are kept to the range TOML integers can
+        // hold, so that they can be written to the render sidecar.
+        let seed = config
+            .random_seed
+            .unwrap_or_else(|| rand::random::<u64>() >> 1);
+        log::info!("Random seed: {seed}");
+        let mut rng = ChaCha12Rng::seed_from_u64(seed);
+        let species = config.resolve_species();
 
         // -----------------------------------------------------------------------------------------
         log::debug!("Computing petal movement");
-        let movement_period = config.movement_period * video_export_config.frame_rate;
-        let x_movement = Self::generate_mixture_of_sines(
-            movement_period,
-            config.movement_n_frequencies,
-            config.movement_low_freq_max_amplitude,
-            config.movement_high_freq_max_amplitude,
-            &mut rng,
-        );
-        let y_movement = Self::generate_mixture_of_sines(
-            movement_period,
-            config.movement_n_frequencies,
-            config.movement_low_freq_max_amplitude,
-            config.movement_high_freq_max_amplitude,
-            &mut rng,
-        );
-        let z_movement = Self::generate_mixture_of_sines(
-            movement_period,
-            config.movement_n_frequencies,
-            config.movement_low_freq_max_amplitude,
-            config.movement_high_freq_max_amplitude,
-            &mut rng,
-        );
+        let species_states = species
+            .iter()
+            .map(|species| {
+                let movement_period = species.movement_period * config.video_export_fps;
+                let mut generate_movement = || {
+                    Self::generate_mixture_of_sines(
+                        movement_period,
+                        species.movement_n_frequencies,
+                        species.movement_low_freq_max_amplitude,
+                        species.movement_high_freq_max_amplitude,
+                        &mut rng,
+                    )
+                };
+                PetalSpeciesState {
+                    fall_speed: species.fall_speed,
+                    tumbling: species.tumbling.clone(),
+                    x_movement: generate_movement(),
+                    y_movement: generate_movement(),
+                    z_movement: generate_movement(),
+                    movement_frame_idx: 0,
+                    movement_period,
+                }
+            })
+            .collect::<Vec<_>>();
 
         // -----------------------------------------------------------------------------------------
         log::debug!("Petal variants setup");
-        let petal_variants: Vec<PetalVariant> = config
-            .petal_textures
-            .iter()
-            .enumerate()
-            .flat_map(|(texture_idx, petal_info)| {
-                // Use a move closure to move ownership of texture_idx into the closure (otherwise,
-                // texture_idx would die at the end of flat_map, leaving a dangling reference).
-                // This also moves ownership of the petal_info reference, but that doesn't matter
-                // since it's just a temporary reference and not ownership of the actual data.
-                petal_info.petal_coordinates.iter().map(move |coords| {
-                    PetalVariant::new(
-                        texture_idx as u32,
-                        petal_info.x_multiplier * coords[0],
-                        petal_info.x_multiplier * coords[1],
-                        petal_info.x_multiplier * coords[2],
-                        petal_info.y_multiplier * coords[3],
+        // The textures and variants of all species are combined into single lists (which is how
+        // they get passed to the GPU), so keep track of where each species' textures and variants
+        // start within those lists.
+        let mut petal_variants: Vec<PetalVariant> = Vec::new();
+        let mut petal_texture_image_paths: Vec<String> = Vec::new();
+        let mut petal_texture_scales: Vec<f32> = Vec::new();
+        let mut species_first_variant_indices: Vec<u32> = Vec::with_capacity(species.len());
+        for species in &species {
+            species_first_variant_indices.push(petal_variants.len() as u32);
+            let first_texture_idx = petal_texture_image_paths.len();
+            petal_variants.extend(species.petal_textures.iter().enumerate().flat_map(
+                |(texture_idx, petal_info)| {
+                    // Use a move closure to move ownership of texture_idx into the closure
+                    // (otherwise, texture_idx would die at the end of flat_map, leaving a dangling
+                    // reference).  This also moves ownership of the petal_info reference, but that
+                    // doesn't matter since it's just a temporary reference and not ownership of
+                    // the actual data.
+                    petal_info.petal_coordinates.iter().enumerate().map(
+                        move |(variant_idx, coords)| {
+                            PetalVariant::new(
+                                (first_texture_idx + texture_idx) as u32,
+                                petal_info.x_multiplier * coords[0],
+                                petal_info.x_multiplier * coords[1],
+                                petal_info.x_multiplier * coords[2],
+                                petal_info.y_multiplier * coords[3],
+                                petal_info.variant_curvature(variant_idx),
+                            )
+                        },
                     )
-                })
-            })
-            .collect();
-        let petal_texture_image_paths = config
-            .petal_textures
-            .iter()
-            .map(|tex| tex.file.clone())
-            .collect::<Vec<String>>();
+                },
+            ));
+            petal_texture_image_paths
+                .extend(species.petal_textures.iter().map(|tex| tex.file.clone()));
+            petal_texture_scales.extend(species.petal_textures.iter().map(|tex| tex.scale));
+        }
 
         // -----------------------------------------------------------------------------------------
         log::debug!("Instance setup");
-        let mut petal_states: Vec<PetalState> = Vec::with_capacity(config.n_petals);
-        for _ in 0..config.n_petals {
-            // Chose a random variant for each petal instance
-            let variant_index = rng.gen_range(0..petal_variants.len() as u32);
-            let aspect_ratio = petal_variants[variant_index as usize]
-                .texture_u_v_width_height
-                .vector[2]
-                / petal_variants[variant_index as usize]
+        let mut petal_states: Vec<PetalState> =
+            Vec::with_capacity(species.iter().map(|species| species.n_petals).sum());
+        for (species_index, species) in species.iter().enumerate() {
+            log::debug!("Species \"{}\": {} petals", species.name, species.n_petals);
+            // Choose the variant for each petal instance up front, so that the configured texture
+            // weights and group ratios are honored exactly (rather than just on average).
+            let variant_indices = choose_variant_indices(
+                &species.petal_textures,
+                &species.petal_groups,
+                species.n_petals,
+                species_first_variant_indices[species_index],
+                &mut rng,
+            );
+            let mut scale_factors = Vec::with_capacity(species.n_petals);
+            for variant_index in variant_indices {
+                let aspect_ratio = petal_variants[variant_index as usize]
                     .texture_u_v_width_height
-                    .vector[3];
-            let actual_scale = petal_variants[variant_index as usize]
-                .texture_u_v_width_height
-                .vector[3]
-                / config.petal_textures[petal_variants[variant_index as usize]
-                    .petal_texture_index
-                    .value as usize]
-                    .scale;
-            let pose = Pose {
+                    .vector[2]
+                    / petal_variants[variant_index as usize]
+                        .texture_u_v_width_height
+                        .vector[3];
+                let actual_scale = petal_variants[variant_index as usize]
+                    .texture_u_v_width_height
+                    .vector[3]
+                    / petal_texture_scales[petal_variants[variant_index as usize]
+                        .petal_texture_index
+                        .value as usize];
                 // Generate random petal positions in view of the camera -- in the [-1,1] x/y range
                 // covered by NDC (normalized device coordinates).
-                position: cgmath::vec3(
+                let position = cgmath::vec3(
                     2.0 * config.max_x * rng.gen::<f32>() - config.max_x,
                     2.0 * config.max_y * rng.gen::<f32>() - config.max_y,
                     2.0 * config.max_z * rng.gen::<f32>() - config.max_z,
-                ),
-                // Randomly choose a rotation (this gives a uniform distribution over all rotations
-                // in 3d space):
-                orientation: cgmath::Quaternion::new(
-                    rng.sample(StandardNormal),
-                    rng.sample(StandardNormal),
-                    rng.sample(StandardNormal),
-                    rng.sample(StandardNormal),
-                )
-                .normalize(),
-                // Give the petal the right shape
-                aspect_ratio,
-                scale: actual_scale
-                    * ((config.max_scale - config.min_scale) * rng.gen::<f32>() + config.min_scale),
-            };
-            let rotation = Self::generate_random_rotation(
-                Rad::<f32>::from(config.min_rotation_speed),
-                Rad::<f32>::from(config.max_rotation_speed),
-                &mut rng,
+                );
+                // The camera looks in the -z direction from the +z side of the volume, so petals
+                // with larger z coordinates are closer to the camera.
+                let scale_factor = sample_scale(
+                    &species.scale_distribution,
+                    species.min_scale,
+                    species.max_scale,
+                    species.scale_depth_correlation,
+                    (position[2] + config.max_z) / (2.0 * config.max_z),
+                    &mut rng,
+                );
+                scale_factors.push(scale_factor);
+                let pose = Pose {
+                    position,
+                    // Randomly choose a rotation (this gives a uniform distribution over all
+                    // rotations in 3d space):
+                    orientation: cgmath::Quaternion::new(
+                        rng.sample(StandardNormal),
+                        rng.sample(StandardNormal),
+                        rng.sample(StandardNormal),
+                        rng.sample(StandardNormal),
+                    )
+                    .normalize(),
+                    // Give the petal the right shape
+                    aspect_ratio,
+                    scale: actual_scale * scale_factor,
+                };
+                let spin = PetalSpin::new_random(
+                    Rad::<f32>::from(species.min_rotation_speed),
+                    Rad::<f32>::from(species.max_rotation_speed),
+                    &mut rng,
+                );
+
+                let bend = PetalBend::new_random(&config.bend_animation, &mut rng);
+
+                petal_states.push(PetalState {
+                    id: petal_states.len() as u32,
+                    pose,
+                    variant_index,
+                    species_index,
+                    spin,
+                    bend,
+                });
+            }
+            log::debug!(
+                "Species \"{}\" scale distribution ({:?}, depth correlation {}):\n{}",
+                species.name,
+                species.scale_distribution,
+                species.scale_depth_correlation,
+                format_scale_histogram(&scale_factors, species.min_scale, species.max_scale, 10),
             );
-
-            petal_states.push(PetalState {
-                pose,
-                variant_index,
-                rotation,
-            });
         }
         petal_states
             .sort_unstable_by(|a, b| a.pose.position[2].partial_cmp(&b.pose.position[2]).unwrap());
 
         // -----------------------------------------------------------------------------------------
-        //log::debug!("Noise generator setup");
-        //let noise_generator = noise::Perlin::default().set_seed(rng.gen()); //noise::Fbm::<noise::OpenSimplex>::default().set_seed(rng.gen());
+        let audio_reactivity = match &config.audio.file {
+            Some(file) if config.audio_reactive.enabled => {
+                log::debug!("Audio analysis");
+                Some(AudioReactivity::from_file(
+                    &config.audio_reactive,
+                    file,
+                    config.audio.offset,
+                    config.video_export_fps,
+                )?)
+            }
+            _ => None,
+        };
+
+        // -----------------------------------------------------------------------------------------
+        log::debug!("Noise generator setup");
+        let tumbling_noise = Perlin::new(rng.gen());
 
         // -----------------------------------------------------------------------------------------
+        // Start recording everything needed to reproduce the export (if any).
+        let render_sidecar = if video_export_configs
+            .iter()
+            .any(|video_export_config| video_export_config.export_enabled)
+        {
+            Some(RenderSidecar::new(&config, seed, &video_export_configs)?)
+        } else {
+            None
+        };
         let graphics_state = GraphicsState::new(
             window,
             &petal_texture_image_paths,
             petal_variants,
             &petal_states,
             &config,
-            video_export_config,
-        );
+            video_export_configs,
+        )?;
         let input_state = InputState::new();
 
         // -----------------------------------------------------------------------------------------
@@ -199,9 +311,10 @@ impl FallingPetalsState {
 
         // -----------------------------------------------------------------------------------------
         let start_time = std::time::Instant::now();
-        Self {
+        Ok(Self {
             config,
             rng,
+            seed,
             previous_time: start_time,
             current_time: start_time,
             graphics_state,
@@ -210,12 +323,13 @@ impl FallingPetalsState {
             petal_states,
             game_window_focused: false,
             mouse_look_enabled: false,
-            x_movement,
-            y_movement,
-            z_movement,
-            movement_frame_idx: 0,
-            movement_period,
-        }
+            species_states,
+            tumbling_noise,
+            frame_idx: 0,
+            audio_reactivity,
+            render_sidecar,
+            camera_path: Vec::new(),
+        })
     }
 
     /// Handles the passed event if possible, and returns a boolean value indicating if the event
@@ -283,19 +397,128 @@ impl FallingPetalsState {
     pub fn update(&mut self) {
         // Game state update code goes here.
 
-        if self.game_window_focused {
+        if !self.camera_path.is_empty() {
+            if let Some(keyframe) = camera_keyframe_at(&self.camera_path, self.frame_idx) {
+                keyframe.apply_to(&mut self.camera);
+            }
+        } else if self.game_window_focused {
             self.update_based_on_input_state();
         }
+        if self.graphics_state.is_exporting_video() {
+            if let Some(render_sidecar) = &mut self.render_sidecar {
+                render_sidecar.record_frame(self.frame_idx, &self.camera);
+            }
+        }
 
-        // Rotate and move petals
-        for petal_state in self.petal_states.iter_mut() {
-            petal_state.pose.orientation = petal_state.rotation * petal_state.pose.orientation;
+        self.step_simulation();
 
-            petal_state.pose.position[1] -= self.config.fall_speed;
+        // Update GPU buffers according to the current game state.
+        self.graphics_state.update(
+            &self.camera,
+            &self.petal_states,
+            self.frame_idx as f32 / self.config.video_export_fps as f32,
+        );
 
-            petal_state.pose.position[0] += self.x_movement[self.movement_frame_idx as usize];
-            petal_state.pose.position[1] += self.y_movement[self.movement_frame_idx as usize];
-            petal_state.pose.position[2] += self.z_movement[self.movement_frame_idx as usize];
+        self.advance_frame_idx();
+    }
+
+    /// Advances the simulation by `n_frames` frames without updating the GPU buffers (e.g. to
+    /// start rendering partway through).  The petals end up exactly where they would have been if
+    /// those frames had been rendered.
+    pub fn skip_frames(&mut self, n_frames: u64) {
+        for _ in 0..n_frames {
+            self.step_simulation();
+            self.advance_frame_idx();
+        }
+    }
+
+    /// Saves the current state of the simulation.
+    pub fn snapshot(&self) -> SimulationSnapshot {
+        SimulationSnapshot {
+            frame_idx: self.frame_idx,
+            rng_word_pos: self.rng.get_word_pos() as u64,
+            movement_frame_idxs: self
+                .species_states
+                .iter()
+                .map(|species_state| species_state.movement_frame_idx)
+                .collect(),
+            petal_states: self.petal_states.clone(),
+        }
+    }
+
+    /// Restores the state of the simulation saved by snapshot().  The simulation must have been set
+    /// up with the same config and seed as when the snapshot was taken.
+    pub fn restore(&mut self, snapshot: SimulationSnapshot) -> anyhow::Result<()> {
+        if snapshot.petal_states.len() != self.petal_states.len()
+            || snapshot.movement_frame_idxs.len() != self.species_states.len()
+        {
+            anyhow::bail!("The snapshot doesn't match the simulation's petals or species");
+        }
+        self.frame_idx = snapshot.frame_idx;
+        self.rng.set_word_pos(u128::from(snapshot.rng_word_pos));
+        for (species_state, movement_frame_idx) in self
+            .species_states
+            .iter_mut()
+            .zip(snapshot.movement_frame_idxs)
+        {
+            species_state.movement_frame_idx = movement_frame_idx;
+        }
+        self.petal_states = snapshot.petal_states;
+        Ok(())
+    }
+
+    /// Moves and rotates the petals by one frame.
+    fn step_simulation(&mut self) {
+        let modulation = self
+            .audio_reactivity
+            .as_ref()
+            .map_or_else(Modulation::default, |audio_reactivity| {
+                audio_reactivity.modulation(self.frame_idx)
+            });
+        // Rotate and move petals
+        for petal_state in self.petal_states.iter_mut() {
+            let species_state = &self.species_states[petal_state.species_index];
+            let movement_frame_idx = species_state.movement_frame_idx as usize;
+            let rotation = petal_state.spin.step(
+                petal_state.pose.orientation,
+                self.frame_idx as f64
+                    / (f64::from(self.config.video_export_fps)
+                        * f64::from(species_state.tumbling.wander_period)),
+                self.config.video_export_fps as f32,
+                &species_state.tumbling,
+                &self.tumbling_noise,
+                &mut self.rng,
+            );
+            let rotation = if modulation.spin_rate != 1.0 {
+                scale_rotation(rotation, modulation.spin_rate)
+            } else {
+                rotation
+            };
+            // Renormalize so that rounding errors don't accumulate over many multiplications.
+            petal_state.pose.orientation = (rotation * petal_state.pose.orientation).normalize();
+
+            petal_state.pose.position[1] -= species_state.fall_speed * modulation.fall_speed;
+
+            let gust_strength = modulation.gust_strength;
+            petal_state.pose.position[0] +=
+                gust_strength * species_state.x_movement[movement_frame_idx];
+            petal_state.pose.position[1] +=
+                gust_strength * species_state.y_movement[movement_frame_idx];
+            petal_state.pose.position[2] +=
+                gust_strength * species_state.z_movement[movement_frame_idx];
+
+            if modulation.burst > 0.0 {
+                // Push the petal away from the center of the view (the z axis).
+                let offset = cgmath::Vector2::new(
+                    petal_state.pose.position[0],
+                    petal_state.pose.position[1],
+                );
+                if offset.magnitude2() > 0.0 {
+                    let push = offset.normalize() * modulation.burst;
+                    petal_state.pose.position[0] += push.x;
+                    petal_state.pose.position[1] += push.y;
+                }
+            }
 
             // Wrap petal locations that exit the simulation volume around so that they come back
             // in on the opposite side.
@@ -335,11 +558,14 @@ impl FallingPetalsState {
         // it's easier (and faster) to just sort by world coordinates.
         self.petal_states
             .sort_unstable_by(|a, b| a.pose.position[2].partial_cmp(&b.pose.position[2]).unwrap());
+    }
 
-        // Update GPU buffers according to the current game state.
-        self.graphics_state.update(&self.camera, &self.petal_states);
-
-        self.movement_frame_idx = (self.movement_frame_idx + 1) % self.movement_period;
+    fn advance_frame_idx(&mut self) {
+        self.frame_idx += 1;
+        for species_state in self.species_states.iter_mut() {
+            species_state.movement_frame_idx =
+                (species_state.movement_frame_idx + 1) % species_state.movement_period;
+        }
     }
 
     fn update_based_on_input_state(&mut self) {
@@ -361,32 +587,28 @@ impl FallingPetalsState {
         self.graphics_state.render()
     }
 
+    /// Stops the video export (if any), waiting for the exported files to be written, and then
+    /// writes the render sidecar next to them.  No sidecar is written if the export failed.
+    pub fn finish_video_export(&mut self) -> anyhow::Result<()> {
+        let render_sidecar = self.render_sidecar.take();
+        self.graphics_state.finish_video_export()?;
+        match render_sidecar {
+            Some(render_sidecar) if render_sidecar.n_frames > 0 => render_sidecar.write(),
+            _ => Ok(()),
+        }
+    }
+
     /// Attempt to reconfigure / reacquire the rendering surface using the last known window size.
     pub fn reconfigure_rendering_surface(&mut self) {
         self.graphics_state.resize(self.graphics_state.size)
     }
 
-    fn generate_random_rotation(
-        min_angle: Rad<f32>,
-        max_angle: Rad<f32>,
-        rng: &mut rand::rngs::ThreadRng,
-    ) -> cgmath::Quaternion<f32> {
-        let axis = cgmath::Vector3::<f32> {
-            x: rng.sample(StandardNormal),
-            y: rng.sample(StandardNormal),
-            z: rng.sample(StandardNormal),
-        }
-        .normalize();
-        let angle = min_angle + (max_angle - min_angle) * rng.gen::<f32>();
-        cgmath::Rotation3::from_axis_angle(axis, angle)
-    }
-
     fn generate_mixture_of_sines(
         length: u32,
         n_frequencies: u32,
         low_freq_max_amplitude: f32,
         high_freq_max_amplitude: f32,
-        rng: &mut rand::rngs::ThreadRng,
+        rng: &mut ChaCha12Rng,
     ) -> Vec<f32> {
         let mut amplitudes_by_frequency = Vec::<f32>::with_capacity(n_frequencies as usize);
         let mut phases_by_frequency = Vec::<f32>::with_capacity(n_frequencies as usize);
@@ -415,7 +637,16 @@ impl FallingPetalsState {
     }
 }
 
-#[derive(Debug, Copy, Clone)]
+impl Drop for FallingPetalsState {
+    fn drop(&mut self) {
+        // Finish writing any video export that is still running (e.g. when the window is closed).
+        if let Err(error) = self.finish_video_export() {
+            log::error!("{error:#}");
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Serialize, Deserialize)]
 pub struct Pose {
     position: cgmath::Vector3<f32>,
     orientation: cgmath::Quaternion<f32>,
@@ -456,8 +687,68 @@ impl From<&Pose> for crate::graphics::gpu_types::Matrix4 {
     }
 }
 
+#[derive(Clone, Serialize, Deserialize)]
 pub struct PetalState {
+    /// Number of the petal in the order the petals were created.  Unlike its index, this stays the
+    /// same when the petals are sorted by depth, so it identifies the petal in the ID matte pass.
+    pub id: u32,
     pub pose: Pose,
     pub variant_index: u32,
-    pub rotation: cgmath::Quaternion<f32>,
+    /// Index of the species this petal belongs to (into FallingPetalsState::species_states).
+    pub species_index: usize,
+    /// Rotation state of the petal, which determines how its orientation changes each frame.
+    pub spin: PetalSpin,
+    /// Parameters of the petal's bend animation (which is computed in the vertex shader).
+    pub bend: PetalBend,
+}
+
+impl From<&PetalState> for crate::graphics::gpu_types::PetalInstanceData {
+    fn from(petal_state: &PetalState) -> Self {
+        crate::graphics::gpu_types::PetalInstanceData {
+            bend_phase: petal_state.bend.phase.0,
+            bend_frequency: petal_state.bend.angular_frequency.0,
+            bend_amplitude: petal_state.bend.bend_amplitude,
+            flap_amplitude: petal_state.bend.flap_amplitude,
+            variant_index: petal_state.variant_index,
+            petal_id: petal_state.id,
+        }
+    }
+}
+
+/// Per-petal parameters of the bend animation.  These stay constant over the life of the petal;
+/// the vertex shader combines them with the simulation time to compute the current bend.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PetalBend {
+    pub phase: Rad<f32>,
+    /// Angular frequency of the animation, in radians per second.
+    pub angular_frequency: Rad<f32>,
+    pub bend_amplitude: f32,
+    pub flap_amplitude: f32,
+}
+
+impl PetalBend {
+    pub fn new_random<R: Rng + ?Sized>(bend_animation: &BendAnimationConfig, rng: &mut R) -> Self {
+        let frequency = bend_animation.min_frequency
+            + (bend_animation.max_frequency - bend_animation.min_frequency) * rng.gen::<f32>();
+        Self {
+            phase: Rad::full_turn() * rng.gen::<f32>(),
+            angular_frequency: Rad::full_turn() * frequency,
+            bend_amplitude: bend_animation.bend_amplitude * rng.gen::<f32>(),
+            flap_amplitude: bend_animation.flap_amplitude * rng.gen::<f32>(),
+        }
+    }
+}
+
+/// Movement state shared by all the petals of a single species.  All petals of a species move in
+/// lock-step, following the same mixture of sinusoids.
+pub struct PetalSpeciesState {
+    /// Constant speed at which the petals of this species fall per frame.
+    pub fall_speed: f32,
+    /// Settings for how the rotation of the petals of this species changes over time.
+    pub tumbling: TumblingConfig,
+    pub x_movement: Vec<f32>,
+    pub y_movement: Vec<f32>,
+    pub z_movement: Vec<f32>,
+    pub movement_frame_idx: u32,
+    pub movement_period: u32,
 }
@@ -0,0 +1,110 @@
+//! Setup shared by the tests that render with the GPU.
+
+use crate::configuration::{FallingPetalsConfig, VideoExportConfig};
+use crate::state::FallingPetalsState;
+use std::path::PathBuf;
+use std::sync::MutexGuard;
+
+/// Held by tests that use the GPU, since some backends (e.g. GL through EGL) don't support using
+/// devices from multiple threads at once.
+static GPU_TEST_LOCK: std::sync::Mutex<()> = std::sync::Mutex::new(());
+
+/// Returns true if wgpu can find any adapter (hardware or software) to run the tests on.
+fn adapter_available() -> bool {
+    let instance = wgpu::Instance::new(wgpu::InstanceDescriptor::default());
+    [false, true].into_iter().any(|force_fallback_adapter| {
+        pollster::block_on(instance.request_adapter(&wgpu::RequestAdapterOptions {
+            power_preference: wgpu::PowerPreference::default(),
+            compatible_surface: None,
+            force_fallback_adapter,
+        }))
+        .is_some()
+    })
+}
+
+/// Prepares a test that uses the GPU: returns the GPU lock and an empty temporary directory for the
+/// test's output files, or None (after saying so) if there is no GPU adapter to run the test on.
+pub fn gpu_test(name: &str) -> Option<(MutexGuard<'static, ()>, PathBuf)> {
+    if !adapter_available() {
+        eprintln!("Skipping {name} test: no GPU adapter available");
+        return None;
+    }
+    let gpu_lock = GPU_TEST_LOCK
+        .lock()
+        .unwrap_or_else(|error| error.into_inner());
+    let directory = std::env::temp_dir().join(format!("falling_petals_{name}_test"));
+    let _ = std::fs::remove_dir_all(&directory);
+    std::fs::create_dir_all(&directory).unwrap();
+    Some((gpu_lock, directory))
+}
+
+/// Returns the config for a small simulation using a generated texture.
+pub fn headless_test_config(name: &str, seed: Option<u64>) -> FallingPetalsConfig {
+    let texture_path = std::env::temp_dir().join(format!("falling_petals_{name}_test.png"));
+    image::RgbaImage::from_pixel(16, 16, image::Rgba([255, 128, 0, 255]))
+        .save(&texture_path)
+        .unwrap();
+    let mut config = FallingPetalsConfig {
+        n_petals: 20,
+        random_seed: seed,
+        ..Default::default()
+    };
+    config.petal_textures[0].file = texture_path.to_string_lossy().into_owned();
+    config
+}
+
+/// Renders `n_frames` frames of a headless simulation to all the export targets of the config, and
+/// waits for all of them to be written.
+pub fn export_frames(config: FallingPetalsConfig, n_frames: usize) {
+    let video_export_configs = VideoExportConfig::all_from_config(&config, true);
+    let mut simulation_state = FallingPetalsState::new(None, config, video_export_configs).unwrap();
+    for _ in 0..n_frames {
+        simulation_state.update();
+        simulation_state.render().unwrap();
+    }
+    simulation_state.finish_video_export().unwrap();
+}
+
+/// Renders `n_frames` frames of a headless simulation to the given video export target (with
+/// export disabled), and returns the frames that would have been sent to the video coding thread.
+pub fn capture_frames(
+    config: FallingPetalsConfig,
+    video_export_config: VideoExportConfig,
+    n_frames: usize,
+) -> Vec<Vec<u8>> {
+    let mut simulation_state =
+        FallingPetalsState::new(None, config, vec![video_export_config]).unwrap();
+    let (tx, rx) = std::sync::mpsc::sync_channel(n_frames);
+    simulation_state.graphics_state.video_export_states[0].video_thread_tx = Some(tx);
+    for _ in 0..n_frames {
+        simulation_state.update();
+        simulation_state.render().unwrap();
+    }
+    simulation_state.graphics_state.flush_video_frames();
+    rx.try_iter().collect()
+}
+
+/// Creates a small headless simulation (without video export) using a generated texture.
+pub fn headless_test_state(name: &str, seed: Option<u64>) -> FallingPetalsState {
+    headless_test_state_with_size(name, seed, 64, 48)
+}
+
+/// Like headless_test_state(), but with a video export target of the given size.
+pub fn headless_test_state_with_size(
+    name: &str,
+    seed: Option<u64>,
+    width: u32,
+    height: u32,
+) -> FallingPetalsState {
+    let config = headless_test_config(name, seed);
+    let video_export_config = VideoExportConfig::new(
+        false,
+        String::new(),
+        Default::default(),
+        width,
+        height,
+        config.video_export_fps,
+        wgpu::TextureFormat::Bgra8UnormSrgb,
+    );
+    FallingPetalsState::new(None, config, vec![video_export_config]).unwrap()
+}
@@ -0,0 +1,518 @@
+//! Tiled and supersampled rendering of exported frames.  Frames larger than the GPU's maximum
+//! texture size are split into tiles, which are each rendered with their part of the camera's view
+//! frustum, read back separately, and stitched together.  With supersampling, each tile is rendered
+//! at a multiple of its resolution (plus a margin for the downsample filter) and filtered down on the
+//! CPU, which anti-aliases the edges of the petals.
+
+use crate::alpha::srgb_to_linear;
+use crate::configuration::{DownsampleFilter, TiledExportConfig};
+use crate::image_sequence::linear_to_srgb;
+
+/// Position of a tile's top left corner in the exported frame (in output pixels).
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Tile {
+    pub x: u32,
+    pub y: u32,
+}
+
+/// The source pixels that one output pixel is filtered from: the index of the first one, and the
+/// weights of it and the ones following it.
+struct FilterTaps {
+    start: usize,
+    weights: Vec<f32>,
+}
+
+/// How the exported frames are split into tiles, and how the tiles are filtered down to the output
+/// resolution.
+pub struct TileLayout {
+    frame_width: u32,
+    frame_height: u32,
+    /// Size of each tile in output pixels (the tiles at the right and bottom edges of the frame are
+    /// cropped)
+    tile_width: u32,
+    tile_height: u32,
+    /// Supersampling factor
+    factor: u32,
+    /// Extra (supersampled) pixels rendered around each tile for the downsample filter
+    margin: u32,
+    /// Width of the texture each tile is rendered to (in supersampled pixels, including margins)
+    pub render_width: u32,
+    /// Height of the texture each tile is rendered to (in supersampled pixels, including margins)
+    pub render_height: u32,
+    /// The tiles, row by row
+    pub tiles: Vec<Tile>,
+    horizontal_taps: Vec<FilterTaps>,
+    vertical_taps: Vec<FilterTaps>,
+}
+
+impl TileLayout {
+    /// Lays out tiles no larger than `max_texture_size` (or config.max_tile_size, if smaller) that
+    /// cover the frame.
+    pub fn new(
+        frame_width: u32,
+        frame_height: u32,
+        config: &TiledExportConfig,
+        max_texture_size: u32,
+    ) -> anyhow::Result<Self> {
+        let factor = config.supersampling.max(1);
+        // Without supersampling, the pixels are used as they are rendered.
+        let radius = match factor {
+            1 => 0.5,
+            _ => config.downsample_filter.radius(),
+        };
+        let margin = ((radius - 0.5) * factor as f32).ceil() as u32;
+        let max_tile_size = match config.max_tile_size {
+            0 => max_texture_size,
+            max_tile_size => max_tile_size.min(max_texture_size),
+        };
+        let max_tile_output_size = max_tile_size.saturating_sub(2 * margin) / factor;
+        if max_tile_output_size == 0 {
+            anyhow::bail!(
+                "Tiles of at most {max_tile_size} pixels are too small for supersampling by \
+                {factor} with the {:?} filter",
+                config.downsample_filter
+            );
+        }
+        // Split the frame evenly, rather than leaving a sliver for the last tile.
+        let n_tiles_x = frame_width.div_ceil(max_tile_output_size);
+        let n_tiles_y = frame_height.div_ceil(max_tile_output_size);
+        let tile_width = frame_width.div_ceil(n_tiles_x);
+        let tile_height = frame_height.div_ceil(n_tiles_y);
+        let render_width = tile_width * factor + 2 * margin;
+        let render_height = tile_height * factor + 2 * margin;
+        let tiles = (0..n_tiles_y)
+            .flat_map(|tile_y| {
+                (0..n_tiles_x).map(move |tile_x| Tile {
+                    x: tile_x * tile_width,
+                    y: tile_y * tile_height,
+                })
+            })
+            .collect();
+        let filter_taps = |n_output_pixels: u32, n_source_pixels: u32| {
+            (0..n_output_pixels)
+                .map(|idx| {
+                    filter_taps(
+                        config.downsample_filter,
+                        factor,
+                        margin as f32 + (idx as f32 + 0.5) * factor as f32,
+                        n_source_pixels,
+                    )
+                })
+                .collect()
+        };
+        Ok(Self {
+            frame_width,
+            frame_height,
+            tile_width,
+            tile_height,
+            factor,
+            margin,
+            render_width,
+            render_height,
+            tiles,
+            horizontal_taps: filter_taps(tile_width, render_width),
+            vertical_taps: filter_taps(tile_height, render_height),
+        })
+    }
+
+    /// Returns true if each frame is rendered as a single tile at the output resolution, so the
+    /// rendered pixels are the frame.
+    pub fn is_single_tile(&self) -> bool {
+        self.tiles.len() == 1 && self.factor == 1
+    }
+
+    /// Returns the part of the full frame (left, top, width, height, as fractions of its width and
+    /// height) that is rendered for the given tile, including its margins.
+    pub fn view_rectangle(&self, tile: Tile) -> (f32, f32, f32, f32) {
+        let frame_width = (self.frame_width * self.factor) as f32;
+        let frame_height = (self.frame_height * self.factor) as f32;
+        (
+            ((tile.x * self.factor) as f32 - self.margin as f32) / frame_width,
+            ((tile.y * self.factor) as f32 - self.margin as f32) / frame_height,
+            self.render_width as f32 / frame_width,
+            self.render_height as f32 / frame_height,
+        )
+    }
+
+    /// Filters the rendered pixels of a tile (`render_width` x `render_height` pixels in the given
+    /// texture format, without row padding) down to the output resolution, and copies them into
+    /// their place in the frame.
+    pub fn add_tile(
+        &self,
+        tile: Tile,
+        tile_pixels: &[u8],
+        texture_format: wgpu::TextureFormat,
+        frame: &mut [u8],
+    ) {
+        let bytes_per_pixel = texture_format.describe().block_size as usize;
+        let frame_row_size = self.frame_width as usize * bytes_per_pixel;
+        // The tiles at the right and bottom edges can extend past the frame.
+        let width = self.tile_width.min(self.frame_width - tile.x) as usize;
+        let height = self.tile_height.min(self.frame_height - tile.y) as usize;
+        let frame_rows = frame
+            .chunks_exact_mut(frame_row_size)
+            .skip(tile.y as usize)
+            .take(height);
+        let frame_offset = tile.x as usize * bytes_per_pixel;
+        if self.factor == 1 {
+            let tile_row_size = self.render_width as usize * bytes_per_pixel;
+            for (frame_row, tile_row) in frame_rows.zip(tile_pixels.chunks_exact(tile_row_size)) {
+                frame_row[frame_offset..frame_offset + width * bytes_per_pixel]
+                    .copy_from_slice(&tile_row[..width * bytes_per_pixel]);
+            }
+            return;
+        }
+
+        // Filter in linear color space, first along the rows and then along the columns.  The
+        // colors are premultiplied by alpha, so transparent pixels don't bleed into the others.
+        let source = decode_pixels(tile_pixels, texture_format);
+        let render_width = self.render_width as usize;
+        let mut filtered_rows = vec![0.0; self.render_height as usize * width * 4];
+        for_each_row_in_parallel(&mut filtered_rows, width * 4, |row_idx, row| {
+            let source_row = &source[row_idx * render_width * 4..(row_idx + 1) * render_width * 4];
+            for (pixel, taps) in row.chunks_exact_mut(4).zip(&self.horizontal_taps) {
+                for (offset, weight) in taps.weights.iter().enumerate() {
+                    let source_idx = (taps.start + offset) * 4;
+                    for channel in 0..4 {
+                        pixel[channel] += weight * source_row[source_idx + channel];
+                    }
+                }
+            }
+        });
+        let mut filtered = vec![0.0; height * width * 4];
+        for_each_row_in_parallel(&mut filtered, width * 4, |row_idx, row| {
+            let taps = &self.vertical_taps[row_idx];
+            for (offset, weight) in taps.weights.iter().enumerate() {
+                let source_row_start = (taps.start + offset) * width * 4;
+                let source_row = &filtered_rows[source_row_start..source_row_start + width * 4];
+                for (value, source_value) in row.iter_mut().zip(source_row) {
+                    *value += weight * source_value;
+                }
+            }
+        });
+        for (frame_row, filtered_row) in frame_rows.zip(filtered.chunks_exact(width * 4)) {
+            encode_pixels(
+                filtered_row,
+                texture_format,
+                &mut frame_row[frame_offset..frame_offset + width * bytes_per_pixel],
+            );
+        }
+    }
+}
+
+impl DownsampleFilter {
+    /// Distance (in output pixels) beyond which the filter's weight is zero.
+    fn radius(self) -> f32 {
+        match self {
+            DownsampleFilter::Box => 0.5,
+            DownsampleFilter::Triangle => 1.0,
+            DownsampleFilter::Lanczos3 => 3.0,
+        }
+    }
+
+    /// Weight of a source pixel at the given distance (in output pixels) from the output pixel.
+    fn weight(self, distance: f32) -> f32 {
+        let distance = distance.abs();
+        match self {
+            DownsampleFilter::Box => f32::from(u8::from(distance < 0.5)),
+            DownsampleFilter::Triangle => (1.0 - distance).max(0.0),
+            DownsampleFilter::Lanczos3 if distance < 3.0 => sinc(distance) * sinc(distance / 3.0),
+            DownsampleFilter::Lanczos3 => 0.0,
+        }
+    }
+}
+
+fn sinc(x: f32) -> f32 {
+    if x == 0.0 {
+        1.0
+    } else {
+        let x = std::f32::consts::PI * x;
+        x.sin() / x
+    }
+}
+
+/// Returns the normalized filter weights of the source pixels around `center` (in source pixel
+/// coordinates).
+fn filter_taps(
+    filter: DownsampleFilter,
+    factor: u32,
+    center: f32,
+    n_source_pixels: u32,
+) -> FilterTaps {
+    let radius = filter.radius() * factor as f32;
+    let start = (center - radius).floor().max(0.0) as usize;
+    let end = ((center + radius).ceil() as usize).min(n_source_pixels as usize);
+    let mut weights = (start..end)
+        .map(|idx| filter.weight((idx as f32 + 0.5 - center) / factor as f32))
+        .collect::<Vec<_>>();
+    let sum = weights.iter().sum::<f32>();
+    for weight in &mut weights {
+        *weight /= sum;
+    }
+    FilterTaps { start, weights }
+}
+
+/// Splits `data` into rows of `row_len` values, and calls `f` with the index and contents of each
+/// row, spread over all the CPU cores.
+fn for_each_row_in_parallel(
+    data: &mut [f32],
+    row_len: usize,
+    f: impl Fn(usize, &mut [f32]) + Sync,
+) {
+    let n_rows = data.len() / row_len;
+    let n_threads = std::thread::available_parallelism().map_or(1, |n| n.get());
+    let rows_per_thread = n_rows.div_ceil(n_threads).max(1);
+    std::thread::scope(|scope| {
+        for (chunk_idx, chunk) in data.chunks_mut(rows_per_thread * row_len).enumerate() {
+            let f = &f;
+            scope.spawn(move || {
+                for (row_idx, row) in chunk.chunks_exact_mut(row_len).enumerate() {
+                    f(chunk_idx * rows_per_thread + row_idx, row);
+                }
+            });
+        }
+    });
+}
+
+/// Converts rendered pixels to linear floating point values (keeping the channel order).
+fn decode_pixels(pixels: &[u8], texture_format: wgpu::TextureFormat) -> Vec<f32> {
+    match texture_format {
+        wgpu::TextureFormat::Bgra8UnormSrgb => {
+            let srgb_table = (0..=255u8)
+                .map(|value| srgb_to_linear(f32::from(value) / 255.0))
+                .collect::<Vec<_>>();
+            pixels
+                .chunks_exact(4)
+                .flat_map(|pixel| {
+                    [
+                        srgb_table[usize::from(pixel[0])],
+                        srgb_table[usize::from(pixel[1])],
+                        srgb_table[usize::from(pixel[2])],
+                        // Alpha is stored linearly.
+                        f32::from(pixel[3]) / 255.0,
+                    ]
+                })
+                .collect()
+        }
+        _ => pixels
+            .chunks_exact(2)
+            .map(|bytes| half::f16::from_le_bytes([bytes[0], bytes[1]]).to_f32())
+            .collect(),
+    }
+}
+
+/// The inverse of decode_pixels().  Values are clamped, since the negative lobes of the Lanczos
+/// filter can overshoot.
+fn encode_pixels(values: &[f32], texture_format: wgpu::TextureFormat, pixels: &mut [u8]) {
+    match texture_format {
+        wgpu::TextureFormat::Bgra8UnormSrgb => {
+            for (idx, (&value, byte)) in values.iter().zip(pixels.iter_mut()).enumerate() {
+                let value = value.clamp(0.0, 1.0);
+                let value = if idx % 4 == 3 {
+                    value
+                } else {
+                    linear_to_srgb(value)
+                };
+                *byte = (value * 255.0).round() as u8;
+            }
+        }
+        _ => {
+            for (idx, (&value, bytes)) in values.iter().zip(pixels.chunks_exact_mut(2)).enumerate()
+            {
+                let value = if idx % 4 == 3 {
+                    value.clamp(0.0, 1.0)
+                } else {
+                    value.max(0.0)
+                };
+                bytes.copy_from_slice(&half::f16::from_f32(value).to_le_bytes());
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::configuration::VideoExportConfig;
+    use crate::test_support::{
+        capture_frames, gpu_test, headless_test_config, headless_test_state,
+    };
+
+    #[test]
+    fn tiles_cover_the_frame() {
+        let config = TiledExportConfig {
+            max_tile_size: 0,
+            supersampling: 1,
+            downsample_filter: DownsampleFilter::Lanczos3,
+        };
+        let layout = TileLayout::new(1920, 1080, &config, 8192).unwrap();
+        assert!(layout.is_single_tile());
+        assert_eq!((layout.render_width, layout.render_height), (1920, 1080));
+        assert_eq!(layout.view_rectangle(layout.tiles[0]), (0.0, 0.0, 1.0, 1.0));
+
+        // 16K x 4K with at most 4096 pixel textures, supersampled twice: each tile covers at most
+        // (4096 - 2 * 5) / 2 = 2043 output pixels, so 8 x 3 tiles are needed.
+        let config = TiledExportConfig {
+            max_tile_size: 4096,
+            supersampling: 2,
+            ..config
+        };
+        let layout = TileLayout::new(15360, 4320, &config, 8192).unwrap();
+        assert!(!layout.is_single_tile());
+        assert_eq!(layout.tiles.len(), 8 * 3);
+        assert_eq!((layout.tile_width, layout.tile_height), (1920, 1440));
+        assert_eq!((layout.render_width, layout.render_height), (3850, 2890));
+        assert_eq!(layout.tiles[10], Tile { x: 3840, y: 1440 });
+        let (left, top, width, _) = layout.view_rectangle(layout.tiles[1]);
+        assert_eq!(left, (1920.0 * 2.0 - 5.0) / 30720.0);
+        assert_eq!(top, -5.0 / 8640.0);
+        assert_eq!(width, 3850.0 / 30720.0);
+
+        assert!(TileLayout::new(
+            100,
+            100,
+            &TiledExportConfig {
+                max_tile_size: 10,
+                ..config
+            },
+            8192
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn downsampling_preserves_flat_colors_and_averages_edges() {
+        for filter in [
+            DownsampleFilter::Box,
+            DownsampleFilter::Triangle,
+            DownsampleFilter::Lanczos3,
+        ] {
+            let config = TiledExportConfig {
+                max_tile_size: 0,
+                supersampling: 2,
+                downsample_filter: filter,
+            };
+            let layout = TileLayout::new(8, 1, &config, 8192).unwrap();
+            let (render_width, render_height) = (layout.render_width, layout.render_height);
+            // Opaque linear gray everywhere, except for a vertical white line two supersampled
+            // pixels wide (one output pixel) centered on the 2nd output pixel.
+            let line_start = layout.margin + 2;
+            let tile_pixels = (0..render_height)
+                .flat_map(|_| {
+                    (0..render_width).flat_map(|x| {
+                        let value = if (line_start..line_start + 2).contains(&x) {
+                            1.0
+                        } else {
+                            0.25
+                        };
+                        [value, value, value, 1.0].map(|value: f32| half::f16::from_f32(value))
+                    })
+                })
+                .flat_map(|value| value.to_le_bytes())
+                .collect::<Vec<_>>();
+            let mut frame = vec![0; 8 * 8];
+            layout.add_tile(
+                layout.tiles[0],
+                &tile_pixels,
+                wgpu::TextureFormat::Rgba16Float,
+                &mut frame,
+            );
+            let values = decode_pixels(&frame, wgpu::TextureFormat::Rgba16Float);
+            assert!(values.chunks(4).all(|pixel| (pixel[3] - 1.0).abs() < 1e-3));
+            // The line is brightest at the 2nd pixel, and pixels beyond the filter's reach stay gray.
+            assert!(values[4] > values[0] && values[4] > values[8], "{filter:?}");
+            assert!(
+                (values[28] - 0.25).abs() < 1e-3,
+                "{filter:?}: {}",
+                values[28]
+            );
+            if filter == DownsampleFilter::Box {
+                assert_eq!(values[4], 1.0);
+                assert_eq!(values[0], 0.25);
+            }
+        }
+    }
+
+    #[test]
+    fn tiled_frames_match_untiled_frames() {
+        let Some((_gpu_lock, _)) = gpu_test("tiling") else {
+            return;
+        };
+        // Renders the 2nd frame with the given tiling settings, and returns its pixels.
+        let render_frame = |tiled_export: TiledExportConfig| {
+            let mut config = headless_test_config("tiling", Some(8));
+            config.n_petals = 300;
+            let mut video_export_config = VideoExportConfig::new(
+                false,
+                String::new(),
+                Default::default(),
+                70,
+                40,
+                config.video_export_fps,
+                wgpu::TextureFormat::Bgra8UnormSrgb,
+            );
+            video_export_config.tiled_export = tiled_export;
+            capture_frames(config, video_export_config, 2)
+                .pop()
+                .unwrap()
+        };
+        let untiled = render_frame(Default::default());
+        // 3 x 2 tiles, the ones at the right and bottom edges extending past the frame.
+        let tiled = render_frame(TiledExportConfig {
+            max_tile_size: 32,
+            ..Default::default()
+        });
+        assert_eq!(tiled.len(), untiled.len());
+        // Pixels that straddle a triangle edge can round differently in the tiles.
+        let n_different_pixels = tiled
+            .chunks(4)
+            .zip(untiled.chunks(4))
+            .filter(|(a, b)| a.iter().zip(b.iter()).any(|(a, b)| a.abs_diff(*b) > 2))
+            .count();
+        assert!(
+            n_different_pixels < 10,
+            "{n_different_pixels} pixels differ"
+        );
+
+        let supersampled = render_frame(TiledExportConfig {
+            max_tile_size: 64,
+            supersampling: 3,
+            downsample_filter: DownsampleFilter::Lanczos3,
+        });
+        assert_eq!(supersampled.len(), untiled.len());
+        assert!(supersampled.chunks(4).all(|pixel| pixel[3] == 255));
+        // Anti-aliasing only changes the edges of the petals.
+        let mean_difference = supersampled
+            .iter()
+            .zip(&untiled)
+            .map(|(a, b)| f64::from(a.abs_diff(*b)))
+            .sum::<f64>()
+            / untiled.len() as f64;
+        assert!(mean_difference < 8.0, "{mean_difference}");
+    }
+
+    #[test]
+    fn exports_frames_wider_than_the_gpu_allows() {
+        let Some((_gpu_lock, _)) = gpu_test("wide_tiling") else {
+            return;
+        };
+        let max_texture_size = headless_test_state("wide_tiling", Some(3))
+            .graphics_state
+            .device
+            .limits()
+            .max_texture_dimension_2d;
+        let config = headless_test_config("wide_tiling", Some(3));
+        let (width, height) = (max_texture_size + 64, 8);
+        let video_export_config = VideoExportConfig::new(
+            false,
+            String::new(),
+            Default::default(),
+            width,
+            height,
+            config.video_export_fps,
+            wgpu::TextureFormat::Bgra8UnormSrgb,
+        );
+        let frames = capture_frames(config, video_export_config, 1);
+        assert_eq!(frames[0].len(), 4 * width as usize * height as usize);
+    }
+}
@@ -0,0 +1,162 @@
+//! Time-varying petal rotation.  Each petal has a base spin axis and speed (randomly chosen at
+//! startup), which are smoothly perturbed over time by Perlin noise so that the petal does not spin
+//! around a single fixed axis forever.  Petals can also occasionally flip over (a half turn around
+//! an axis lying in the plane of the petal), which mimics a petal catching the air and tumbling.
+
+use crate::configuration::TumblingConfig;
+use cgmath::prelude::*;
+use cgmath::{Quaternion, Rad, Vector3};
+use noise::{NoiseFn, Perlin};
+use rand::prelude::*;
+use rand_distr::StandardNormal;
+use serde::{Deserialize, Serialize};
+
+/// Offsets between the noise coordinates used for the different components of a petal's spin, so
+/// that the components vary independently of each other.
+const NOISE_COMPONENT_OFFSETS: [f64; 4] = [0.0, 17.31, 34.62, 51.93];
+
+/// Per-petal rotation state.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PetalSpin {
+    /// Axis the petal spins around when no wander is applied (unit vector).
+    pub base_axis: Vector3<f32>,
+    /// Spin speed (per frame) when no speed variation is applied.
+    pub base_speed: Rad<f32>,
+    /// Offset of this petal within the noise field, so that each petal wanders differently.
+    pub noise_offset: f64,
+    /// Axis of the flip currently in progress (only meaningful while flip_frames_total > 0).
+    pub flip_axis: Vector3<f32>,
+    /// Number of frames of the flip in progress that have been completed.
+    pub flip_frame: u32,
+    /// Total number of frames the flip in progress takes (0 if the petal is not flipping).
+    pub flip_frames_total: u32,
+}
+
+impl PetalSpin {
+    /// Randomly chooses the base spin axis and a speed between `min_speed` and `max_speed`.
+    pub fn new_random<R: Rng + ?Sized>(
+        min_speed: Rad<f32>,
+        max_speed: Rad<f32>,
+        rng: &mut R,
+    ) -> Self {
+        let base_axis = Vector3::<f32> {
+            x: rng.sample(StandardNormal),
+            y: rng.sample(StandardNormal),
+            z: rng.sample(StandardNormal),
+        }
+        .normalize();
+        Self {
+            base_axis,
+            base_speed: min_speed + (max_speed - min_speed) * rng.gen::<f32>(),
+            // Keep away from integer coordinates, where Perlin noise is always zero.
+            noise_offset: 1000.0 * rng.gen::<f64>() + 0.5,
+            flip_axis: Vector3::unit_x(),
+            flip_frame: 0,
+            flip_frames_total: 0,
+        }
+    }
+
+    /// Returns the rotation to apply to the petal for the given frame.  `time` is the position of
+    /// the frame on the noise time axis (i.e. the time in seconds divided by the wander period),
+    /// and `orientation` is the petal's current orientation (used to pick flip axes that lie in
+    /// the plane of the petal).
+    pub fn step<R: Rng + ?Sized>(
+        &mut self,
+        orientation: Quaternion<f32>,
+        time: f64,
+        frames_per_second: f32,
+        tumbling: &TumblingConfig,
+        noise: &Perlin,
+        rng: &mut R,
+    ) -> Quaternion<f32> {
+        let sample = |component: usize| {
+            noise.get([time, self.noise_offset + NOISE_COMPONENT_OFFSETS[component]]) as f32
+        };
+        let wandering_axis =
+            self.base_axis + tumbling.axis_wander * Vector3::new(sample(0), sample(1), sample(2));
+        // With large amounts of wander, the noise could (rarely) cancel out the base axis.
+        let axis = if wandering_axis.magnitude2() > 1e-6 {
+            wandering_axis.normalize()
+        } else {
+            self.base_axis
+        };
+        let speed = self.base_speed * (1.0 + tumbling.speed_variation * sample(3)).max(0.0);
+        let mut rotation = Quaternion::from_axis_angle(axis, speed);
+
+        if self.flip_frames_total == 0
+            && tumbling.flip_probability > 0.0
+            && rng.gen::<f32>() < tumbling.flip_probability / frames_per_second
+        {
+            // Flip around a random axis within the plane of the petal (the petal's local x/y plane).
+            let angle = 2.0 * std::f32::consts::PI * rng.gen::<f32>();
+            self.flip_axis =
+                (orientation * Vector3::new(angle.cos(), angle.sin(), 0.0)).normalize();
+            self.flip_frame = 0;
+            self.flip_frames_total =
+                ((tumbling.flip_duration * frames_per_second).round() as u32).max(1);
+        }
+        if self.flip_frames_total > 0 {
+            // Ease in and out of the flip, so the flip speeds up and slows down smoothly.  The
+            // per-frame angles follow the differences of a cosine, so they add up to exactly a half
+            // turn over the duration of the flip.
+            let n = self.flip_frames_total as f32;
+            let k = self.flip_frame as f32;
+            let flip_angle = Rad(std::f32::consts::FRAC_PI_2
+                * ((std::f32::consts::PI * k / n).cos()
+                    - (std::f32::consts::PI * (k + 1.0) / n).cos()));
+            rotation = Quaternion::from_axis_angle(self.flip_axis, flip_angle) * rotation;
+            self.flip_frame += 1;
+            if self.flip_frame >= self.flip_frames_total {
+                self.flip_frames_total = 0;
+            }
+        }
+        rotation
+    }
+}
+
+/// Scales the angle of a rotation by `factor`, keeping its axis (e.g. to spin petals faster).
+pub fn scale_rotation(rotation: Quaternion<f32>, factor: f32) -> Quaternion<f32> {
+    let sin_half_angle = rotation.v.magnitude();
+    if sin_half_angle < 1e-9 {
+        return rotation;
+    }
+    let angle = 2.0 * sin_half_angle.atan2(rotation.s);
+    Quaternion::from_axis_angle(rotation.v / sin_half_angle, Rad(angle * factor))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn scaling_a_rotation_scales_its_angle() {
+        let axis = Vector3::new(1.0, 2.0, 3.0).normalize();
+        let rotation = Quaternion::from_axis_angle(axis, Rad(0.1));
+        let scaled = scale_rotation(rotation, 2.5);
+        let expected = Quaternion::from_axis_angle(axis, Rad(0.25));
+        assert!((scaled - expected).magnitude() < 1e-6, "{scaled:?}");
+        assert_eq!(scale_rotation(Quaternion::one(), 2.0), Quaternion::one());
+    }
+
+    #[test]
+    fn flip_adds_up_to_half_turn() {
+        let mut rng = rand::thread_rng();
+        let tumbling = TumblingConfig {
+            axis_wander: 0.0,
+            speed_variation: 0.0,
+            wander_period: 1.0,
+            flip_probability: 1.0e9,
+            flip_duration: 0.5,
+        };
+        let mut spin = PetalSpin::new_random(Rad(0.0), Rad(0.0), &mut rng);
+        let noise = Perlin::new(0);
+        let mut orientation = Quaternion::one();
+        for frame in 0..30 {
+            let rotation = spin.step(orientation, frame as f64, 60.0, &tumbling, &noise, &mut rng);
+            orientation = (rotation * orientation).normalize();
+        }
+        // After a half turn around an axis in the x/y plane, the petal's normal points backwards.
+        let normal = orientation * Vector3::unit_z();
+        assert!((normal.z + 1.0).abs() < 1e-4, "{normal:?}");
+    }
+}
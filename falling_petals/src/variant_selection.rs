@@ -0,0 +1,206 @@
+//! Functions for deciding how many petals of each petal variant get spawned.  Rather than choosing
+//! a variant independently at random for each petal (which only gives the requested proportions
+//! on average), the number of petals for each variant is computed up front so that the requested
+//! proportions are honored as exactly as whole petal counts allow.  The resulting list of variant
+//! indices is then shuffled so that the variants are still randomly distributed among the petals.
+
+use crate::configuration::{PetalGroupConfig, PetalTextureConfig};
+use rand::prelude::*;
+
+/// Splits `total` into whole numbers that are proportional to `weights`, using the largest
+/// remainder method.  Each entry first gets the integer part of its exact share, and the units left
+/// over are then handed out one at a time to the entries with the largest fractional parts (ties
+/// going to the earlier entry).  The results always sum to `total` (unless all weights are zero, in
+/// which case everything is zero), and each result differs from its exact share by less than one.
+pub fn apportion(total: usize, weights: &[f32]) -> Vec<usize> {
+    let weight_sum: f64 = weights
+        .iter()
+        .map(|&weight| f64::from(weight.max(0.0)))
+        .sum();
+    if weight_sum <= 0.0 {
+        return vec![0; weights.len()];
+    }
+    let exact_shares = weights
+        .iter()
+        .map(|&weight| total as f64 * f64::from(weight.max(0.0)) / weight_sum)
+        .collect::<Vec<_>>();
+    let mut counts = exact_shares
+        .iter()
+        .map(|share| share.floor() as usize)
+        .collect::<Vec<_>>();
+    let mut remaining = total - counts.iter().sum::<usize>();
+    // Sort indices by descending remainder.  The sort is stable, so ties keep their original order.
+    let mut indices_by_remainder = (0..weights.len()).collect::<Vec<_>>();
+    indices_by_remainder.sort_by(|&a, &b| {
+        let remainder_a = exact_shares[a] - exact_shares[a].floor();
+        let remainder_b = exact_shares[b] - exact_shares[b].floor();
+        remainder_b.total_cmp(&remainder_a)
+    });
+    for idx in indices_by_remainder {
+        if remaining == 0 {
+            break;
+        }
+        if weights[idx] > 0.0 {
+            counts[idx] += 1;
+            remaining -= 1;
+        }
+    }
+    counts
+}
+
+/// Returns the relative weight of each petal variant defined in `petal_textures` (in the same order
+/// the variants are created in).  Each texture's weight is split among its variants according to
+/// their variant weights, so a texture containing many petal images does not get picked more often
+/// than one containing only a few.
+pub fn variant_weights(petal_textures: &[PetalTextureConfig]) -> Vec<f32> {
+    petal_textures
+        .iter()
+        .flat_map(|texture| {
+            let weights = (0..texture.petal_coordinates.len())
+                .map(|idx| texture.variant_weights.get(idx).copied().unwrap_or(1.0))
+                .collect::<Vec<_>>();
+            let weight_sum: f32 = weights.iter().sum();
+            weights.into_iter().map(move |weight| {
+                if weight_sum > 0.0 {
+                    texture.weight * weight / weight_sum
+                } else {
+                    0.0
+                }
+            })
+        })
+        .collect()
+}
+
+/// Chooses a variant index for each of `n_petals` petals.  If any petal groups are defined, the
+/// petals are first split among the groups according to the group ratios, and then each group's
+/// petals are split among the variants of the textures in that group according to their weights.
+/// Otherwise, the petals are split among all variants according to their weights.  The returned
+/// indices are shuffled, and are offset by `first_variant_index` so that they index into the
+/// combined list of all variants uploaded to the GPU.
+pub fn choose_variant_indices<R: Rng + ?Sized>(
+    petal_textures: &[PetalTextureConfig],
+    petal_groups: &[PetalGroupConfig],
+    n_petals: usize,
+    first_variant_index: u32,
+    rng: &mut R,
+) -> Vec<u32> {
+    let weights = variant_weights(petal_textures);
+    let variant_textures = petal_textures
+        .iter()
+        .flat_map(|texture| std::iter::repeat_n(texture, texture.petal_coordinates.len()))
+        .collect::<Vec<_>>();
+
+    let mut variant_counts = vec![0; weights.len()];
+    if petal_groups.is_empty() {
+        variant_counts = apportion(n_petals, &weights);
+    } else {
+        let group_counts = apportion(
+            n_petals,
+            &petal_groups
+                .iter()
+                .map(|group| group.ratio)
+                .collect::<Vec<_>>(),
+        );
+        for (group, group_count) in petal_groups.iter().zip(group_counts) {
+            let in_group = variant_textures
+                .iter()
+                .map(|texture| texture.group.as_deref() == Some(group.name.as_str()))
+                .collect::<Vec<_>>();
+            let group_weights = weights
+                .iter()
+                .zip(&in_group)
+                .map(|(&weight, &in_group)| if in_group { weight } else { 0.0 })
+                .collect::<Vec<_>>();
+            for (count, group_variant_count) in variant_counts
+                .iter_mut()
+                .zip(apportion(group_count, &group_weights))
+            {
+                *count += group_variant_count;
+            }
+            log::debug!(
+                "Petal group \"{}\": {group_count} petals ({:.2}%)",
+                group.name,
+                100.0 * group_count as f32 / n_petals.max(1) as f32,
+            );
+        }
+    }
+
+    let mut variant_indices = variant_counts
+        .iter()
+        .enumerate()
+        .flat_map(|(idx, &count)| std::iter::repeat_n(first_variant_index + idx as u32, count))
+        .collect::<Vec<_>>();
+    variant_indices.shuffle(rng);
+    variant_indices
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn texture(n_variants: usize, weight: f32, group: Option<&str>) -> PetalTextureConfig {
+        PetalTextureConfig {
+            file: String::new(),
+            scale: 1.0,
+            x_multiplier: 1.0,
+            y_multiplier: 1.0,
+            petal_coordinates: vec![[0.0, 0.0, 1.0, 1.0]; n_variants],
+            weight,
+            variant_weights: Vec::new(),
+            group: group.map(String::from),
+            curvature: Default::default(),
+            variant_curvatures: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn apportion_sums_to_total_and_stays_within_one_of_exact_share() {
+        let weights = [3.0, 1.0, 1.0, 0.5, 2.25];
+        let weight_sum: f32 = weights.iter().sum();
+        for total in [0, 1, 7, 100, 7001] {
+            let counts = apportion(total, &weights);
+            assert_eq!(counts.iter().sum::<usize>(), total);
+            for (count, weight) in counts.iter().zip(weights) {
+                let exact = total as f32 * weight / weight_sum;
+                assert!((*count as f32 - exact).abs() < 1.0);
+            }
+        }
+    }
+
+    #[test]
+    fn apportion_skips_zero_weights() {
+        assert_eq!(apportion(5, &[0.0, 1.0, 0.0]), vec![0, 5, 0]);
+        assert_eq!(apportion(5, &[0.0, 0.0]), vec![0, 0]);
+    }
+
+    #[test]
+    fn texture_weight_is_independent_of_variant_count() {
+        let textures = [texture(2, 1.0, None), texture(8, 1.0, None)];
+        let indices = choose_variant_indices(&textures, &[], 1000, 0, &mut rand::thread_rng());
+        let from_first_texture = indices.iter().filter(|&&idx| idx < 2).count();
+        assert_eq!(from_first_texture, 500);
+    }
+
+    #[test]
+    fn group_ratios_are_exact() {
+        let textures = [
+            texture(3, 1.0, Some("orange")),
+            texture(5, 1.0, Some("yellow")),
+            texture(4, 2.0, Some("yellow")),
+        ];
+        let groups = [
+            PetalGroupConfig {
+                name: String::from("orange"),
+                ratio: 3.0,
+            },
+            PetalGroupConfig {
+                name: String::from("yellow"),
+                ratio: 1.0,
+            },
+        ];
+        let indices = choose_variant_indices(&textures, &groups, 7000, 10, &mut rand::thread_rng());
+        assert_eq!(indices.len(), 7000);
+        assert_eq!(indices.iter().filter(|&&idx| idx < 13).count(), 5250);
+        assert!(indices.iter().all(|&idx| (10..22).contains(&idx)));
+    }
+}
@@ -0,0 +1,506 @@
+//! Translation of the video encoder (and audio track) settings into ffmpeg command-line arguments,
+//! and checking that the installed ffmpeg can actually encode with them before any rendering starts.
+
+use crate::configuration::{AudioConfig, EncoderPreset, VideoEncoderConfig, VideoExportConfig};
+use anyhow::{bail, Context};
+
+/// The ffmpeg settings making up an encoder preset.
+struct PresetSettings {
+    codec: &'static str,
+    /// Options specific to the codec (dropped if the codec is overridden)
+    codec_args: &'static [&'static str],
+    pixel_format: Option<&'static str>,
+    /// Whether to put a keyframe every half second
+    half_second_gop: bool,
+    /// Other output (e.g. muxer) options
+    output_args: &'static [&'static str],
+    /// Audio codec for videos with an audio track (None lets ffmpeg choose)
+    audio_codec: Option<&'static str>,
+    /// Options specific to the audio codec (dropped if the audio codec is overridden)
+    audio_codec_args: &'static [&'static str],
+}
+
+fn preset_settings(preset: EncoderPreset) -> PresetSettings {
+    match preset {
+        EncoderPreset::Youtube => PresetSettings {
+            codec: "libx264", // The default H.264 codec
+            codec_args: &[
+                "-preset", // Preset for video codec
+                "slow",    //   Slower gives better compression
+                "-crf",    // Quality setting for libx264 codec
+                "18",      //   0=51, lower is higher quality, 23 is default
+                "-bf",     // Limit consecutive B-frames (bidirectionally predicted frames)
+                "2",       //   Youtube recommends a limit of 2 consecutive B-frames.
+            ],
+            // yuv420p (recommended by YouTube, needed for many devices)
+            pixel_format: Some("yuv420p"),
+            // YouTube recommends a GOP of half the frame rate
+            half_second_gop: true,
+            output_args: &[
+                "-movflags",  // Muxer flags
+                "+faststart", //   YouTube recommends MOOV atom at the beginning of the file
+            ],
+            audio_codec: Some("aac"),
+            audio_codec_args: &[
+                "-b:a", // Audio bitrate
+                "384k", //   YouTube's recommendation for stereo audio
+            ],
+        },
+        EncoderPreset::ProresMaster => PresetSettings {
+            codec: "prores_ks",
+            codec_args: &[
+                "-profile:v", // ProRes profile
+                "3",          //   422 HQ
+                "-vendor",    // Vendor ID written to the file
+                "apl0",       //   Apple's, which some editing software expects
+            ],
+            pixel_format: Some("yuv422p10le"),
+            half_second_gop: false,
+            output_args: &[],
+            // Uncompressed 24-bit audio, as usual for ProRes masters
+            audio_codec: Some("pcm_s24le"),
+            audio_codec_args: &[],
+        },
+        EncoderPreset::LosslessArchive => PresetSettings {
+            codec: "ffv1",
+            codec_args: &[
+                "-level",    // FFV1 version
+                "3",         //   Version 3 supports slices and per-slice checksums
+                "-slices",   // Number of slices each frame is split into
+                "16",        //   Allows encoding and decoding with multiple threads
+                "-slicecrc", // Per-slice checksums
+                "1",         //   Enabled, to detect corruption of the archived file
+                "-g",        // GOP size
+                "1",         //   Every frame is a keyframe (recommended for archiving)
+            ],
+            // Keep the exact pixel values that were rendered.
+            pixel_format: Some("bgra"),
+            half_second_gop: false,
+            output_args: &[],
+            audio_codec: Some("flac"),
+            audio_codec_args: &[],
+        },
+        EncoderPreset::Prores4444 => PresetSettings {
+            codec: "prores_ks",
+            codec_args: &[
+                "-profile:v",  // ProRes profile
+                "4",           //   4444
+                "-vendor",     // Vendor ID written to the file
+                "apl0",        //   Apple's, which some editing software expects
+                "-alpha_bits", // Bits per alpha sample
+                "16",          //   The most precise
+            ],
+            pixel_format: Some("yuva444p10le"),
+            half_second_gop: false,
+            output_args: &[],
+            audio_codec: Some("pcm_s24le"),
+            audio_codec_args: &[],
+        },
+        EncoderPreset::Vp9Alpha => PresetSettings {
+            codec: "libvpx-vp9",
+            codec_args: &[
+                "-crf",          // Quality setting for libvpx-vp9
+                "20",            //   0-63, lower is higher quality
+                "-b:v",          // Bitrate limit
+                "0",             //   None, so the quality setting alone decides the bitrate
+                "-auto-alt-ref", // Alternate reference frames
+                "0",             //   Disabled, since older libvpx versions drop the alpha with them
+                "-row-mt",       // Row-based multithreading
+                "1",             //   Enabled, for faster encoding
+            ],
+            pixel_format: Some("yuva420p"),
+            half_second_gop: false,
+            output_args: &[],
+            audio_codec: Some("libopus"),
+            audio_codec_args: &[],
+        },
+        EncoderPreset::Custom => PresetSettings {
+            codec: "",
+            codec_args: &[],
+            pixel_format: None,
+            half_second_gop: false,
+            output_args: &[],
+            audio_codec: None,
+            audio_codec_args: &[],
+        },
+    }
+}
+
+/// Returns the name of the ffmpeg encoder that will be used.
+pub fn codec(encoder: &VideoEncoderConfig) -> &str {
+    encoder
+        .codec
+        .as_deref()
+        .unwrap_or(preset_settings(encoder.preset).codec)
+}
+
+/// Returns the pixel format the video will be encoded with (None lets ffmpeg choose).
+pub fn pixel_format(encoder: &VideoEncoderConfig) -> Option<&str> {
+    encoder
+        .pixel_format
+        .as_deref()
+        .or(preset_settings(encoder.preset).pixel_format)
+}
+
+/// Returns true if the ffmpeg pixel format has an alpha channel (e.g. yuva444p10le or bgra).
+pub fn pixel_format_has_alpha(pixel_format: &str) -> bool {
+    ["yuva", "rgba", "bgra", "argb", "abgr", "gbrap", "ya"]
+        .iter()
+        .any(|prefix| pixel_format.starts_with(prefix))
+}
+
+/// Returns the horizontal and vertical chroma subsampling factors of the ffmpeg pixel format (e.g.
+/// (2, 2) for yuv420p).  The width and height of the video must be multiples of them.
+pub fn chroma_subsampling(pixel_format: &str) -> (u32, u32) {
+    const SUBSAMPLED_FORMATS: [(&[&str], (u32, u32)); 5] = [
+        (
+            &[
+                "yuv420", "yuva420", "yuvj420", "nv12", "nv21", "p010", "p016",
+            ],
+            (2, 2),
+        ),
+        (
+            &[
+                "yuv422", "yuva422", "yuvj422", "nv16", "yuyv422", "uyvy422", "y210",
+            ],
+            (2, 1),
+        ),
+        (&["yuv411", "yuvj411", "uyyvyy411"], (4, 1)),
+        (&["yuv410"], (4, 4)),
+        (&["yuv440", "yuvj440"], (1, 2)),
+    ];
+    SUBSAMPLED_FORMATS
+        .iter()
+        .find(|(prefixes, _)| {
+            prefixes
+                .iter()
+                .any(|prefix| pixel_format.starts_with(prefix))
+        })
+        .map_or((1, 1), |&(_, factors)| factors)
+}
+
+/// Returns true if the ffmpeg pixel format stores RGB values (rather than e.g. YUV), so that 8-bit
+/// colors are kept exactly by lossless codecs.
+pub fn pixel_format_is_rgb(pixel_format: &str) -> bool {
+    [
+        "rgb", "bgr", "argb", "abgr", "0rgb", "0bgr", "gbrp", "gbrap",
+    ]
+    .iter()
+    .any(|prefix| pixel_format.starts_with(prefix))
+}
+
+/// Returns the ffmpeg output options (everything between the input and the output file name) for
+/// the given encoder settings.
+pub fn ffmpeg_output_args(encoder: &VideoEncoderConfig, frame_rate: u32) -> Vec<String> {
+    let preset = preset_settings(encoder.preset);
+    let mut args = vec![String::from("-c:v"), codec(encoder).to_string()];
+    if encoder.codec.is_none() {
+        args.extend(preset.codec_args.iter().map(|arg| arg.to_string()));
+    }
+    if let Some(pixel_format) = pixel_format(encoder) {
+        args.extend([String::from("-pix_fmt"), pixel_format.to_string()]);
+    }
+    if let Some(bitrate) = &encoder.bitrate {
+        args.extend([String::from("-b:v"), bitrate.clone()]);
+    }
+    if preset.half_second_gop {
+        args.extend([String::from("-g"), (frame_rate / 2).max(1).to_string()]);
+    }
+    args.extend(preset.output_args.iter().map(|arg| arg.to_string()));
+    args.extend(encoder.extra_args.iter().cloned());
+    args
+}
+
+/// Returns the name of the ffmpeg audio encoder that will be used (None lets ffmpeg choose).
+fn audio_codec<'a>(encoder: &VideoEncoderConfig, audio: &'a AudioConfig) -> Option<&'a str> {
+    audio
+        .codec
+        .as_deref()
+        .or(preset_settings(encoder.preset).audio_codec)
+}
+
+/// Returns the ffmpeg options for the audio track of the video: the options for the audio input
+/// (which come after the video input), and the output options for the audio.  `duration` is the
+/// length of the video in seconds, if known in advance.
+pub fn ffmpeg_audio_args(
+    audio: &AudioConfig,
+    encoder: &VideoEncoderConfig,
+    duration: Option<f64>,
+) -> (Vec<String>, Vec<String>) {
+    let Some(file) = &audio.file else {
+        return (Vec::new(), vec![String::from("-an")]); // No audio
+    };
+    let input_args = vec![
+        String::from("-ss"), // Start reading the audio file at the offset
+        audio.offset.to_string(),
+        String::from("-i"),
+        file.clone(),
+    ];
+    let mut filters = Vec::new();
+    if audio.normalize_loudness {
+        filters.push(format!(
+            "loudnorm=I={}:TP=-1.5:LRA=11",
+            audio.target_loudness
+        ));
+        // loudnorm upsamples the audio to 192 kHz, so resample it back down.
+        filters.push(String::from("aresample=48000"));
+    }
+    if audio.fade_in > 0.0 {
+        filters.push(format!("afade=t=in:st=0:d={}", audio.fade_in));
+    }
+    if let Some(duration) = duration.filter(|_| audio.fade_out > 0.0) {
+        let start = (duration - audio.fade_out).max(0.0);
+        filters.push(format!("afade=t=out:st={start}:d={}", audio.fade_out));
+    }
+    // Pad the audio with silence in case it's shorter than the video.
+    filters.push(String::from("apad"));
+
+    let mut output_args = vec![
+        String::from("-map"), // Video from the first input (the rendered frames)
+        String::from("0:v"),
+        String::from("-map"), // Audio from the second input (the audio file)
+        String::from("1:a"),
+        String::from("-af"),
+        filters.join(","),
+    ];
+    if let Some(codec) = audio_codec(encoder, audio) {
+        output_args.extend([String::from("-c:a"), codec.to_string()]);
+    }
+    if audio.codec.is_none() {
+        let preset = preset_settings(encoder.preset);
+        output_args.extend(preset.audio_codec_args.iter().map(|arg| arg.to_string()));
+    }
+    if let Some(bitrate) = &audio.bitrate {
+        output_args.extend([String::from("-b:a"), bitrate.clone()]);
+    }
+    // Stop at the end of the video (the padded audio never ends).
+    output_args.push(String::from("-shortest"));
+    if let Some(duration) = duration {
+        // -shortest can overshoot by a few audio frames, so also trim to the exact length.
+        output_args.extend([String::from("-t"), duration.to_string()]);
+    }
+    (input_args, output_args)
+}
+
+/// Returns all the ffmpeg options set by an export target's encoder and audio settings: the options
+/// for the audio input, and the output options (video and audio).
+pub fn ffmpeg_export_args(video_config: &VideoExportConfig) -> (Vec<String>, Vec<String>) {
+    let mut output_args = ffmpeg_output_args(&video_config.encoder, video_config.frame_rate);
+    let duration = video_config
+        .n_frames
+        .map(|n_frames| n_frames as f64 / f64::from(video_config.frame_rate));
+    let (audio_input_args, audio_output_args) =
+        ffmpeg_audio_args(&video_config.audio, &video_config.encoder, duration);
+    output_args.extend(audio_output_args);
+    (audio_input_args, output_args)
+}
+
+/// Returns the ffmpeg arguments that join the segments listed in `list_file` (in the format of
+/// ffmpeg's concat demuxer) into the export target's output file, without re-encoding the video.
+/// The segments have no audio, so the audio track is added here.
+fn concat_args(video_config: &VideoExportConfig, list_file: &str) -> Vec<String> {
+    let duration = video_config
+        .n_frames
+        .map(|n_frames| n_frames as f64 / f64::from(video_config.frame_rate));
+    let (audio_input_args, audio_output_args) =
+        ffmpeg_audio_args(&video_config.audio, &video_config.encoder, duration);
+    let mut args = [
+        "-y",
+        "-hide_banner",
+        "-nostats",
+        "-f",      // Format
+        "concat",  //   list of files to play one after another
+        "-safe",   // Allow any file names in the list
+        "0",       //
+        "-i",      // Input file
+        list_file, //
+    ]
+    .map(String::from)
+    .to_vec();
+    args.extend(audio_input_args);
+    args.extend(["-c:v", "copy"].map(String::from));
+    args.extend(audio_output_args);
+    let preset = preset_settings(video_config.encoder.preset);
+    args.extend(preset.output_args.iter().map(|arg| arg.to_string()));
+    args.push(video_config.output_file.clone());
+    args
+}
+
+/// Joins the segments of a video written with checkpoints into the export target's output file.
+pub fn concatenate_segments(
+    segment_files: &[String],
+    video_config: &VideoExportConfig,
+) -> anyhow::Result<()> {
+    let list_file = format!("{}.segments.txt", video_config.output_file);
+    let list = segment_files
+        .iter()
+        .map(|segment_file| {
+            let path = std::fs::canonicalize(segment_file)
+                .with_context(|| format!("Segment {segment_file} is missing"))?;
+            // Quotes in the file name are escaped by closing the quotes around the name,
+            // escaping the quote, and opening them again.
+            Ok(format!(
+                "file '{}'\n",
+                path.to_string_lossy().replace('\'', "'\\''")
+            ))
+        })
+        .collect::<anyhow::Result<String>>()?;
+    std::fs::write(&list_file, list).with_context(|| format!("Failed to write {list_file}"))?;
+    let output = std::process::Command::new("ffmpeg")
+        .args(concat_args(video_config, &list_file))
+        .output()
+        .context("Could not run ffmpeg")?;
+    let _ = std::fs::remove_file(&list_file);
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        let lines = stderr.lines().collect::<Vec<_>>();
+        bail!(
+            "ffmpeg exited with {} while joining the segments of {}:\n{}",
+            output.status,
+            video_config.output_file,
+            lines[lines.len().saturating_sub(20)..].join("\n")
+        );
+    }
+    Ok(())
+}
+
+/// Checks that ffmpeg can be run and that it supports the encoders the settings use, and that the
+/// audio file (if any) exists.
+pub fn check_ffmpeg_support(
+    encoder: &VideoEncoderConfig,
+    audio: &AudioConfig,
+) -> anyhow::Result<()> {
+    if let Some(file) = &audio.file {
+        if !std::path::Path::new(file).is_file() {
+            bail!("Audio file \"{file}\" not found");
+        }
+    }
+    let output = std::process::Command::new("ffmpeg")
+        .args(["-hide_banner", "-encoders"])
+        .output()
+        .context("Could not run ffmpeg (is it installed and on the PATH?)")?;
+    if !output.status.success() {
+        bail!("ffmpeg -encoders exited with {}", output.status);
+    }
+    let encoders = String::from_utf8_lossy(&output.stdout);
+    let encoders = parse_ffmpeg_encoders(&encoders);
+    let audio_codec = audio.file.as_ref().and(audio_codec(encoder, audio));
+    for codec in std::iter::once(codec(encoder)).chain(audio_codec) {
+        if !encoders.contains(&codec) {
+            bail!("This ffmpeg build does not include the \"{codec}\" encoder");
+        }
+    }
+    Ok(())
+}
+
+/// Returns the encoder names listed in the output of `ffmpeg -encoders`.
+fn parse_ffmpeg_encoders(output: &str) -> Vec<&str> {
+    // The list of encoders follows a legend of the capability flags, separated by a line of dashes.
+    // Each encoder is listed as its flags, name, and description.
+    output
+        .lines()
+        .skip_while(|line| !line.trim_start().starts_with("---"))
+        .skip(1)
+        .filter_map(|line| line.split_whitespace().nth(1))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn presets_can_be_overridden() {
+        let youtube = VideoEncoderConfig::default();
+        assert_eq!(
+            ffmpeg_output_args(&youtube, 60).join(" "),
+            "-c:v libx264 -preset slow -crf 18 -bf 2 -pix_fmt yuv420p -g 30 -movflags +faststart"
+        );
+        let hevc = VideoEncoderConfig {
+            codec: Some(String::from("libx265")),
+            bitrate: Some(String::from("20M")),
+            extra_args: vec![String::from("-tag:v"), String::from("hvc1")],
+            ..Default::default()
+        };
+        assert_eq!(
+            ffmpeg_output_args(&hevc, 30).join(" "),
+            "-c:v libx265 -pix_fmt yuv420p -b:v 20M -g 15 -movflags +faststart -tag:v hvc1"
+        );
+    }
+
+    #[test]
+    fn segments_are_joined_without_reencoding() {
+        let mut video_config = VideoExportConfig::new(
+            true,
+            String::from("loop.mp4"),
+            VideoEncoderConfig::default(),
+            1920,
+            1080,
+            60,
+            wgpu::TextureFormat::Bgra8UnormSrgb,
+        );
+        video_config.n_frames = Some(600);
+        assert_eq!(
+            concat_args(&video_config, "list.txt").join(" "),
+            "-y -hide_banner -nostats -f concat -safe 0 -i list.txt -c:v copy -an \
+            -movflags +faststart loop.mp4"
+        );
+        video_config.audio.file = Some(String::from("music.flac"));
+        let args = concat_args(&video_config, "list.txt").join(" ");
+        assert!(args.contains("-i list.txt -ss 0 -i music.flac -c:v copy -map 0:v -map 1:a"));
+        assert!(args.ends_with("-shortest -t 10 -movflags +faststart loop.mp4"));
+    }
+
+    #[test]
+    fn audio_is_faded_and_trimmed_to_video_length() {
+        let encoder = VideoEncoderConfig::default();
+        let (input_args, output_args) =
+            ffmpeg_audio_args(&AudioConfig::default(), &encoder, Some(10.0));
+        assert!(input_args.is_empty());
+        assert_eq!(output_args, ["-an"]);
+
+        let audio = AudioConfig {
+            file: Some(String::from("music.flac")),
+            offset: 12.5,
+            fade_in: 1.0,
+            fade_out: 2.0,
+            normalize_loudness: true,
+            ..Default::default()
+        };
+        let (input_args, output_args) = ffmpeg_audio_args(&audio, &encoder, Some(10.0));
+        assert_eq!(input_args.join(" "), "-ss 12.5 -i music.flac");
+        assert_eq!(
+            output_args.join(" "),
+            "-map 0:v -map 1:a -af loudnorm=I=-14:TP=-1.5:LRA=11,aresample=48000,\
+            afade=t=in:st=0:d=1,afade=t=out:st=8:d=2,apad -c:a aac -b:a 384k -shortest -t 10"
+        );
+        // Without a known length, there is no fade out.
+        let audio = AudioConfig {
+            codec: Some(String::from("libopus")),
+            ..audio
+        };
+        let (_, output_args) = ffmpeg_audio_args(&audio, &encoder, None);
+        assert_eq!(
+            output_args.join(" "),
+            "-map 0:v -map 1:a -af loudnorm=I=-14:TP=-1.5:LRA=11,aresample=48000,\
+            afade=t=in:st=0:d=1,apad -c:a libopus -shortest"
+        );
+    }
+
+    #[test]
+    fn parses_encoder_list() {
+        let output = "\
+Encoders:
+ V..... = Video
+ A..... = Audio
+ ------
+ V....D libx264              libx264 H.264 / AVC / MPEG-4 AVC / MPEG-4 part 10 (codec h264)
+ V..... prores_ks            Apple ProRes (iCodec Pro) (codec prores)
+ A....D aac                  AAC (Advanced Audio Coding)
+";
+        assert_eq!(
+            parse_ffmpeg_encoders(output),
+            ["libx264", "prores_ks", "aac"]
+        );
+    }
+}